@@ -0,0 +1 @@
+ALTER TABLE organizations ADD COLUMN email_message TEXT;
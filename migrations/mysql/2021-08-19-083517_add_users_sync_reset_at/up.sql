@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN sync_reset_at DATETIME NOT NULL DEFAULT '1970-01-01 00:00:00';
+UPDATE users SET sync_reset_at = updated_at;
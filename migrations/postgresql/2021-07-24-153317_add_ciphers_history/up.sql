@@ -0,0 +1,12 @@
+CREATE TABLE ciphers_history (
+  uuid             CHAR(36) NOT NULL PRIMARY KEY,
+  cipher_uuid      CHAR(36) NOT NULL REFERENCES ciphers (uuid),
+  revision_date    TIMESTAMP NOT NULL,
+  atype            INTEGER  NOT NULL,
+  name             TEXT     NOT NULL,
+  notes            TEXT,
+  fields           TEXT,
+  data             TEXT     NOT NULL,
+  password_history TEXT,
+  reprompt         INTEGER
+);
@@ -0,0 +1,3 @@
+ALTER TABLE ciphers
+    ADD COLUMN
+    archived_at TIMESTAMP;
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN trash_auto_delete_days INTEGER;
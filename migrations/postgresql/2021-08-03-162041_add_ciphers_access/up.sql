@@ -0,0 +1,7 @@
+CREATE TABLE ciphers_access (
+  cipher_uuid    CHAR(36)  NOT NULL REFERENCES ciphers (uuid),
+  user_uuid      CHAR(36)  NOT NULL REFERENCES users (uuid),
+  access_count   INTEGER   NOT NULL,
+  last_access_at TIMESTAMP NOT NULL,
+  PRIMARY KEY (cipher_uuid, user_uuid)
+);
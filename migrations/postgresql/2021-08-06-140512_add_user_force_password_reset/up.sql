@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN force_password_reset BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1 @@
+ALTER TABLE organizations ADD COLUMN created_at TIMESTAMP;
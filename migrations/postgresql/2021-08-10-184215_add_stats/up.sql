@@ -0,0 +1,6 @@
+CREATE TABLE stats (
+  day   CHAR(10)    NOT NULL,
+  name  VARCHAR(64) NOT NULL,
+  value BIGINT      NOT NULL,
+  PRIMARY KEY (day, name)
+);
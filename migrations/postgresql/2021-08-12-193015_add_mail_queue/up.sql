@@ -0,0 +1,12 @@
+CREATE TABLE mail_queue (
+  uuid            CHAR(36)     NOT NULL PRIMARY KEY,
+  address         VARCHAR(255) NOT NULL,
+  subject         TEXT         NOT NULL,
+  body_html       TEXT         NOT NULL,
+  body_text       TEXT         NOT NULL,
+  attempts        INTEGER      NOT NULL,
+  next_attempt_at TIMESTAMP    NOT NULL,
+  last_error      TEXT,
+  failed          BOOLEAN      NOT NULL,
+  created_at      TIMESTAMP    NOT NULL
+);
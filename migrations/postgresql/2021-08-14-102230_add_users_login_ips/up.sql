@@ -0,0 +1,7 @@
+CREATE TABLE users_login_ips (
+  user_uuid     CHAR(36)    NOT NULL REFERENCES users (uuid),
+  ip_network    VARCHAR(64) NOT NULL,
+  first_seen_at TIMESTAMP   NOT NULL,
+  last_seen_at  TIMESTAMP   NOT NULL,
+  PRIMARY KEY (user_uuid, ip_network)
+);
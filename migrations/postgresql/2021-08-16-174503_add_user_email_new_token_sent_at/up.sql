@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN email_new_token_sent_at TIMESTAMP;
+ALTER TABLE users ADD COLUMN email_new_token_attempts INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+ALTER TABLE collections
+ADD COLUMN external_id TEXT;
+
+ALTER TABLE users_organizations
+ADD COLUMN external_id TEXT;
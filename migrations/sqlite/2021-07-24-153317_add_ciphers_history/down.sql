@@ -0,0 +1 @@
+DROP TABLE ciphers_history;
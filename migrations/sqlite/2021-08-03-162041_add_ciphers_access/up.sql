@@ -0,0 +1,7 @@
+CREATE TABLE ciphers_access (
+  cipher_uuid    TEXT     NOT NULL REFERENCES ciphers (uuid),
+  user_uuid      TEXT     NOT NULL REFERENCES users (uuid),
+  access_count   INTEGER  NOT NULL,
+  last_access_at DATETIME NOT NULL,
+  PRIMARY KEY (cipher_uuid, user_uuid)
+);
@@ -0,0 +1 @@
+ALTER TABLE sends ADD COLUMN password_failures INTEGER NOT NULL DEFAULT 0;
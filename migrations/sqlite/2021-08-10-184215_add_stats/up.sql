@@ -0,0 +1,6 @@
+CREATE TABLE stats (
+  day   TEXT    NOT NULL,
+  name  TEXT    NOT NULL,
+  value BIGINT  NOT NULL,
+  PRIMARY KEY (day, name)
+);
@@ -1,7 +1,13 @@
+use chrono::{DateTime, Local};
+use num_traits::FromPrimitive;
 use once_cell::sync::Lazy;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
-use std::{env, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    time::{Duration, Instant},
+};
 
 use rocket::{
     http::{Cookie, Cookies, SameSite, Status},
@@ -13,45 +19,94 @@ use rocket_contrib::json::Json;
 
 use crate::{
     api::{ApiResult, EmptyResult, JsonResult, NumberOrString},
-    auth::{decode_admin, encode_jwt, generate_admin_claims, ClientIp},
+    auth::{
+        decode_admin, decode_admin_sso, encode_jwt, generate_admin_claims, generate_admin_sso_claims,
+        revoke_admin_session, AdminJwtClaims, ClientIp,
+    },
     config::ConfigBuilder,
     db::{backup_database, get_sql_server_version, models::*, DbConn, DbConnType},
     error::{Error, MapResult},
     mail,
+    sso,
+    stats,
+    storage::{get_storage, FileArea},
     util::{format_naive_datetime_local, get_display_size, get_reqwest_client, is_running_in_docker},
     CONFIG,
 };
 
 pub fn routes() -> Vec<Route> {
-    if !CONFIG.disable_admin_token() && !CONFIG.is_admin_token_set() {
-        return routes![admin_disabled];
+    // The JSON API has its own token, so it can be used even when the admin panel is disabled
+    let mut api_routes = routes![
+        api_get_users,
+        api_get_user,
+        api_invite_user,
+        api_delete_user,
+        api_deauth_user,
+        api_disable_user,
+        api_enable_user,
+        api_get_organizations,
+        api_get_config,
+        api_backup_db
+    ];
+
+    if !CONFIG.disable_admin_token() && !CONFIG.is_admin_token_set() && !CONFIG.is_admin_sso_enabled() {
+        let mut routes = routes![admin_disabled, setup_page, post_setup];
+        routes.append(&mut api_routes);
+        return routes;
     }
 
-    routes![
+    let mut routes = routes![
         admin_login,
         get_users_json,
         get_user_json,
+        get_stats_json,
         post_admin_login,
+        admin_sso_login,
+        admin_sso_callback,
         admin_page,
         invite_user,
+        bulk_invite_users,
+        resend_user_invite,
+        change_user_email,
         logout,
         delete_user,
         deauth_user,
+        get_user_devices,
+        deauth_user_device,
         disable_user,
         enable_user,
         remove_2fa,
+        force_password_reset,
         update_user_org_type,
         update_revision_users,
         post_config,
         delete_config,
+        export_config_profile,
+        import_config_profile,
         backup_db,
+        purge_data,
+        reload_templates,
         test_smtp,
         users_overview,
         organizations_overview,
         delete_organization,
+        delete_organization_member,
+        make_organization_owner,
+        sends_overview,
+        get_sends_json,
+        delete_send,
+        mail_queue_overview,
+        retry_queued_mail,
+        delete_queued_mail,
+        storage_overview,
+        get_storage_json,
+        metrics_overview,
+        get_metrics_json,
         diagnostics,
         get_diagnostics_config
-    ]
+    ];
+    routes.append(&mut api_routes);
+    routes
 }
 
 static DB_TYPE: Lazy<&str> = Lazy::new(|| {
@@ -64,15 +119,13 @@ static DB_TYPE: Lazy<&str> = Lazy::new(|| {
         .unwrap_or("Unknown")
 });
 
-static CAN_BACKUP: Lazy<bool> =
-    Lazy::new(|| DbConnType::from_url(&CONFIG.database_url()).map(|t| t == DbConnType::sqlite).unwrap_or(false));
-
-#[get("/")]
-fn admin_disabled() -> &'static str {
+#[get("/", rank = 2)]
+fn admin_disabled(_ip: AdminIpAllowed) -> &'static str {
     "The admin panel is disabled, please configure the 'ADMIN_TOKEN' variable to enable it"
 }
 
 const COOKIE_NAME: &str = "BWRS_ADMIN";
+const SSO_COOKIE_NAME: &str = "BWRS_ADMIN_SSO";
 const ADMIN_PATH: &str = "/admin";
 
 const BASE_TEMPLATE: &str = "admin/base";
@@ -135,14 +188,16 @@ fn admin_url(referer: Referer) -> String {
 }
 
 #[get("/", rank = 2)]
-fn admin_login(flash: Option<FlashMessage>) -> ApiResult<Html<String>> {
+fn admin_login(_ip: AdminIpAllowed, flash: Option<FlashMessage>) -> ApiResult<Html<String>> {
     // If there is an error, show it
     let msg = flash.map(|msg| format!("{}: {}", msg.name(), msg.msg()));
     let json = json!({
         "page_content": "admin/login",
         "version": VERSION,
         "error": msg,
-        "urlpath": CONFIG.domain_path()
+        "urlpath": CONFIG.domain_path(),
+        "token_enabled": CONFIG.is_admin_token_set(),
+        "sso_enabled": CONFIG.is_admin_sso_enabled(),
     });
 
     // Return the page
@@ -157,6 +212,7 @@ struct LoginForm {
 
 #[post("/", data = "<data>")]
 fn post_admin_login(
+    _allowed: AdminIpAllowed,
     data: Form<LoginForm>,
     mut cookies: Cookies,
     ip: ClientIp,
@@ -169,23 +225,243 @@ fn post_admin_login(
         error!("Invalid admin token. IP: {}", ip.ip);
         Err(Flash::error(Redirect::to(admin_url(referer)), "Invalid admin token, please try again."))
     } else {
-        // If the token received is valid, generate JWT and save it as a cookie
-        let claims = generate_admin_claims();
-        let jwt = encode_jwt(&claims);
-
-        let cookie = Cookie::build(COOKIE_NAME, jwt)
-            .path(admin_path())
-            .max_age(time::Duration::minutes(20))
-            .same_site(SameSite::Strict)
-            .http_only(true)
-            .finish();
-
-        cookies.add(cookie);
+        add_admin_cookie(&mut cookies, &generate_admin_claims(None));
         Ok(Redirect::to(admin_url(referer)))
     }
 }
 
+/// Saves the admin JWT as a cookie, once the admin is authenticated or when the session is renewed
+fn add_admin_cookie(cookies: &mut Cookies, claims: &AdminJwtClaims) {
+    let jwt = encode_jwt(claims);
+
+    let cookie = Cookie::build(COOKIE_NAME, jwt)
+        .path(admin_path())
+        .max_age(time::Duration::minutes(CONFIG.admin_session_lifetime()))
+        .same_site(SameSite::Strict)
+        .http_only(true)
+        .finish();
+
+    cookies.add(cookie);
+}
+
+fn admin_sso_redirect_uri() -> String {
+    format!("{}{}/sso/callback", CONFIG.domain_origin(), admin_path())
+}
+
+/// Sends the admin to the SSO provider to log in, the state of the login is kept in a cookie until it comes back
+#[get("/sso")]
+fn admin_sso_login(_ip: AdminIpAllowed, mut cookies: Cookies) -> Result<Redirect, Flash<Redirect>> {
+    if !CONFIG.is_admin_sso_enabled() {
+        return Err(Flash::error(Redirect::to(admin_url(Referer(None))), "SSO is not enabled"));
+    }
+
+    let state = crate::crypto::generate_id(16);
+    let nonce = crate::crypto::generate_id(16);
+    let url = match sso::authorize_url(&admin_sso_redirect_uri(), &state, &nonce) {
+        Ok(url) => url,
+        Err(e) => {
+            error!("Unable to start the admin SSO login: {}", e);
+            return Err(Flash::error(Redirect::to(admin_url(Referer(None))), "Unable to contact the SSO provider"));
+        }
+    };
+
+    // SameSite=Lax, as the cookie needs to be sent when the provider redirects back here
+    let cookie = Cookie::build(SSO_COOKIE_NAME, encode_jwt(&generate_admin_sso_claims(state, nonce)))
+        .path(admin_path())
+        .max_age(time::Duration::minutes(10))
+        .same_site(SameSite::Lax)
+        .http_only(true)
+        .finish();
+    cookies.add(cookie);
+
+    Ok(Redirect::to(url))
+}
+
+#[get("/sso/callback?<code>&<state>")]
+fn admin_sso_callback(
+    _allowed: AdminIpAllowed,
+    code: Option<String>,
+    state: Option<String>,
+    mut cookies: Cookies,
+    ip: ClientIp,
+) -> Result<Redirect, Flash<Redirect>> {
+    let login_url = admin_url(Referer(None));
+    if !CONFIG.is_admin_sso_enabled() {
+        return Err(Flash::error(Redirect::to(login_url), "SSO is not enabled"));
+    }
+
+    let claims = cookies.get(SSO_COOKIE_NAME).and_then(|c| decode_admin_sso(c.value()).ok());
+    cookies.remove(Cookie::build(SSO_COOKIE_NAME, "").path(admin_path()).finish());
+
+    let (code, claims) = match (code, state, claims) {
+        (Some(code), Some(state), Some(claims)) if crate::crypto::ct_eq(&state, &claims.state) => (code, claims),
+        _ => return Err(Flash::error(Redirect::to(login_url), "Invalid or expired SSO login, please try again.")),
+    };
+
+    match sso::exchange_code(&code, &admin_sso_redirect_uri(), &claims.nonce) {
+        Ok(sub) if sso::is_subject_allowed(&sub) => {
+            info!("Admin SSO login of {}. IP: {}", sub, ip.ip);
+            add_admin_cookie(&mut cookies, &generate_admin_claims(None));
+            Ok(Redirect::to(login_url))
+        }
+        Ok(sub) => {
+            error!("The SSO subject {} is not allowed in the admin panel. IP: {}", sub, ip.ip);
+            Err(Flash::error(Redirect::to(login_url), "This account is not allowed in the admin panel."))
+        }
+        Err(e) => {
+            error!("Admin SSO login failed: {}. IP: {}", e, ip.ip);
+            Err(Flash::error(Redirect::to(login_url), "SSO login failed, please try again."))
+        }
+    }
+}
+
+/// Only allow the first-run setup while the instance is still pristine:
+/// no admin token configured (and not explicitly disabled) and no users created yet.
+struct SetupAllowed;
+
+impl<'a, 'r> FromRequest<'a, 'r> for SetupAllowed {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        if CONFIG.disable_admin_token() || CONFIG.is_admin_token_set() || CONFIG.is_admin_sso_enabled() {
+            return Outcome::Forward(());
+        }
+
+        if let Outcome::Failure((status, _)) = request.guard::<AdminIpAllowed>() {
+            return Outcome::Failure((status, ()));
+        }
+
+        let conn = match request.guard::<DbConn>() {
+            Outcome::Success(conn) => conn,
+            _ => return Outcome::Forward(()),
+        };
+
+        if User::get_all(&conn).is_empty() {
+            Outcome::Success(SetupAllowed)
+        } else {
+            Outcome::Forward(())
+        }
+    }
+}
+
+#[get("/", rank = 1)]
+fn setup_page(_setup: SetupAllowed, flash: Option<FlashMessage>) -> ApiResult<Html<String>> {
+    let msg = flash.map(|msg| format!("{}: {}", msg.name(), msg.msg()));
+    let json = json!({
+        "page_content": "admin/setup",
+        "version": VERSION,
+        "error": msg,
+        "urlpath": CONFIG.domain_path(),
+        "page_data": {
+            "domain": CONFIG.domain(),
+        },
+    });
+
+    let text = CONFIG.render_template(BASE_TEMPLATE, &json)?;
+    Ok(Html(text))
+}
+
+#[derive(FromForm)]
+struct SetupForm {
+    email: String,
+    domain: String,
+    smtp_host: Option<String>,
+    smtp_port: Option<u16>,
+    smtp_from: Option<String>,
+    smtp_username: Option<String>,
+    smtp_password: Option<String>,
+}
+
+#[post("/setup", data = "<data>")]
+fn post_setup(
+    data: Form<SetupForm>,
+    _setup: SetupAllowed,
+    referer: Referer,
+    conn: DbConn,
+) -> Result<Html<String>, Flash<Redirect>> {
+    let data = data.into_inner();
+    let redirect_url = admin_url(referer);
+    let setup_err = |msg: &str| Flash::error(Redirect::to(redirect_url.clone()), msg);
+
+    let email = data.email.trim().to_lowercase();
+    if !email.contains('@') {
+        return Err(setup_err("The email address of the first user is invalid"));
+    }
+
+    // Empty form fields are treated as not set
+    fn non_empty(value: Option<String>) -> Option<String> {
+        value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+    }
+
+    let admin_token = data_encoding::BASE64.encode(&crate::crypto::get_random_64());
+    let smtp_host = non_empty(data.smtp_host);
+
+    let builder: ConfigBuilder = match serde_json::from_value(json!({
+        "admin_token": admin_token,
+        "domain": data.domain.trim().trim_end_matches('/'),
+        "smtp_host": smtp_host,
+        "smtp_port": smtp_host.as_ref().and(data.smtp_port),
+        "smtp_from": smtp_host.as_ref().and(non_empty(data.smtp_from)),
+        "smtp_username": smtp_host.as_ref().and(non_empty(data.smtp_username)),
+        "smtp_password": smtp_host.as_ref().and(non_empty(data.smtp_password)),
+    })) {
+        Ok(builder) => builder,
+        Err(e) => return Err(setup_err(&format!("Invalid settings: {}", e))),
+    };
+
+    // The master password never reaches the server, so the first user can't be fully
+    // created here. Instead, pre-register it with an invitation, which allows registering
+    // that email address even when signups are disabled or the invitation email is lost.
+    // Writing the config locks the setup, so it's only done once the user exists.
+    let mut user = User::new(email.clone());
+    let setup_result = conn.transaction(|| {
+        Invitation::new(email.clone()).save(&conn)?;
+        user.save(&conn)?;
+        CONFIG.update_config_partial(builder)
+    });
+
+    if let Err(e) = setup_result {
+        return Err(setup_err(e.message()));
+    }
+
+    // The email is sent with the SMTP settings which were just saved
+    let invite_sent = CONFIG.mail_enabled()
+        && match mail::send_invite(&user.email, &user.uuid, None, None, &CONFIG.invitation_org_name(), None, None) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Error sending the invitation of the first user during setup: {:#?}", e);
+                false
+            }
+        };
+
+    info!("First-run setup completed, the admin token has been written to the config file");
+
+    let json = json!({
+        "page_content": "admin/setup",
+        "version": VERSION,
+        "error": null,
+        "urlpath": CONFIG.domain_path(),
+        "page_data": {
+            "completed": true,
+            "admin_token": admin_token,
+            "email": email,
+            "mail_enabled": invite_sent,
+            "admin_url": redirect_url,
+        },
+    });
+
+    match CONFIG.render_template(BASE_TEMPLATE, &json) {
+        Ok(text) => Ok(Html(text)),
+        Err(e) => Err(setup_err(&e.to_string())),
+    }
+}
+
 fn _validate_token(token: &str) -> bool {
+    // An empty token can be set when the panel is only used with SSO, it must never match
+    if !CONFIG.is_admin_token_set() {
+        return false;
+    }
+
     match CONFIG.admin_token().as_ref() {
         None => false,
         Some(t) => crate::crypto::ct_eq(t.trim(), token.trim()),
@@ -198,7 +474,6 @@ struct AdminTemplateData {
     version: Option<&'static str>,
     page_data: Option<Value>,
     config: Value,
-    can_backup: bool,
     logged_in: bool,
     urlpath: String,
 }
@@ -209,7 +484,6 @@ impl AdminTemplateData {
             page_content: String::from("admin/settings"),
             version: VERSION,
             config: CONFIG.prepare_json(),
-            can_backup: *CAN_BACKUP,
             logged_in: true,
             urlpath: CONFIG.domain_path(),
             page_data: None,
@@ -222,7 +496,6 @@ impl AdminTemplateData {
             version: VERSION,
             page_data: Some(page_data),
             config: CONFIG.prepare_json(),
-            can_backup: *CAN_BACKUP,
             logged_in: true,
             urlpath: CONFIG.domain_path(),
         }
@@ -267,7 +540,7 @@ fn invite_user(data: Json<InviteData>, _token: AdminToken, conn: DbConn) -> Json
     // See: https://github.com/rust-lang/rust/issues/31436
     (|| {
         if CONFIG.mail_enabled() {
-            mail::send_invite(&user.email, &user.uuid, None, None, &CONFIG.invitation_org_name(), None)?;
+            mail::send_invite(&user.email, &user.uuid, None, None, &CONFIG.invitation_org_name(), None, None)?;
         } else {
             let invitation = Invitation::new(data.email);
             invitation.save(&conn)?;
@@ -280,53 +553,327 @@ fn invite_user(data: Json<InviteData>, _token: AdminToken, conn: DbConn) -> Json
     Ok(Json(user.to_json(&conn)))
 }
 
+#[derive(Deserialize)]
+struct BulkInviteData {
+    csv: String,
+}
+
+/// Splits a CSV line in its fields, quoted fields can contain commas and doubled quotes
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Invites the users of a CSV with the columns `email[,name[,organization]]`, where the organization can be
+/// its name or uuid. Every row is handled on its own, and the result of each one is returned
+#[post("/invite/bulk", data = "<data>")]
+fn bulk_invite_users(data: Json<BulkInviteData>, _token: AdminToken, conn: DbConn) -> JsonResult {
+    let data: BulkInviteData = data.into_inner();
+    let orgs = Organization::get_all(&conn);
+
+    let mut results = Vec::new();
+    for (i, line) in data.csv.lines().enumerate() {
+        let fields = split_csv_line(line);
+        let field = |n: usize| fields.get(n).map(|f| f.trim()).filter(|f| !f.is_empty());
+
+        let email = match field(0) {
+            Some(email) => email.to_lowercase(),
+            None => continue,
+        };
+        // Skip the header row, if any
+        if i == 0 && email == "email" {
+            continue;
+        }
+
+        // The invitation is only sent once the user and the membership are committed
+        let result = match conn.transaction(|| invite_csv_row(&email, field(1), field(2), &orgs, &conn)) {
+            Ok((msg, Some(invite))) => match invite.send() {
+                Ok(_) => Ok(msg),
+                Err(e) => Err(format!("{}, but the invitation email couldn't be sent: {}", msg, e.message())),
+            },
+            Ok((msg, None)) => Ok(msg),
+            Err(e) => Err(e.message().to_string()),
+        };
+        results.push(json!({
+            "Row": i + 1,
+            "Email": email,
+            "Success": result.is_ok(),
+            "Message": match result {
+                Ok(msg) | Err(msg) => msg,
+            },
+        }));
+    }
+
+    Ok(Json(json!({ "Results": results })))
+}
+
+/// Invitation email of a CSV row, sent after the changes of the row are committed
+struct CsvInvite {
+    email: String,
+    user_uuid: String,
+    org_id: Option<String>,
+    org_user_id: Option<String>,
+    org_name: String,
+    email_message: Option<String>,
+}
+
+impl CsvInvite {
+    fn send(self) -> EmptyResult {
+        mail::send_invite(
+            &self.email,
+            &self.user_uuid,
+            self.org_id,
+            self.org_user_id,
+            &self.org_name,
+            None,
+            self.email_message.as_deref(),
+        )
+    }
+}
+
+/// Creates the user and the membership of a CSV row, returning the result message and the invitation to send, if any
+fn invite_csv_row(
+    email: &str,
+    name: Option<&str>,
+    org: Option<&str>,
+    orgs: &[Organization],
+    conn: &DbConn,
+) -> ApiResult<(String, Option<CsvInvite>)> {
+    if !email.contains('@') {
+        err!("Invalid email address")
+    }
+
+    let org = match org {
+        Some(org) => match orgs.iter().find(|o| o.uuid == org || o.name.eq_ignore_ascii_case(org)) {
+            Some(org) => Some(org),
+            None => err!(format!("Organization not found: {}", org)),
+        },
+        None => None,
+    };
+
+    let (user, created) = match User::find_by_mail(email, conn) {
+        Some(_) if org.is_none() => err!("User already exists"),
+        Some(user) => (user, false),
+        None => {
+            let mut user = User::new(email.to_string());
+            if let Some(name) = name {
+                user.name = name.to_string();
+            }
+            if !CONFIG.mail_enabled() {
+                Invitation::new(user.email.clone()).save(conn)?;
+            }
+            user.save(conn)?;
+            (user, true)
+        }
+    };
+
+    let org = match org {
+        Some(org) => org,
+        None => {
+            let invite = if CONFIG.mail_enabled() {
+                Some(CsvInvite {
+                    email: user.email,
+                    user_uuid: user.uuid,
+                    org_id: None,
+                    org_user_id: None,
+                    org_name: CONFIG.invitation_org_name(),
+                    email_message: None,
+                })
+            } else {
+                None
+            };
+            return Ok((String::from("User invited"), invite));
+        }
+    };
+
+    if UserOrganization::find_by_user_and_org(&user.uuid, &org.uuid, conn).is_some() {
+        err!(format!("User already in organization {}", org.name))
+    }
+
+    let mut user_org = UserOrganization::new(user.uuid.clone(), org.uuid.clone());
+    // Same as the invitations of the organizations, existing users are accepted directly without mail
+    if created || CONFIG.mail_enabled() {
+        user_org.status = UserOrgStatus::Invited as i32;
+    }
+    user_org.save(conn)?;
+
+    let invite = if CONFIG.mail_enabled() {
+        Some(CsvInvite {
+            email: user.email,
+            user_uuid: user.uuid,
+            org_id: Some(org.uuid.clone()),
+            org_user_id: Some(user_org.uuid),
+            org_name: org.name.clone(),
+            email_message: org.email_message.clone(),
+        })
+    } else {
+        None
+    };
+
+    Ok((format!("User invited to organization {}", org.name), invite))
+}
+
+/// Sends a test email and returns the steps of the SMTP session, the test failing is not an error of the request
 #[post("/test/smtp", data = "<data>")]
-fn test_smtp(data: Json<InviteData>, _token: AdminToken) -> EmptyResult {
+fn test_smtp(data: Json<InviteData>, _token: AdminToken) -> JsonResult {
     let data: InviteData = data.into_inner();
 
-    if CONFIG.mail_enabled() {
-        mail::send_test(&data.email)
-    } else {
+    if !CONFIG.mail_enabled() {
         err!("Mail is not enabled")
     }
+
+    let mut transcript = Vec::new();
+    let result = mail::send_test(&data.email, &mut transcript);
+    if let Err(e) = &result {
+        transcript.push(format!("Error: {}", e.message()));
+    }
+
+    Ok(Json(json!({
+        "Success": result.is_ok(),
+        "Transcript": transcript,
+    })))
 }
 
 #[get("/logout")]
-fn logout(mut cookies: Cookies, referer: Referer) -> Redirect {
+fn logout(_ip: AdminIpAllowed, mut cookies: Cookies, referer: Referer) -> Redirect {
+    // Revoke the session, so its token can't be used anymore even if it was copied somewhere else
+    if let Some(Ok(claims)) = cookies.get(COOKIE_NAME).map(|cookie| decode_admin(cookie.value())) {
+        revoke_admin_session(&claims.jti, claims.exp);
+    }
     cookies.remove(Cookie::named(COOKIE_NAME));
     Redirect::to(admin_url(referer))
 }
 
-#[get("/users")]
-fn get_users_json(_token: AdminToken, conn: DbConn) -> Json<Value> {
-    let users = User::get_all(&conn);
+/// Lists the users, the ones whose email or name contain `search` when it's set. With a `limit`, the users
+/// are returned in pages of that size, `page` starting at 1.
+#[get("/users?<page>&<limit>&<search>")]
+fn get_users_json(
+    page: Option<i64>,
+    limit: Option<i64>,
+    search: Option<String>,
+    _token: AdminToken,
+    conn: DbConn,
+) -> JsonResult {
+    if limit.map_or(false, |limit| limit < 1) {
+        err!("The limit must be at least 1")
+    }
+    if page.map_or(false, |page| page < 1) {
+        err!("The page must be at least 1")
+    }
+
+    let search = search.as_deref().map(str::trim).filter(|search| !search.is_empty());
+    let offset = (page.unwrap_or(1) - 1).saturating_mul(limit.unwrap_or(0));
+
+    let users = User::find_page(search, offset, limit, &conn);
     let users_json: Vec<Value> = users.iter().map(|u| u.to_json(&conn)).collect();
 
-    Json(Value::Array(users_json))
+    Ok(Json(Value::Array(users_json)))
+}
+
+/// Number of users shown in each page of the users overview
+const USERS_PAGE_SIZE: usize = 50;
+
+#[derive(FromForm)]
+struct UsersQuery {
+    page: Option<usize>,
+    search: Option<String>,
+    filter: Option<String>,
+    sort: Option<String>,
 }
 
-#[get("/users/overview")]
-fn users_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
-    let users = User::get_all(&conn);
+/// Checks the filters of the users overview, the ones which need more queries are only checked when selected
+fn user_matches_filter(user: &User, filter: &str, conn: &DbConn) -> bool {
+    match filter {
+        "disabled" => !user.enabled,
+        "no-2fa" => TwoFactor::find_by_user(&user.uuid, conn).is_empty(),
+        "never-logged-in" => user.last_active(conn).is_none(),
+        _ => true,
+    }
+}
+
+#[get("/users/overview?<query..>")]
+fn users_overview(query: Form<UsersQuery>, _token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
+    let query = query.into_inner();
+    let search = query.search.as_deref().map(str::trim).unwrap_or_default().to_lowercase();
+    let filter = query.filter.as_deref().unwrap_or_default();
+
+    let sort = query.sort.as_deref().unwrap_or_default();
+
+    let mut users: Vec<User> = User::get_all(&conn)
+        .into_iter()
+        .filter(|u| {
+            search.is_empty() || u.email.to_lowercase().contains(&search) || u.name.to_lowercase().contains(&search)
+        })
+        .filter(|u| user_matches_filter(u, filter, &conn))
+        .collect();
+
+    // The newest first, the users who never logged in are shown last when sorting by activity
+    match sort {
+        "created" => users.sort_by(|a, b| b.created_at.cmp(&a.created_at)),
+        "last-active" => users.sort_by_cached_key(|u| std::cmp::Reverse(u.last_active(&conn))),
+        "email" => users.sort_by(|a, b| a.email.cmp(&b.email)),
+        _ => (),
+    }
+
+    let total = users.len();
+    let page_count = ((total + USERS_PAGE_SIZE - 1) / USERS_PAGE_SIZE).max(1);
+    let page = query.page.unwrap_or(1).clamp(1, page_count);
+
     let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
     let users_json: Vec<Value> = users
         .iter()
+        .skip((page - 1) * USERS_PAGE_SIZE)
+        .take(USERS_PAGE_SIZE)
         .map(|u| {
             let mut usr = u.to_json(&conn);
             usr["cipher_count"] = json!(Cipher::count_owned_by_user(&u.uuid, &conn));
             usr["attachment_count"] = json!(Attachment::count_by_user(&u.uuid, &conn));
-            usr["attachment_size"] = json!(get_display_size(Attachment::size_by_user(&u.uuid, &conn) as i32));
+            usr["attachment_size"] = json!(get_display_size(Attachment::size_by_user(&u.uuid, &conn)));
             usr["user_enabled"] = json!(u.enabled);
             usr["created_at"] = json!(format_naive_datetime_local(&u.created_at, dt_fmt));
             usr["last_active"] = match u.last_active(&conn) {
                 Some(dt) => json!(format_naive_datetime_local(&dt, dt_fmt)),
                 None => json!("Never"),
             };
+
+            let mut client_types: Vec<&str> =
+                Device::find_by_user(&u.uuid, &conn).iter().map(Device::type_name).collect();
+            client_types.sort_unstable();
+            client_types.dedup();
+            usr["client_types"] = json!(client_types);
             usr
         })
         .collect();
 
-    let text = AdminTemplateData::with_data("admin/users", json!(users_json)).render()?;
+    let page_data = json!({
+        "users": users_json,
+        "search": query.search,
+        "filter": filter,
+        "sort": sort,
+        "total": total,
+        "page": page,
+        "page_count": page_count,
+        "prev_page": if page > 1 { Some(page - 1) } else { None },
+        "next_page": if page < page_count { Some(page + 1) } else { None },
+    });
+
+    let text = AdminTemplateData::with_data("admin/users", page_data).render()?;
     Ok(Html(text))
 }
 
@@ -337,6 +884,98 @@ fn get_user_json(uuid: String, _token: AdminToken, conn: DbConn) -> JsonResult {
     Ok(Json(user.to_json(&conn)))
 }
 
+#[get("/users/<uuid>/devices")]
+fn get_user_devices(uuid: String, _token: AdminToken, conn: DbConn) -> JsonResult {
+    let user = get_user_or_404(&uuid, &conn)?;
+
+    let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
+    let devices: Vec<Value> = Device::find_by_user(&user.uuid, &conn)
+        .iter()
+        .map(|d| {
+            json!({
+                "id": d.uuid,
+                "name": d.name,
+                "type": d.type_name(),
+                "created_at": format_naive_datetime_local(&d.created_at, dt_fmt),
+                "last_active": format_naive_datetime_local(&d.updated_at, dt_fmt),
+                "push_registered": d.push_token.is_some(),
+            })
+        })
+        .collect();
+
+    Ok(Json(Value::Array(devices)))
+}
+
+/// Removes a single device of the user, its tokens stop being accepted and it has to log in again
+#[post("/users/<uuid>/devices/<device_uuid>/deauth")]
+fn deauth_user_device(uuid: String, device_uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let device = Device::find_by_uuid_and_user(&device_uuid, &uuid, &conn).map_res("Device doesn't exist")?;
+    device.delete(&conn)
+}
+
+/// Sends the invitation email again to a user who hasn't registered yet, the previous link may have expired
+#[post("/users/<uuid>/invite/resend")]
+fn resend_user_invite(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let user = get_user_or_404(&uuid, &conn)?;
+    if !user.password_hash.is_empty() {
+        err!("The user has already registered")
+    }
+
+    if CONFIG.mail_enabled() {
+        mail::send_invite(&user.email, &user.uuid, None, None, &CONFIG.invitation_org_name(), None, None)
+    } else {
+        err!("Invitations can only be resent with mail enabled, the user can register directly")
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangeEmailData {
+    email: String,
+    send_invite: Option<bool>,
+}
+
+/// Changes the email of a user who hasn't registered yet, for example when the invitation was sent to an old address.
+/// The email of a registered user is the salt of its master key, so it can only be changed from the web vault.
+#[post("/users/<uuid>/email", data = "<data>")]
+fn change_user_email(uuid: String, data: Json<ChangeEmailData>, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let data: ChangeEmailData = data.into_inner();
+    let mut user = get_user_or_404(&uuid, &conn)?;
+    if !user.password_hash.is_empty() {
+        err!("The user has already registered, its email can only be changed from the web vault")
+    }
+
+    let new_email = data.email.trim().to_lowercase();
+    if !new_email.contains('@') {
+        err!("Invalid email address")
+    }
+    if User::find_by_mail(&new_email, &conn).is_some() {
+        err_code!("Email already in use", Status::Conflict.code)
+    }
+    if !CONFIG.is_email_domain_allowed(&new_email) {
+        err!("Email domain not allowed")
+    }
+
+    let old_email = std::mem::replace(&mut user.email, new_email.clone());
+    if user.name == old_email {
+        user.name = new_email.clone();
+    }
+    user.verified_at = None;
+    user.clear_email_change();
+    user.save(&conn)?;
+
+    // Invitations without mail are stored by email, move it to the new address
+    if Invitation::take(&old_email, &conn) {
+        Invitation::new(new_email.clone()).save(&conn)?;
+    }
+
+    if CONFIG.mail_enabled() && data.send_invite.unwrap_or(true) {
+        mail::send_invite(&new_email, &user.uuid, None, None, &CONFIG.invitation_org_name(), None, None)?;
+    }
+
+    info!("Admin changed the email of invited user {} from {} to {}", user.uuid, old_email, new_email);
+    Ok(())
+}
+
 #[post("/users/<uuid>/delete")]
 fn delete_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
     let user = get_user_or_404(&uuid, &conn)?;
@@ -370,12 +1009,35 @@ fn enable_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
     user.save(&conn)
 }
 
+/// Removes all the two-factor methods of a locked out user, the removal is logged as there is no other trace of it
 #[post("/users/<uuid>/remove-2fa")]
-fn remove_2fa(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+fn remove_2fa(uuid: String, _token: AdminToken, ip: ClientIp, conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(&uuid, &conn)?;
+    let twofactors = TwoFactor::find_by_user(&user.uuid, &conn);
     TwoFactor::delete_all_by_user(&user.uuid, &conn)?;
     user.totp_recover = None;
-    user.save(&conn)
+    user.save(&conn)?;
+
+    let types: Vec<String> = twofactors
+        .iter()
+        .filter(|tf| tf.atype < TwoFactorType::U2fRegisterChallenge as i32)
+        .filter_map(|tf| TwoFactorType::from_i32(tf.atype))
+        .map(|t| format!("{:?}", t))
+        .collect();
+    warn!("Admin removed the two-factor methods of {} (types: {}). IP: {}", user.email, types.join(", "), ip.ip);
+    Ok(())
+}
+
+/// Makes the clients of the user ask for a new master password, until it's changed
+#[post("/users/<uuid>/force-password-reset")]
+fn force_password_reset(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let mut user = get_user_or_404(&uuid, &conn)?;
+    user.force_password_reset = true;
+    user.save(&conn)?;
+
+    // Make the clients sync, as they get the flag from the profile
+    User::update_uuid_revision(&user.uuid, &conn);
+    Ok(())
 }
 
 #[derive(Deserialize, Debug)]
@@ -399,17 +1061,35 @@ fn update_user_org_type(data: Json<UserOrgTypeData>, _token: AdminToken, conn: D
         None => err!("Invalid type"),
     };
 
-    if user_to_edit.atype == UserOrgType::Owner && new_type != UserOrgType::Owner {
+    if new_type != UserOrgType::Owner && user_to_edit.is_last_confirmed_owner(&conn) {
         // Removing owner permmission, check that there are at least another owner
-        let num_owners = UserOrganization::find_by_org_and_type(&data.org_uuid, UserOrgType::Owner as i32, &conn).len();
+        err!("Can't change the type of the last owner")
+    }
 
-        if num_owners <= 1 {
-            err!("Can't change the type of the last owner")
-        }
+    // Only the confirmed members have the organization key, an owner without it can't manage the organization
+    if new_type == UserOrgType::Owner && !user_to_edit.has_status(UserOrgStatus::Confirmed) {
+        err!("Only confirmed members can be made owners")
     }
 
     user_to_edit.atype = new_type as i32;
-    user_to_edit.save(&conn)
+    user_to_edit.save(&conn)?;
+
+    User::update_uuid_revision(&user_to_edit.user_uuid, &conn);
+    Ok(())
+}
+
+/// Promotes a confirmed member to owner, for example when the only owner of the organization left
+#[post("/organizations/<org_uuid>/members/<member_uuid>/make-owner")]
+fn make_organization_owner(org_uuid: String, member_uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let member = UserOrganization::find_by_uuid_and_org(&member_uuid, &org_uuid, &conn)
+        .map_res("The specified user isn't member of the organization")?;
+
+    let data = UserOrgTypeData {
+        user_type: NumberOrString::Number(UserOrgType::Owner as i32),
+        user_uuid: member.user_uuid,
+        org_uuid,
+    };
+    update_user_org_type(Json(data), AdminToken {}, conn)
 }
 
 #[post("/users/update_revision")]
@@ -420,6 +1100,7 @@ fn update_revision_users(_token: AdminToken, conn: DbConn) -> EmptyResult {
 #[get("/organizations/overview")]
 fn organizations_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
     let organizations = Organization::get_all(&conn);
+    let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
     let organizations_json: Vec<Value> = organizations
         .iter()
         .map(|o| {
@@ -427,7 +1108,33 @@ fn organizations_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<St
             org["user_count"] = json!(UserOrganization::count_by_org(&o.uuid, &conn));
             org["cipher_count"] = json!(Cipher::count_by_org(&o.uuid, &conn));
             org["attachment_count"] = json!(Attachment::count_by_org(&o.uuid, &conn));
-            org["attachment_size"] = json!(get_display_size(Attachment::size_by_org(&o.uuid, &conn) as i32));
+            org["attachment_size"] = json!(get_display_size(Attachment::size_by_org(&o.uuid, &conn)));
+            org["attachment_limit"] = match CONFIG.org_attachment_limit() {
+                Some(limit_kb) => json!(get_display_size(limit_kb * 1024)),
+                None => json!(null),
+            };
+            org["created_at"] = match o.created_at {
+                Some(dt) => json!(format_naive_datetime_local(&dt, dt_fmt)),
+                None => json!("Unknown"),
+            };
+            let members: Vec<Value> = UserOrganization::find_by_org(&o.uuid, &conn)
+                .iter()
+                .map(|m| {
+                    json!({
+                        "id": m.uuid,
+                        "email": User::find_by_uuid(&m.user_uuid, &conn).map(|u| u.email).unwrap_or_default(),
+                        "type": match UserOrgType::from_i32(m.atype) {
+                            Some(UserOrgType::Owner) => "Owner",
+                            Some(UserOrgType::Admin) => "Admin",
+                            Some(UserOrgType::Manager) => "Manager",
+                            _ => "User",
+                        },
+                        "is_owner": m.atype == UserOrgType::Owner as i32,
+                        "confirmed": m.has_status(UserOrgStatus::Confirmed),
+                    })
+                })
+                .collect();
+            org["members"] = json!(members);
             org
         })
         .collect();
@@ -436,12 +1143,313 @@ fn organizations_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<St
     Ok(Html(text))
 }
 
+/// Vault statistics of every user and organization, so the growth of the instance can be monitored.
+/// The attachment sizes are in bytes.
+#[get("/stats")]
+fn get_stats_json(_token: AdminToken, conn: DbConn) -> Json<Value> {
+    let users_json: Vec<Value> = User::get_all(&conn)
+        .iter()
+        .map(|u| {
+            json!({
+                "id": u.uuid,
+                "email": u.email,
+                "cipher_count": Cipher::count_owned_by_user(&u.uuid, &conn),
+                "attachment_count": Attachment::count_by_user(&u.uuid, &conn),
+                "attachment_size": Attachment::size_by_user(&u.uuid, &conn),
+                "folder_count": Folder::count_by_user(&u.uuid, &conn),
+                "send_count": Send::count_by_user(&u.uuid, &conn),
+            })
+        })
+        .collect();
+
+    let organizations_json: Vec<Value> = Organization::get_all(&conn)
+        .iter()
+        .map(|o| {
+            json!({
+                "id": o.uuid,
+                "name": o.name,
+                "user_count": UserOrganization::count_by_org(&o.uuid, &conn),
+                "cipher_count": Cipher::count_by_org(&o.uuid, &conn),
+                "attachment_count": Attachment::count_by_org(&o.uuid, &conn),
+                "attachment_size": Attachment::size_by_org(&o.uuid, &conn),
+                "send_count": Send::count_by_org(&o.uuid, &conn),
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "users": users_json,
+        "organizations": organizations_json,
+    }))
+}
+
 #[post("/organizations/<uuid>/delete")]
 fn delete_organization(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
     let org = Organization::find_by_uuid(&uuid, &conn).map_res("Organization doesn't exist")?;
     org.delete(&conn)
 }
 
+/// Details of the active sends which are useful to handle abuse reports, their contents are encrypted
+fn get_sends_data(conn: &DbConn) -> Vec<Value> {
+    let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
+    let users: HashMap<String, String> = User::get_all(conn).into_iter().map(|u| (u.uuid, u.email)).collect();
+
+    Send::find_active(conn)
+        .iter()
+        .map(|s| {
+            let size = s.file_size();
+            json!({
+                "id": s.uuid,
+                "user_uuid": s.user_uuid,
+                "user_email": s.user_uuid.as_ref().and_then(|uuid| users.get(uuid)),
+                "type": if s.atype == SendType::File as i32 { "File" } else { "Text" },
+                "size": size,
+                "display_size": size.map(get_display_size),
+                "access_count": s.access_count,
+                "max_access_count": s.max_access_count,
+                "has_password": s.password_hash.is_some(),
+                "disabled": s.disabled,
+                "expiration_date": s.expiration_date.as_ref().map(|d| format_naive_datetime_local(d, dt_fmt)),
+                "deletion_date": format_naive_datetime_local(&s.deletion_date, dt_fmt),
+            })
+        })
+        .collect()
+}
+
+#[get("/sends/overview")]
+fn sends_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
+    let text = AdminTemplateData::with_data("admin/sends", json!(get_sends_data(&conn))).render()?;
+    Ok(Html(text))
+}
+
+#[get("/sends")]
+fn get_sends_json(_token: AdminToken, conn: DbConn) -> Json<Value> {
+    Json(Value::Array(get_sends_data(&conn)))
+}
+
+#[post("/sends/<uuid>/delete")]
+fn delete_send(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let send = Send::find_by_uuid(&uuid, &conn).map_res("Send doesn't exist")?;
+    send.delete(&conn)
+}
+
+/// Sums the size of the files of the file sends, and returns it together with the number of sends
+fn sends_storage(sends: &[Send]) -> (usize, i64) {
+    let sizes: Vec<i64> = sends.iter().filter_map(Send::file_size).collect();
+    (sizes.len(), sizes.iter().sum())
+}
+
+/// Compares the files referenced in the database with the ones in the storage, and returns
+/// the referenced files which are missing and the stored files which aren't referenced anymore
+fn verify_storage(area: FileArea, referenced: &HashSet<String>) -> Value {
+    let stored = match get_storage(area).list() {
+        Ok(stored) => stored,
+        Err(e) => return json!({ "error": format!("{:?}", e) }),
+    };
+
+    let stored_paths: HashSet<&str> = stored.iter().map(|(path, _)| path.as_str()).collect();
+    let mut missing: Vec<&String> = referenced.iter().filter(|p| !stored_paths.contains(p.as_str())).collect();
+    missing.sort();
+
+    let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
+    let orphaned: Vec<Value> = stored
+        .iter()
+        .filter(|(path, _)| !referenced.contains(path))
+        .map(|(path, modified)| {
+            json!({
+                "path": path,
+                "modified": DateTime::<Local>::from(*modified).format(dt_fmt).to_string(),
+            })
+        })
+        .collect();
+
+    json!({
+        "stored_count": stored.len(),
+        "missing": missing,
+        "orphaned": orphaned,
+    })
+}
+
+fn get_storage_data(conn: &DbConn) -> Value {
+    // The sends past their deletion date still have their files until they are purged
+    let mut sends = Send::find_active(conn);
+    sends.extend(Send::find_by_past_deletion_date(conn));
+    let (_, sends_size) = sends_storage(&sends);
+    let send_paths: HashSet<String> = sends.iter().filter_map(Send::file_storage_path).collect();
+
+    // The size of the Send files is only known from their data, so they can't be summed by the database
+    let mut user_sends: HashMap<&str, (usize, i64)> = HashMap::new();
+    let mut org_sends: HashMap<&str, (usize, i64)> = HashMap::new();
+    for send in &sends {
+        let size = match send.file_size() {
+            Some(size) => size,
+            None => continue,
+        };
+        let owner = match (&send.user_uuid, &send.organization_uuid) {
+            (Some(user_uuid), _) => user_sends.entry(user_uuid.as_str()).or_default(),
+            (None, Some(org_uuid)) => org_sends.entry(org_uuid.as_str()).or_default(),
+            (None, None) => continue,
+        };
+        owner.0 += 1;
+        owner.1 += size;
+    }
+
+    let user_attachments = Attachment::storage_by_user(conn);
+    let mut users: Vec<(i64, Value)> = User::get_all(conn)
+        .iter()
+        .filter_map(|u| {
+            let (attachment_count, attachment_size) = user_attachments.get(&u.uuid).copied().unwrap_or_default();
+            let (send_count, send_size) = user_sends.get(u.uuid.as_str()).copied().unwrap_or_default();
+            if attachment_count == 0 && send_count == 0 {
+                return None;
+            }
+
+            let total = attachment_size + send_size;
+            Some((
+                total,
+                json!({
+                    "email": u.email,
+                    "attachment_count": attachment_count,
+                    "attachment_size": get_display_size(attachment_size),
+                    "send_count": send_count,
+                    "send_size": get_display_size(send_size),
+                    "total_size": get_display_size(total),
+                }),
+            ))
+        })
+        .collect();
+    users.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let org_attachments = Attachment::storage_by_org(conn);
+    let mut organizations: Vec<(i64, Value)> = Organization::get_all(conn)
+        .iter()
+        .filter_map(|o| {
+            let (attachment_count, attachment_size) = org_attachments.get(&o.uuid).copied().unwrap_or_default();
+            let (send_count, send_size) = org_sends.get(o.uuid.as_str()).copied().unwrap_or_default();
+            if attachment_count == 0 && send_count == 0 {
+                return None;
+            }
+
+            let total = attachment_size + send_size;
+            Some((
+                total,
+                json!({
+                    "name": o.name,
+                    "attachment_count": attachment_count,
+                    "attachment_size": get_display_size(attachment_size),
+                    "send_count": send_count,
+                    "send_size": get_display_size(send_size),
+                    "total_size": get_display_size(total),
+                }),
+            ))
+        })
+        .collect();
+    organizations.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let attachments = Attachment::get_all(conn);
+    let attachments_size: i64 = attachments.iter().map(|a| i64::from(a.file_size)).sum();
+    let attachment_paths: HashSet<String> = attachments.iter().map(Attachment::get_storage_path).collect();
+
+    json!({
+        "users": users.into_iter().map(|(_, u)| u).collect::<Vec<Value>>(),
+        "organizations": organizations.into_iter().map(|(_, o)| o).collect::<Vec<Value>>(),
+        "attachments_count": attachments.len(),
+        "attachments_size": get_display_size(attachments_size),
+        "sends_count": send_paths.len(),
+        "sends_size": get_display_size(sends_size),
+        "total_size": get_display_size(attachments_size + sends_size),
+        "attachments_storage": verify_storage(FileArea::Attachments, &attachment_paths),
+        "sends_storage": verify_storage(FileArea::Sends, &send_paths),
+    })
+}
+
+#[get("/storage/overview")]
+fn storage_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
+    let text = AdminTemplateData::with_data("admin/storage", get_storage_data(&conn)).render()?;
+    Ok(Html(text))
+}
+
+#[get("/storage")]
+fn get_storage_json(_token: AdminToken, conn: DbConn) -> Json<Value> {
+    Json(get_storage_data(&conn))
+}
+
+#[get("/mails/overview")]
+fn mail_queue_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
+    let mails: Vec<Value> = QueuedMail::get_all(&conn).iter().map(QueuedMail::to_json).collect();
+    let text = AdminTemplateData::with_data("admin/mails", json!(mails)).render()?;
+    Ok(Html(text))
+}
+
+#[post("/mails/<uuid>/retry")]
+fn retry_queued_mail(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let mut mail = QueuedMail::find_by_uuid(&uuid, &conn).map_res("Email not found in the queue")?;
+    mail.reset();
+    mail.save(&conn)
+}
+
+#[post("/mails/<uuid>/delete")]
+fn delete_queued_mail(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let mail = QueuedMail::find_by_uuid(&uuid, &conn).map_res("Email not found in the queue")?;
+    mail.delete(&conn)
+}
+
+/// Days shown in the metrics page
+const METRICS_DAYS: i64 = 30;
+
+fn get_metrics_data(conn: &DbConn) -> Value {
+    // Save the pending counters first, so the current day is up to date
+    stats::flush(conn);
+
+    let days: Vec<String> = (0..METRICS_DAYS).rev().map(stats::days_ago).collect();
+    let day_stats = Stat::find_since(&days[0], conn);
+
+    // The counters default to 0 for the days without activity, the totals are unknown for those days
+    let series = |name: &str, default: Value| -> Vec<Value> {
+        days.iter()
+            .map(|day| match day_stats.iter().find(|s| &s.day == day && s.name == name) {
+                Some(stat) => json!(stat.value),
+                None => default.clone(),
+            })
+            .collect()
+    };
+
+    json!({
+        "days": days,
+        "users": series("users", Value::Null),
+        "ciphers": series("ciphers", Value::Null),
+        "logins": series("logins", json!(0)),
+        "requests": series("requests", json!(0)),
+        "server_errors": series("server_errors", json!(0)),
+        "job_runs": series("job_runs", json!(0)),
+    })
+}
+
+/// The charts are drawn from the data of `/admin/metrics`, fetched by the page
+#[get("/metrics/overview")]
+fn metrics_overview(_token: AdminToken) -> ApiResult<Html<String>> {
+    let text = AdminTemplateData::with_data("admin/metrics", json!({ "days": METRICS_DAYS })).render()?;
+    Ok(Html(text))
+}
+
+#[get("/metrics")]
+fn get_metrics_json(_token: AdminToken, conn: DbConn) -> Json<Value> {
+    Json(get_metrics_data(&conn))
+}
+
+/// Removes a member from an organization, for example to clean up an organization whose owners are gone
+#[post("/organizations/<org_uuid>/members/<member_uuid>/delete")]
+fn delete_organization_member(org_uuid: String, member_uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let member = UserOrganization::find_by_uuid_and_org(&member_uuid, &org_uuid, &conn)
+        .map_res("The specified user isn't member of the organization")?;
+
+    if member.is_last_confirmed_owner(&conn) {
+        err!("Can't remove the last owner, delete the organization instead")
+    }
+
+    member.delete(&conn)
+}
+
 #[derive(Deserialize)]
 struct WebVaultVersion {
     version: String,
@@ -463,15 +1471,38 @@ fn get_github_api<T: DeserializeOwned>(url: &str) -> Result<T, Error> {
     Ok(github_api.get(url).timeout(Duration::from_secs(10)).send()?.error_for_status()?.json::<T>()?)
 }
 
-fn has_http_access() -> bool {
+/// Checks if we have internet access, and returns the drift in seconds between our clock
+/// and the `Date` header of the response, which comes from an NTP synchronized server
+fn check_http_access() -> (bool, Option<i64>) {
+    use chrono::Utc;
+
     let http_access = get_reqwest_client();
 
     match http_access.head("https://github.com/dani-garcia/vaultwarden").timeout(Duration::from_secs(10)).send() {
-        Ok(r) => r.status().is_success(),
-        _ => false,
+        Ok(r) => {
+            let time_drift = r
+                .headers()
+                .get(reqwest::header::DATE)
+                .and_then(|d| d.to_str().ok())
+                .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+                .map(|d| Utc::now().signed_duration_since(d).num_seconds());
+            (r.status().is_success(), time_drift)
+        }
+        _ => (false, None),
     }
 }
 
+/// Runs a trivial query to measure the round trip time to the database
+fn get_db_latency_ms(conn: &DbConn) -> f64 {
+    let start = Instant::now();
+    get_sql_server_version(conn);
+    start.elapsed().as_secs_f64() * 1000.0
+}
+
+/// Maximum drift in seconds between the server clock and the reference time before warning about it,
+/// the TOTP codes and the tokens stop being accepted when the drift gets too big
+const MAX_TIME_DRIFT_SECS: i64 = 30;
+
 #[get("/diagnostics")]
 fn diagnostics(_token: AdminToken, ip_header: IpHeader, conn: DbConn) -> ApiResult<Html<String>> {
     use crate::util::read_file_string;
@@ -492,7 +1523,7 @@ fn diagnostics(_token: AdminToken, ip_header: IpHeader, conn: DbConn) -> ApiResu
 
     // Execute some environment checks
     let running_within_docker = is_running_in_docker();
-    let has_http_access = has_http_access();
+    let (has_http_access, time_drift) = check_http_access();
     let uses_proxy = env::var_os("HTTP_PROXY").is_some()
         || env::var_os("http_proxy").is_some()
         || env::var_os("HTTPS_PROXY").is_some()
@@ -557,6 +1588,10 @@ fn diagnostics(_token: AdminToken, ip_header: IpHeader, conn: DbConn) -> ApiResu
         "uses_proxy": uses_proxy,
         "db_type": *DB_TYPE,
         "db_version": get_sql_server_version(&conn),
+        "db_latency": format!("{:.2}", get_db_latency_ms(&conn)),
+        "time_drift": time_drift,
+        "time_drift_checked": time_drift.is_some(),
+        "time_drift_ok": time_drift.map_or(false, |d| d.abs() <= MAX_TIME_DRIFT_SECS),
         "admin_url": format!("{}/diagnostics", admin_url(Referer(None))),
         "overrides": &CONFIG.get_overrides().join(", "),
         "server_time_local": Local::now().format("%Y-%m-%d %H:%M:%S %Z").to_string(),
@@ -584,12 +1619,188 @@ fn delete_config(_token: AdminToken) -> EmptyResult {
     CONFIG.delete_user_config()
 }
 
+#[get("/config/profile")]
+fn export_config_profile(_token: AdminToken) -> Json<Value> {
+    Json(CONFIG.export_profile())
+}
+
+#[post("/config/profile", data = "<data>")]
+fn import_config_profile(data: Json<Value>, _token: AdminToken) -> EmptyResult {
+    CONFIG.import_profile(data.into_inner())
+}
+
+/// Creates a backup of the database in the backups folder, and returns its file name
 #[post("/config/backup_db")]
-fn backup_db(_token: AdminToken, conn: DbConn) -> EmptyResult {
-    if *CAN_BACKUP {
-        backup_database(&conn)
-    } else {
-        err!("Can't back up current DB (Only SQLite supports this feature)");
+fn backup_db(_token: AdminToken, conn: DbConn) -> JsonResult {
+    let backup_file = backup_database(&conn)?;
+    let file_name = std::path::Path::new(&backup_file).file_name().map(|n| n.to_string_lossy().into_owned());
+
+    Ok(Json(json!({
+        "File": file_name,
+    })))
+}
+
+#[derive(Deserialize)]
+struct PurgeData {
+    devices_inactive_days: Option<i64>,
+}
+
+/// Longest inactivity of the devices which can be purged, ten years
+const MAX_PURGE_INACTIVE_DAYS: i64 = 10 * 365;
+
+/// Permanently deletes the data which isn't needed anymore, and returns how much of each kind was removed.
+/// The devices are only deleted when `devices_inactive_days` is given, their users have to log in again on them
+#[post("/config/purge", data = "<data>")]
+fn purge_data(data: Json<PurgeData>, _token: AdminToken, conn: DbConn) -> JsonResult {
+    let data: PurgeData = data.into_inner();
+
+    if let Some(days) = data.devices_inactive_days {
+        if !(1..=MAX_PURGE_INACTIVE_DAYS).contains(&days) {
+            err_code!(
+                format!("The inactivity of the devices must be between 1 and {} days", MAX_PURGE_INACTIVE_DAYS),
+                Status::BadRequest.code
+            )
+        }
+    }
+
+    let trashed_ciphers = Cipher::purge_trash(&conn);
+
+    let mut orphaned_attachments = 0;
+    for attachment in Attachment::find_orphaned(&conn) {
+        attachment.delete(&conn)?;
+        orphaned_attachments += 1;
+    }
+    let orphaned_attachment_files = crate::api::core::purge_orphaned_attachment_files(&conn);
+
+    let mut dangling_collection_users = 0;
+    for collection_user in CollectionUser::find_dangling(&conn) {
+        collection_user.delete(&conn)?;
+        dangling_collection_users += 1;
+    }
+
+    let mut inactive_devices = 0;
+    if let Some(days) = data.devices_inactive_days {
+        let dt = chrono::Utc::now().naive_utc() - chrono::Duration::days(days);
+        for device in Device::find_inactive_since(&dt, &conn) {
+            device.delete(&conn)?;
+            inactive_devices += 1;
+        }
+    }
+
+    info!(
+        "Purged {} trashed ciphers, {} orphaned attachments and {} of their files, {} collection accesses, {} devices",
+        trashed_ciphers, orphaned_attachments, orphaned_attachment_files, dangling_collection_users, inactive_devices
+    );
+
+    Ok(Json(json!({
+        "TrashedCiphers": trashed_ciphers,
+        "OrphanedAttachments": orphaned_attachments,
+        "OrphanedAttachmentFiles": orphaned_attachment_files,
+        "DanglingCollectionUsers": dangling_collection_users,
+        "InactiveDevices": inactive_devices,
+    })))
+}
+
+/// Reloads the templates, so the changes to the overrides of the templates folder are applied without restarting
+#[post("/templates/reload")]
+fn reload_templates(_token: AdminToken) -> JsonResult {
+    let overrides = CONFIG.reload_templates_folder()?;
+
+    Ok(Json(json!({
+        "Overrides": overrides,
+    })))
+}
+
+//
+// JSON API, authenticated with the admin API token instead of the session cookie.
+// The routes reuse the handlers of the admin panel with the same behavior.
+//
+
+#[get("/api/users?<page>&<limit>&<search>")]
+fn api_get_users(
+    page: Option<i64>,
+    limit: Option<i64>,
+    search: Option<String>,
+    _token: AdminApiToken,
+    conn: DbConn,
+) -> JsonResult {
+    get_users_json(page, limit, search, AdminToken {}, conn)
+}
+
+#[get("/api/users/<uuid>")]
+fn api_get_user(uuid: String, _token: AdminApiToken, conn: DbConn) -> JsonResult {
+    get_user_json(uuid, AdminToken {}, conn)
+}
+
+#[post("/api/users", data = "<data>")]
+fn api_invite_user(data: Json<InviteData>, _token: AdminApiToken, conn: DbConn) -> JsonResult {
+    invite_user(data, AdminToken {}, conn)
+}
+
+#[delete("/api/users/<uuid>")]
+fn api_delete_user(uuid: String, _token: AdminApiToken, conn: DbConn) -> EmptyResult {
+    delete_user(uuid, AdminToken {}, conn)
+}
+
+#[post("/api/users/<uuid>/deauth")]
+fn api_deauth_user(uuid: String, _token: AdminApiToken, conn: DbConn) -> EmptyResult {
+    deauth_user(uuid, AdminToken {}, conn)
+}
+
+#[post("/api/users/<uuid>/disable")]
+fn api_disable_user(uuid: String, _token: AdminApiToken, conn: DbConn) -> EmptyResult {
+    disable_user(uuid, AdminToken {}, conn)
+}
+
+#[post("/api/users/<uuid>/enable")]
+fn api_enable_user(uuid: String, _token: AdminApiToken, conn: DbConn) -> EmptyResult {
+    enable_user(uuid, AdminToken {}, conn)
+}
+
+#[get("/api/organizations")]
+fn api_get_organizations(_token: AdminApiToken, conn: DbConn) -> Json<Value> {
+    let organizations_json: Vec<Value> = Organization::get_all(&conn)
+        .iter()
+        .map(|o| {
+            let mut org = o.to_json();
+            org["user_count"] = json!(UserOrganization::count_by_org(&o.uuid, &conn));
+            org["cipher_count"] = json!(Cipher::count_by_org(&o.uuid, &conn));
+            org
+        })
+        .collect();
+
+    Json(Value::Array(organizations_json))
+}
+
+#[get("/api/config")]
+fn api_get_config(_token: AdminApiToken) -> Json<Value> {
+    export_config_profile(AdminToken {})
+}
+
+#[post("/api/backup")]
+fn api_backup_db(_token: AdminApiToken, conn: DbConn) -> JsonResult {
+    backup_db(AdminToken {}, conn)
+}
+
+/// Rejects the requests from the IPs not listed in ADMIN_ALLOWED_IPS. The admin routes use it directly,
+/// or through the AdminToken, AdminApiToken and SetupAllowed guards
+struct AdminIpAllowed;
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminIpAllowed {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let ip = match request.guard::<ClientIp>() {
+            Outcome::Success(ip) => ip.ip,
+            _ => err_handler!("Error getting Client IP"),
+        };
+
+        if CONFIG.is_admin_ip_allowed(&ip) {
+            Outcome::Success(AdminIpAllowed)
+        } else {
+            error!("Admin panel access from a not allowed IP: {}", ip);
+            Outcome::Failure((Status::Forbidden, "The admin panel can't be accessed from this IP"))
+        }
     }
 }
 
@@ -599,6 +1810,10 @@ impl<'a, 'r> FromRequest<'a, 'r> for AdminToken {
     type Error = &'static str;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        if let Outcome::Failure(e) = request.guard::<AdminIpAllowed>() {
+            return Outcome::Failure(e);
+        }
+
         if CONFIG.disable_admin_token() {
             Outcome::Success(AdminToken {})
         } else {
@@ -614,14 +1829,52 @@ impl<'a, 'r> FromRequest<'a, 'r> for AdminToken {
                 _ => err_handler!("Error getting Client IP"),
             };
 
-            if decode_admin(access_token).is_err() {
-                // Remove admin cookie
-                cookies.remove(Cookie::named(COOKIE_NAME));
-                error!("Invalid or expired admin JWT. IP: {}.", ip);
-                return Outcome::Forward(());
+            let claims = match decode_admin(access_token) {
+                Ok(claims) => claims,
+                Err(_) => {
+                    // Remove admin cookie
+                    cookies.remove(Cookie::named(COOKIE_NAME));
+                    error!("Invalid or expired admin JWT. IP: {}.", ip);
+                    return Outcome::Forward(());
+                }
+            };
+
+            if claims.needs_renewal() {
+                add_admin_cookie(&mut cookies, &generate_admin_claims(Some(claims.jti)));
             }
 
             Outcome::Success(AdminToken {})
         }
     }
 }
+
+pub struct AdminApiToken {}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminApiToken {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        if let Outcome::Failure(e) = request.guard::<AdminIpAllowed>() {
+            return Outcome::Failure(e);
+        }
+
+        let api_token = match CONFIG.admin_api_token() {
+            Some(token) if !token.trim().is_empty() => token,
+            _ => return Outcome::Failure((Status::NotFound, "The admin API is disabled")),
+        };
+
+        let ip = match request.guard::<ClientIp>() {
+            Outcome::Success(ip) => ip.ip,
+            _ => err_handler!("Error getting Client IP"),
+        };
+
+        let token = request.headers().get_one("Authorization").and_then(|h| h.strip_prefix("Bearer "));
+        match token {
+            Some(token) if crate::crypto::ct_eq(api_token.trim(), token.trim()) => Outcome::Success(AdminApiToken {}),
+            _ => {
+                error!("Invalid admin API token. IP: {}", ip);
+                Outcome::Failure((Status::Unauthorized, "Invalid admin API token"))
+            }
+        }
+    }
+}
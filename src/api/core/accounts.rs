@@ -1,4 +1,4 @@
-use chrono::Utc;
+use chrono::{Duration, Utc};
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
@@ -16,6 +16,8 @@ pub fn routes() -> Vec<rocket::Route> {
         profile,
         put_profile,
         post_profile,
+        put_trash_retention,
+        post_trash_retention,
         get_public_keys,
         post_keys,
         post_password,
@@ -30,6 +32,7 @@ pub fn routes() -> Vec<rocket::Route> {
         post_delete_recover_token,
         delete_account,
         post_delete_account,
+        post_export,
         revision_date,
         password_hint,
         prelogin,
@@ -140,8 +143,10 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
             }
 
             user.last_verifying_at = Some(user.created_at);
-        } else if let Err(e) = mail::send_welcome(&user.email) {
-            error!("Error sending welcome email: {:#?}", e);
+        } else if CONFIG.welcome_email() {
+            if let Err(e) = mail::send_welcome(&user.email) {
+                error!("Error sending welcome email: {:#?}", e);
+            }
         }
     }
 
@@ -182,6 +187,40 @@ fn post_profile(data: JsonUpcase<ProfileData>, headers: Headers, conn: DbConn) -
     Ok(Json(user.to_json(&conn)))
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct TrashRetentionData {
+    Days: Option<i32>,
+}
+
+#[put("/accounts/trash-retention", data = "<data>")]
+fn put_trash_retention(data: JsonUpcase<TrashRetentionData>, headers: Headers, conn: DbConn) -> JsonResult {
+    post_trash_retention(data, headers, conn)
+}
+
+/// Sets the number of days after which the trashed items of the user are deleted permanently.
+/// Sending no value reverts to the retention configured for the instance.
+#[post("/accounts/trash-retention", data = "<data>")]
+fn post_trash_retention(data: JsonUpcase<TrashRetentionData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: TrashRetentionData = data.into_inner().data;
+
+    if let Some(days) = data.Days {
+        if days < 1 {
+            err!("The trash retention must be at least 1 day")
+        }
+        if let Some(max_days) = CONFIG.trash_auto_delete_days() {
+            if i64::from(days) > max_days {
+                err!(format!("The trash retention can't be longer than {} days", max_days))
+            }
+        }
+    }
+
+    let mut user = headers.user;
+    user.trash_auto_delete_days = data.Days;
+    user.save(&conn)?;
+    Ok(Json(user.to_json(&conn)))
+}
+
 #[get("/users/<uuid>/public-key")]
 fn get_public_keys(uuid: String, _headers: Headers, conn: DbConn) -> JsonResult {
     let user = match User::find_by_uuid(&uuid, &conn) {
@@ -233,6 +272,7 @@ fn post_password(data: JsonUpcase<ChangePassData>, headers: Headers, conn: DbCon
 
     user.set_password(&data.NewMasterPasswordHash, Some("post_rotatekey"));
     user.akey = data.Key;
+    user.force_password_reset = false;
     user.save(&conn)
 }
 
@@ -260,6 +300,7 @@ fn post_kdf(data: JsonUpcase<ChangeKdfData>, headers: Headers, conn: DbConn) ->
     user.client_kdf_type = data.Kdf;
     user.set_password(&data.NewMasterPasswordHash, None);
     user.akey = data.Key;
+    user.force_password_reset = false;
     user.save(&conn)
 }
 
@@ -271,19 +312,29 @@ struct UpdateFolderData {
 }
 
 use super::ciphers::CipherData;
+use super::sends::SendData;
 
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct KeyData {
     Ciphers: Vec<CipherData>,
     Folders: Vec<UpdateFolderData>,
+    Sends: Option<Vec<SendData>>,
     Key: String,
     PrivateKey: String,
     MasterPasswordHash: String,
 }
 
+/// Replaces the user key. All the folders, personal ciphers and sends of the user need to be sent
+/// re-encrypted with the new key, otherwise they would become unreadable, and are saved in a single
+/// transaction. Resetting the security stamp logs out all the other sessions.
 #[post("/accounts/key", data = "<data>")]
 fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
+    use std::collections::HashSet;
+
+    use super::ciphers::update_cipher_from_data;
+    use super::sends::update_send_from_data;
+
     let data: KeyData = data.into_inner().data;
 
     if !headers.user.check_valid_password(&data.MasterPasswordHash) {
@@ -291,47 +342,80 @@ fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, conn: DbConn, nt:
     }
 
     let user_uuid = &headers.user.uuid;
+    let sends_data = data.Sends.unwrap_or_default();
 
-    // Update folder data
-    for folder_data in data.Folders {
-        let mut saved_folder = match Folder::find_by_uuid(&folder_data.Id, &conn) {
-            Some(folder) => folder,
-            None => err!("Folder doesn't exist"),
-        };
+    let folder_ids: HashSet<String> = Folder::find_by_user(user_uuid, &conn).into_iter().map(|f| f.uuid).collect();
+    let data_folder_ids: HashSet<String> = data.Folders.iter().map(|f| f.Id.clone()).collect();
+    if folder_ids != data_folder_ids || data.Folders.len() != data_folder_ids.len() {
+        err!("All the folders need to be re-encrypted with the new key")
+    }
 
-        if &saved_folder.user_uuid != user_uuid {
-            err!("The folder is not owned by the user")
-        }
+    let cipher_ids: HashSet<String> =
+        Cipher::find_owned_by_user(user_uuid, &conn).into_iter().map(|c| c.uuid).collect();
+    let data_cipher_ids: HashSet<String> = data.Ciphers.iter().filter_map(|c| c.Id.clone()).collect();
+    if cipher_ids != data_cipher_ids || data.Ciphers.len() != data_cipher_ids.len() {
+        err!("All the personal ciphers need to be re-encrypted with the new key")
+    }
 
-        saved_folder.name = folder_data.Name;
-        saved_folder.save(&conn)?
+    let send_ids: HashSet<String> = Send::find_by_user(user_uuid, &conn).into_iter().map(|s| s.uuid).collect();
+    let data_send_ids: HashSet<String> = sends_data.iter().filter_map(|s| s.Id.clone()).collect();
+    if send_ids != data_send_ids || sends_data.len() != data_send_ids.len() {
+        err!("All the sends need to be re-encrypted with the new key")
     }
 
-    // Update cipher data
-    use super::ciphers::update_cipher_from_data;
+    let user = conn.transaction(|| {
+        // Update folder data
+        for folder_data in data.Folders {
+            let mut saved_folder = match Folder::find_by_uuid(&folder_data.Id, &conn) {
+                Some(folder) => folder,
+                None => err!("Folder doesn't exist"),
+            };
 
-    for cipher_data in data.Ciphers {
-        let mut saved_cipher = match Cipher::find_by_uuid(cipher_data.Id.as_ref().unwrap(), &conn) {
-            Some(cipher) => cipher,
-            None => err!("Cipher doesn't exist"),
-        };
+            saved_folder.name = folder_data.Name;
+            saved_folder.save(&conn)?
+        }
+
+        // Update cipher data
+        for cipher_data in data.Ciphers {
+            let mut saved_cipher = match Cipher::find_by_uuid(cipher_data.Id.as_ref().unwrap(), &conn) {
+                Some(cipher) => cipher,
+                None => err!("Cipher doesn't exist"),
+            };
 
-        if saved_cipher.user_uuid.as_ref().unwrap() != user_uuid {
-            err!("The cipher is not owned by the user")
+            // The previous revisions are encrypted with the old key and can't be restored anymore
+            CipherHistory::delete_all_by_cipher(&saved_cipher.uuid, &conn)?;
+            update_cipher_from_data(&mut saved_cipher, cipher_data, &headers, false, &conn, &nt, UpdateType::None)?
         }
 
-        update_cipher_from_data(&mut saved_cipher, cipher_data, &headers, false, &conn, &nt, UpdateType::CipherUpdate)?
-    }
+        // Update send data
+        for send_data in sends_data {
+            let mut saved_send = match Send::find_by_uuid(send_data.Id.as_ref().unwrap(), &conn) {
+                Some(send) => send,
+                None => err!("Send doesn't exist"),
+            };
 
-    // Update user data
-    let mut user = headers.user;
+            update_send_from_data(&mut saved_send, send_data)?;
+            saved_send.save(&conn)?
+        }
 
-    user.akey = data.Key;
-    user.private_key = Some(data.PrivateKey);
-    user.reset_security_stamp();
-    user.reset_stamp_exception();
+        // Update user data
+        let mut user = match User::find_by_uuid(user_uuid, &conn) {
+            Some(user) => user,
+            None => err!("User doesn't exist"),
+        };
 
-    user.save(&conn)
+        user.akey = data.Key;
+        user.private_key = Some(data.PrivateKey);
+        user.reset_security_stamp();
+        user.reset_stamp_exception();
+
+        user.save(&conn)?;
+        Ok(user)
+    })?;
+
+    // The other sessions are no longer valid, make their clients log out
+    nt.send_user_update(UpdateType::LogOut, &user);
+    Ok(())
 }
 
 #[post("/accounts/security-stamp", data = "<data>")]
@@ -372,19 +456,23 @@ fn post_email_token(data: JsonUpcase<EmailTokenData>, headers: Headers, conn: Db
         err!("Email domain not allowed");
     }
 
-    let token = crypto::generate_token(6)?;
+    let token = crypto::generate_token(EMAIL_CHANGE_TOKEN_SIZE)?;
 
+    // The token proves that the user controls the new address, so the change can't go on without sending it
     if CONFIG.mail_enabled() {
         if let Err(e) = mail::send_change_email(&data.NewEmail, &token) {
             error!("Error sending change-email email: {:#?}", e);
+            err!("Could not send the verification code to the new email address")
         }
     }
 
-    user.email_new = Some(data.NewEmail);
-    user.email_new_token = Some(token);
+    user.set_email_change(data.NewEmail, token);
     user.save(&conn)
 }
 
+/// Number of digits of the token sent to the new address when changing the email
+const EMAIL_CHANGE_TOKEN_SIZE: u32 = 6;
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct ChangeEmailData {
@@ -409,6 +497,10 @@ fn post_email(data: JsonUpcase<ChangeEmailData>, headers: Headers, conn: DbConn)
         err!("Email already in use");
     }
 
+    if !CONFIG.is_email_domain_allowed(&data.NewEmail) {
+        err!("Email domain not allowed");
+    }
+
     match user.email_new {
         Some(ref val) => {
             if val != &data.NewEmail {
@@ -420,13 +512,28 @@ fn post_email(data: JsonUpcase<ChangeEmailData>, headers: Headers, conn: DbConn)
 
     if CONFIG.mail_enabled() {
         // Only check the token if we sent out an email...
-        match user.email_new_token {
-            Some(ref val) => {
-                if *val != data.Token.into_string() {
-                    err!("Token mismatch");
-                }
+        let (issued_token, sent_at) = match (&user.email_new_token, user.email_new_token_sent_at) {
+            (Some(token), Some(sent_at)) => (token.clone(), sent_at),
+            _ => err!("No email change pending"),
+        };
+
+        // A token sent as a number loses its leading zeros
+        let token = format!("{:0>width$}", data.Token.into_string(), width = EMAIL_CHANGE_TOKEN_SIZE as usize);
+        if !crypto::ct_eq(&issued_token, &token) {
+            user.email_new_token_attempts += 1;
+            if user.email_new_token_attempts as u64 >= CONFIG.email_attempts_limit() {
+                user.clear_email_change();
+                user.save(&conn)?;
+                err!("Too many invalid codes, please request a new one")
             }
-            None => err!("No email change pending"),
+            user.save(&conn)?;
+            err!("Token mismatch");
+        }
+
+        if sent_at + Duration::seconds(CONFIG.email_expiration_time() as i64) < Utc::now().naive_utc() {
+            user.clear_email_change();
+            user.save(&conn)?;
+            err!("The code has expired, please request a new one")
         }
         user.verified_at = Some(Utc::now().naive_utc());
     } else {
@@ -434,8 +541,7 @@ fn post_email(data: JsonUpcase<ChangeEmailData>, headers: Headers, conn: DbConn)
     }
 
     user.email = data.NewEmail;
-    user.email_new = None;
-    user.email_new_token = None;
+    user.clear_email_change();
 
     user.set_password(&data.NewMasterPasswordHash, None);
     user.akey = data.Key;
@@ -481,6 +587,9 @@ fn post_verify_email_token(data: JsonUpcase<VerifyEmailTokenData>, conn: DbConn)
     if claims.sub != user.uuid {
         err!("Invalid claim");
     }
+    // The registration is only complete once the address is verified, so that's when the welcome email is sent
+    let send_welcome = user.verified_at.is_none() && CONFIG.welcome_email() && CONFIG.mail_enabled();
+
     user.verified_at = Some(Utc::now().naive_utc());
     user.last_verifying_at = None;
     user.login_verify_count = 0;
@@ -488,6 +597,12 @@ fn post_verify_email_token(data: JsonUpcase<VerifyEmailTokenData>, conn: DbConn)
         error!("Error saving email verification: {:#?}", e);
     }
 
+    if send_welcome {
+        if let Err(e) = mail::send_welcome(&user.email) {
+            error!("Error sending welcome email: {:#?}", e);
+        }
+    }
+
     Ok(())
 }
 
@@ -562,6 +677,81 @@ fn delete_account(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn
     user.delete(&conn)
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ExportData {
+    MasterPasswordHash: String,
+    Format: Option<String>,
+}
+
+/// Exports the personal vault of the user with the encrypted JSON format of the clients.
+/// The unencrypted formats can only be created by the clients, as the server can't decrypt the vault.
+#[post("/accounts/export", data = "<data>")]
+fn post_export(data: JsonUpcase<ExportData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: ExportData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    match data.Format.as_deref() {
+        None | Some("encrypted_json") => (),
+        Some("json") | Some("csv") => err!("Unencrypted exports can only be created by the clients"),
+        Some(_) => err!("Invalid export format"),
+    }
+
+    // Fields of the ciphers which are part of the exports, the rest only matter to the clients
+    const EXPORT_FIELDS: &[&str] = &[
+        "Id",
+        "OrganizationId",
+        "FolderId",
+        "Type",
+        "Reprompt",
+        "Name",
+        "Notes",
+        "Favorite",
+        "Fields",
+        "Login",
+        "SecureNote",
+        "Card",
+        "Identity",
+        "PasswordHistory",
+        "RevisionDate",
+        "CreationDate",
+        "DeletedDate",
+    ];
+
+    let folders: Vec<Value> = Folder::find_by_user(&user.uuid, &conn)
+        .iter()
+        .map(|f| {
+            json!({
+                "id": f.uuid,
+                "name": f.name,
+            })
+        })
+        .collect();
+
+    let items: Vec<Value> = Cipher::find_owned_by_user(&user.uuid, &conn)
+        .iter()
+        .filter(|c| c.deleted_at.is_none())
+        .map(|c| {
+            let cipher_json = c.to_json(&headers.host, &user.uuid, &conn);
+            let mut item = json!({ "collectionIds": null });
+            for field in EXPORT_FIELDS {
+                item[crate::util::lcase_first(field)] = crate::util::lcase_value(cipher_json[field].clone());
+            }
+            item
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "encrypted": true,
+        "folders": folders,
+        "items": items,
+    })))
+}
+
 #[get("/accounts/revision-date")]
 fn revision_date(headers: Headers) -> String {
     let revision_date = headers.user.updated_at.timestamp_millis();
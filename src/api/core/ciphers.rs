@@ -2,6 +2,8 @@ use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
 use chrono::{NaiveDateTime, Utc};
+use data_encoding::BASE32_NOPAD;
+use oath::{totp_raw_custom_time, HashType};
 use rocket::{http::ContentType, request::Form, Data, Route};
 use rocket_contrib::json::Json;
 use serde_json::Value;
@@ -9,10 +11,12 @@ use serde_json::Value;
 use multipart::server::{save::SavedData, Multipart, SaveResult};
 
 use crate::{
-    api::{self, EmptyResult, JsonResult, JsonUpcase, Notify, PasswordData, UpdateType},
-    auth::Headers,
+    api::{self, ApiResult, EmptyResult, JsonResult, JsonUpcase, Notify, PasswordData, UpdateType},
+    auth::{Headers, IfUnmodifiedSince},
     crypto,
     db::{models::*, DbConn, DbPool},
+    storage::{get_storage, FileArea},
+    util::format_date,
     CONFIG,
 };
 
@@ -30,9 +34,13 @@ pub fn routes() -> Vec<Route> {
     routes![
         sync,
         get_ciphers,
+        search_ciphers,
+        post_totp_verify,
         get_cipher,
         get_cipher_admin,
         get_cipher_details,
+        post_cipher_access,
+        get_unused_ciphers,
         post_ciphers,
         put_cipher_admin,
         post_ciphers_admin,
@@ -41,6 +49,11 @@ pub fn routes() -> Vec<Route> {
         get_attachment,
         post_attachment_v2,
         post_attachment_v2_data,
+        get_attachment_v2_renew,
+        get_attachment_chunked,
+        post_attachment_chunked,
+        put_attachment_chunk,
+        post_attachment_chunked_finalize,
         post_attachment,       // legacy
         post_attachment_admin, // legacy
         post_attachment_share,
@@ -54,6 +67,8 @@ pub fn routes() -> Vec<Route> {
         put_cipher_share_selected,
         post_cipher,
         put_cipher,
+        get_cipher_history,
+        post_cipher_history_restore,
         delete_cipher_post,
         delete_cipher_post_admin,
         delete_cipher_put,
@@ -69,6 +84,10 @@ pub fn routes() -> Vec<Route> {
         restore_cipher_put,
         restore_cipher_put_admin,
         restore_cipher_selected,
+        archive_cipher_put,
+        archive_cipher_selected,
+        unarchive_cipher_put,
+        unarchive_cipher_selected,
         delete_all,
         move_cipher_selected,
         move_cipher_selected_put,
@@ -76,30 +95,98 @@ pub fn routes() -> Vec<Route> {
         post_collections_update,
         post_collections_admin,
         put_collections_admin,
+        post_ciphers_bulk_collections,
     ]
 }
 
 pub fn purge_trashed_ciphers(pool: DbPool) {
     debug!("Purging trashed ciphers");
     if let Ok(conn) = pool.get() {
-        Cipher::purge_trash(&conn);
+        let purged = Cipher::purge_trash(&conn);
+        if purged > 0 {
+            info!("Purged {} trashed ciphers past their retention", purged);
+        }
     } else {
         error!("Failed to get DB connection while purging trashed ciphers")
     }
 }
 
+/// Files modified more recently than this are kept, as their attachment may still be in the middle of its upload
+const ORPHANED_ATTACHMENT_MIN_AGE: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Removes the attachment files which aren't used by any attachment anymore,
+/// for example because their deletion failed after the attachment was removed from the database.
+pub fn purge_orphaned_attachments(pool: DbPool) {
+    debug!("Purging orphaned attachment files");
+    let conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => {
+            error!("Failed to get DB connection while purging orphaned attachment files");
+            return;
+        }
+    };
+
+    let purged = purge_orphaned_attachment_files(&conn);
+    if purged > 0 {
+        info!("Purged {} orphaned attachment files", purged);
+    }
+}
+
+/// Deletes the attachment files which aren't used anymore and returns how many were deleted
+pub fn purge_orphaned_attachment_files(conn: &DbConn) -> usize {
+    let storage = get_storage(FileArea::Attachments);
+    let files = match storage.list() {
+        Ok(files) => files,
+        Err(e) => {
+            warn!("Unable to list the attachment files: {:#?}", e);
+            return 0;
+        }
+    };
+
+    let mut purged = 0;
+    for (path, modified) in files {
+        let is_recent = modified.elapsed().map_or(true, |age| age < ORPHANED_ATTACHMENT_MIN_AGE);
+        let _lock = Attachment::lock_files();
+        if is_recent || Attachment::is_file_used(&path, conn) {
+            continue;
+        }
+
+        match storage.delete(&path) {
+            Ok(()) => purged += 1,
+            Err(e) => warn!("Failed to delete orphaned attachment file {}: {:#?}", path, e),
+        }
+    }
+    purged
+}
+
 #[derive(FromForm, Default)]
 struct SyncData {
     #[form(field = "excludeDomains")]
     exclude_domains: bool, // Default: 'false'
+    // Revision date of the last sync of the client, in milliseconds like `/accounts/revision-date`
+    since: Option<i64>,
 }
 
+/// Returns the whole vault of the user. When `since` is provided only the ciphers and folders modified
+/// after that date are included, along with the ids of all the current ones so the clients can remove
+/// the deleted ones. Changes that don't modify the cipher itself, like moving it to another folder or
+/// gaining access to it through a collection, reset the sync of the user, and the whole vault is returned
+/// when that happened after `since`.
 #[get("/sync?<data..>")]
 fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> Json<Value> {
     let user_json = headers.user.to_json(&conn);
 
+    let since = data
+        .since
+        .and_then(|ms| NaiveDateTime::from_timestamp_opt(ms.div_euclid(1000), ms.rem_euclid(1000) as u32 * 1_000_000))
+        .filter(|since| headers.user.sync_reset_at <= *since);
+    // Nothing can have changed if the user revision is older than the last sync
+    let modified = since.map_or(true, |since| headers.user.updated_at > since);
+    let is_changed = |updated_at: &NaiveDateTime| since.map_or(true, |since| *updated_at > since);
+
     let folders = Folder::find_by_user(&headers.user.uuid, &conn);
-    let folders_json: Vec<Value> = folders.iter().map(Folder::to_json).collect();
+    let folders_json: Vec<Value> =
+        folders.iter().filter(|f| modified && is_changed(&f.updated_at)).map(Folder::to_json).collect();
 
     let collections = Collection::find_by_user_uuid(&headers.user.uuid, &conn);
     let collections_json: Vec<Value> =
@@ -109,8 +196,8 @@ fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> Json<Value> {
     let policies_json: Vec<Value> = policies.iter().map(OrgPolicy::to_json).collect();
 
     let ciphers = Cipher::find_by_user_visible(&headers.user.uuid, &conn);
-    let ciphers_json: Vec<Value> =
-        ciphers.iter().map(|c| c.to_json(&headers.host, &headers.user.uuid, &conn)).collect();
+    let changed_ciphers: Vec<&Cipher> = ciphers.iter().filter(|c| modified && is_changed(&c.updated_at)).collect();
+    let ciphers_json = ciphers_to_json(&changed_ciphers, &headers, &conn);
 
     let sends = Send::find_by_user(&headers.user.uuid, &conn);
     let sends_json: Vec<Value> = sends.iter().map(|s| s.to_json()).collect();
@@ -118,10 +205,10 @@ fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> Json<Value> {
     let domains_json = if data.exclude_domains {
         Value::Null
     } else {
-        api::core::_get_eq_domains(headers, true).into_inner()
+        api::core::_get_eq_domains(&headers.user, true).into_inner()
     };
 
-    Json(json!({
+    let mut sync_json = json!({
         "Profile": user_json,
         "Folders": folders_json,
         "Collections": collections_json,
@@ -131,15 +218,40 @@ fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> Json<Value> {
         "Sends": sends_json,
         "unofficialServer": true,
         "Object": "sync"
-    }))
+    });
+
+    if since.is_some() {
+        sync_json["Delta"] = json!(true);
+        sync_json["FolderIds"] = json!(folders.iter().map(|f| &f.uuid).collect::<Vec<_>>());
+        sync_json["CipherIds"] = json!(ciphers.iter().map(|c| &c.uuid).collect::<Vec<_>>());
+    }
+
+    Json(sync_json)
+}
+
+/// Serializes many ciphers of a user. The data they need is loaded upfront with a few queries instead
+/// of several queries for every cipher, and the attachments are loaded one chunk of ciphers at a time.
+fn ciphers_to_json(ciphers: &[&Cipher], headers: &Headers, conn: &DbConn) -> Vec<Value> {
+    const CHUNK_SIZE: usize = 500;
+
+    let user_uuid = &headers.user.uuid;
+    let mut sync_data = CipherSyncData::new(user_uuid, conn);
+    let mut ciphers_json = Vec::with_capacity(ciphers.len());
+    for chunk in ciphers.chunks(CHUNK_SIZE) {
+        sync_data.load_attachments(chunk, conn);
+        ciphers_json
+            .extend(chunk.iter().map(|c| c.to_json_with_sync_data(&headers.host, user_uuid, Some(&sync_data), conn)));
+    }
+    ciphers_json
 }
 
 #[get("/ciphers")]
 fn get_ciphers(headers: Headers, conn: DbConn) -> Json<Value> {
     let ciphers = Cipher::find_by_user_visible(&headers.user.uuid, &conn);
 
-    let ciphers_json: Vec<Value> =
-        ciphers.iter().map(|c| c.to_json(&headers.host, &headers.user.uuid, &conn)).collect();
+    // Trashed ciphers are only sent in the full sync, which the clients use to build the trash view
+    let ciphers: Vec<&Cipher> = ciphers.iter().filter(|c| c.deleted_at.is_none()).collect();
+    let ciphers_json = ciphers_to_json(&ciphers, &headers, &conn);
 
     Json(json!({
       "Data": ciphers_json,
@@ -148,6 +260,191 @@ fn get_ciphers(headers: Headers, conn: DbConn) -> Json<Value> {
     }))
 }
 
+#[derive(FromForm)]
+struct CipherSearchData {
+    #[form(field = "folderId")]
+    folder_id: Option<String>,
+    #[form(field = "organizationId")]
+    organization_id: Option<String>,
+    #[form(field = "collectionId")]
+    collection_id: Option<String>,
+    #[form(field = "type")]
+    atype: Option<i32>,
+    favorite: Option<bool>,
+    #[form(field = "hasAttachments")]
+    has_attachments: Option<bool>,
+    deleted: Option<bool>, // Default: 'false'
+    archived: Option<bool>,
+    // Revision dates in milliseconds, like `/accounts/revision-date`
+    #[form(field = "revisedAfter")]
+    revised_after: Option<i64>,
+    #[form(field = "revisedBefore")]
+    revised_before: Option<i64>,
+    #[form(field = "continuationToken")]
+    continuation_token: Option<usize>,
+    #[form(field = "pageSize")]
+    page_size: Option<usize>,
+}
+
+/// Searches the ciphers of the user by the metadata the server knows about, for automation and CLI use.
+/// The names, URIs and the rest of the content are encrypted, so they can only be searched by the clients.
+/// The results are sorted by revision date, newest first, and paged with the continuation token.
+#[get("/ciphers/search?<data..>")]
+fn search_ciphers(data: Form<CipherSearchData>, headers: Headers, conn: DbConn) -> Json<Value> {
+    const CHUNK_SIZE: usize = 500;
+
+    let user_uuid = &headers.user.uuid;
+    let mut ciphers = Cipher::find_by_user_visible(user_uuid, &conn);
+    ciphers.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| a.uuid.cmp(&b.uuid)));
+
+    let mut sync_data = CipherSyncData::new(user_uuid, &conn);
+    let deleted = data.deleted.unwrap_or(false);
+
+    let mut found: Vec<&Cipher> = Vec::new();
+    for chunk in ciphers.chunks(CHUNK_SIZE) {
+        let chunk: Vec<&Cipher> = chunk.iter().collect();
+        if data.has_attachments.is_some() {
+            sync_data.load_attachments(&chunk, &conn);
+        }
+
+        found.extend(chunk.into_iter().filter(|c| {
+            let revision_date = c.updated_at.timestamp_millis();
+            c.deleted_at.is_some() == deleted
+                && data.archived.map_or(true, |a| c.archived_at.is_some() == a)
+                && data.folder_id.as_ref().map_or(true, |f| sync_data.get_folder(&c.uuid) == Some(f))
+                && data.organization_id.as_ref().map_or(true, |o| c.organization_uuid.as_ref() == Some(o))
+                && data.collection_id.as_ref().map_or(true, |col| sync_data.get_collections(&c.uuid).contains(col))
+                && data.atype.map_or(true, |t| c.atype == t)
+                && data.favorite.map_or(true, |f| sync_data.is_favorite(&c.uuid) == f)
+                && data.has_attachments.map_or(true, |a| sync_data.has_attachments(&c.uuid) == a)
+                && data.revised_after.map_or(true, |d| revision_date > d)
+                && data.revised_before.map_or(true, |d| revision_date < d)
+        }));
+    }
+
+    let page_size = data.page_size.unwrap_or(100).clamp(1, CHUNK_SIZE);
+    let start = data.continuation_token.unwrap_or(0).min(found.len());
+    let end = (start + page_size).min(found.len());
+    let page = &found[start..end];
+
+    sync_data.load_attachments(page, &conn);
+    let ciphers_json: Vec<Value> =
+        page.iter().map(|c| c.to_json_with_sync_data(&headers.host, user_uuid, Some(&sync_data), &conn)).collect();
+
+    let continuation_token = if end < found.len() {
+        Value::String(end.to_string())
+    } else {
+        Value::Null
+    };
+
+    Json(json!({
+      "Data": ciphers_json,
+      "Object": "list",
+      "ContinuationToken": continuation_token
+    }))
+}
+
+/// Settings of a TOTP seed, as entered in the login items
+struct TotpSeed {
+    secret: Vec<u8>,
+    digits: u32,
+    period: u64,
+    algorithm: HashType,
+}
+
+/// Parses a TOTP seed, either a plain base32 secret or an `otpauth://totp/` URI
+fn parse_totp_seed(key: &str) -> ApiResult<TotpSeed> {
+    let key = key.trim();
+    let mut secret = key.to_string();
+    let mut digits = 6;
+    let mut period = 30;
+    let mut algorithm = HashType::SHA1;
+
+    let lower_key = key.to_lowercase();
+    if lower_key.starts_with("steam://") {
+        err!("Steam Guard keys are not supported")
+    } else if lower_key.starts_with("otpauth://") {
+        let uri = match url::Url::parse(key) {
+            Ok(uri) => uri,
+            Err(_) => err!("Invalid TOTP URI"),
+        };
+        if uri.host_str() != Some("totp") {
+            err!("Only TOTP keys are supported")
+        }
+
+        secret.clear();
+        for (name, value) in uri.query_pairs() {
+            match name.to_lowercase().as_str() {
+                "secret" => secret = value.into_owned(),
+                "digits" => match value.parse() {
+                    Ok(d @ 1..=10) => digits = d,
+                    _ => err!("Invalid TOTP digits"),
+                },
+                "period" => match value.parse() {
+                    Ok(p) if p > 0 => period = p,
+                    _ => err!("Invalid TOTP period"),
+                },
+                "algorithm" => match value.to_uppercase().as_str() {
+                    "SHA1" => algorithm = HashType::SHA1,
+                    "SHA256" => algorithm = HashType::SHA256,
+                    "SHA512" => algorithm = HashType::SHA512,
+                    _ => err!("Unsupported TOTP algorithm"),
+                },
+                _ => (),
+            }
+        }
+    }
+
+    // Authenticator apps accept secrets with spaces, lowercase letters and padding
+    let secret: String = secret.chars().filter(|c| !c.is_whitespace() && *c != '=').collect();
+    match BASE32_NOPAD.decode(secret.to_uppercase().as_bytes()) {
+        Ok(secret) if !secret.is_empty() => Ok(TotpSeed {
+            secret,
+            digits,
+            period,
+            algorithm,
+        }),
+        _ => err!("Invalid TOTP secret"),
+    }
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct TotpVerifyData {
+    Key: String,
+    Code: Option<String>,
+}
+
+/// Generates the current code of a just scanned TOTP seed with the server clock, so the clients can give
+/// immediate feedback during the authenticator setup of an item, even when their own clock is off.
+/// When a code is given, it's checked against the current and the surrounding time steps,
+/// and `Drift` tells which one matched.
+#[post("/ciphers/totp/verify", data = "<data>")]
+fn post_totp_verify(data: JsonUpcase<TotpVerifyData>, _headers: Headers) -> JsonResult {
+    let data: TotpVerifyData = data.into_inner().data;
+    let seed = parse_totp_seed(&data.Key)?;
+
+    let now = Utc::now().timestamp();
+    let generate = |time: i64| {
+        let code = totp_raw_custom_time(&seed.secret, seed.digits, 0, seed.period, time as u64, &seed.algorithm);
+        format!("{:0width$}", code, width = seed.digits as usize)
+    };
+
+    let drift = data.Code.as_deref().and_then(|code| {
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+        (-1..=1).find(|step| generate(now + step * seed.period as i64) == code)
+    });
+
+    Ok(Json(json!({
+        "Code": generate(now),
+        "Period": seed.period,
+        "RemainingSeconds": seed.period - (now as u64 % seed.period),
+        "Valid": data.Code.as_ref().map(|_| drift.is_some()),
+        "Drift": drift,
+        "Object": "totpVerification"
+    })))
+}
+
 #[get("/ciphers/<uuid>")]
 fn get_cipher(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
     let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
@@ -159,9 +456,79 @@ fn get_cipher(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
         err!("Cipher is not owned by user")
     }
 
+    CipherAccess::record(&cipher.uuid, &headers.user.uuid, &conn);
+
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
 }
 
+/// Lets the clients report that the user used a cipher, for example to fill or copy a password,
+/// as those happen on the synced copy and the server can't see them otherwise.
+#[post("/ciphers/<uuid>/access")]
+fn post_cipher_access(uuid: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not owned by user")
+    }
+
+    CipherAccess::record(&cipher.uuid, &headers.user.uuid, &conn);
+    Ok(())
+}
+
+#[derive(FromForm)]
+struct UnusedCiphersData {
+    days: Option<i64>,
+}
+
+/// Lists the ciphers the user hasn't used nor modified for the given number of days, a year by default,
+/// so credentials which are not needed anymore can be pruned.
+#[get("/ciphers/unused?<data..>")]
+fn get_unused_ciphers(data: Form<UnusedCiphersData>, headers: Headers, conn: DbConn) -> JsonResult {
+    if !CONFIG.cipher_access_tracking() {
+        err!("Item usage tracking is disabled on this server")
+    }
+
+    let days = data.days.unwrap_or(365);
+    if days < 1 {
+        err!("The number of days must be at least 1")
+    }
+    let limit = Utc::now().naive_utc() - chrono::Duration::days(days);
+
+    let accesses: HashMap<String, CipherAccess> = CipherAccess::find_by_user(&headers.user.uuid, &conn)
+        .into_iter()
+        .map(|access| (access.cipher_uuid.clone(), access))
+        .collect();
+
+    let ciphers_json: Vec<Value> = Cipher::find_by_user_visible(&headers.user.uuid, &conn)
+        .iter()
+        .filter(|cipher| cipher.deleted_at.is_none())
+        .filter_map(|cipher| {
+            let access = accesses.get(&cipher.uuid);
+            let last_used = access.map(|access| access.last_access_at);
+            if last_used.map_or(cipher.updated_at, |last_used| last_used.max(cipher.updated_at)) >= limit {
+                return None;
+            }
+
+            Some(json!({
+                "CipherId": cipher.uuid,
+                "RevisionDate": format_date(&cipher.updated_at),
+                "LastUsedDate": last_used.map(|date| format_date(&date)),
+                "AccessCount": access.map_or(0, |access| access.access_count),
+                "Object": "unusedCipher"
+            }))
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": ciphers_json,
+        "Object": "list",
+        "ContinuationToken": null
+    })))
+}
+
 #[get("/ciphers/<uuid>/admin")]
 fn get_cipher_admin(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
     // TODO: Implement this correctly
@@ -179,7 +546,7 @@ pub struct CipherData {
     // Id is optional as it is included only in bulk share
     pub Id: Option<String>,
     // Folder id is not included in import
-    FolderId: Option<String>,
+    pub FolderId: Option<String>,
     // TODO: Some of these might appear all the time, no need for Option
     OrganizationId: Option<String>,
 
@@ -192,6 +559,7 @@ pub struct CipherData {
     pub Type: i32, // TODO: Change this to NumberOrString
     pub Name: String,
     Notes: Option<String>,
+    // Custom fields, stored as sent. Linked fields (Type 3) reference a property of the item with `LinkedId`
     Fields: Option<Value>,
 
     // Only one of these should exist, depending on type
@@ -300,6 +668,16 @@ fn enforce_personal_ownership_policy(data: &CipherData, headers: &Headers, conn:
     Ok(())
 }
 
+/// Rejects the update of a cipher that was modified after the given revision date,
+/// so two clients can't silently overwrite each other's changes.
+fn check_cipher_revision(cipher: &Cipher, last_known_revision: &NaiveDateTime) -> EmptyResult {
+    // The dates sent by the clients don't always have sub-second precision
+    if cipher.updated_at.signed_duration_since(*last_known_revision).num_seconds() > 1 {
+        err_code!("The client copy of this cipher is out of date. Resync the client and try again.", 409)
+    }
+    Ok(())
+}
+
 pub fn update_cipher_from_data(
     cipher: &mut Cipher,
     data: CipherData,
@@ -316,10 +694,7 @@ pub fn update_cipher_from_data(
         match NaiveDateTime::parse_from_str(&dt, "%+") {
             // ISO 8601 format
             Err(err) => warn!("Error parsing LastKnownRevisionDate '{}': {}", dt, err),
-            Ok(dt) if cipher.updated_at.signed_duration_since(dt).num_seconds() > 1 => {
-                err!("The client copy of this cipher is out of date. Resync the client and try again.")
-            }
-            Ok(_) => (),
+            Ok(dt) => check_cipher_revision(cipher, &dt)?,
         }
     }
 
@@ -410,20 +785,35 @@ pub fn update_cipher_from_data(
         Some(mut data) => {
             // Remove the 'Response' key from the base object.
             data.as_object_mut().unwrap().remove("Response");
-            // Remove the 'Response' key from every Uri.
-            if data["Uris"].is_array() {
-                data["Uris"] = _clean_cipher_data(data["Uris"].clone());
+            // Remove the 'Response' key from every Uri and stored passkey (Fido2Credentials).
+            // Everything else is stored as sent, so newer client fields are kept.
+            for key in &["Uris", "Fido2Credentials"] {
+                if data[*key].is_array() {
+                    data[*key] = _clean_cipher_data(data[*key].clone());
+                }
             }
             data
         }
         None => err!("Data missing"),
     };
 
+    let stored_history: Option<Value> = cipher.password_history.as_ref().and_then(|h| serde_json::from_str(h).ok());
+    let password_history = match data.PasswordHistory {
+        Some(history) => Some(_clean_cipher_data(history)),
+        // Clients without password history support don't send it, keep the stored one
+        None => stored_history.clone(),
+    };
+    let password_history = if cipher.atype == 1 && data.Type == 1 {
+        track_password_change(&cipher.data, &type_data, password_history)
+    } else {
+        password_history
+    };
+
     cipher.name = data.Name;
     cipher.notes = data.Notes;
     cipher.fields = data.Fields.map(|f| _clean_cipher_data(f).to_string());
     cipher.data = type_data.to_string();
-    cipher.password_history = data.PasswordHistory.map(|f| f.to_string());
+    cipher.password_history = password_history.map(|f| f.to_string());
     cipher.reprompt = data.Reprompt;
 
     cipher.save(conn)?;
@@ -437,6 +827,44 @@ pub fn update_cipher_from_data(
     Ok(())
 }
 
+/// Adds the previous password of a login to its password history when the password revision date
+/// changed but the client didn't record the change itself, like older or third-party clients.
+/// As the clients do, the newest entry goes first and only the last five passwords are kept.
+fn track_password_change(old_data: &str, new_data: &Value, history: Option<Value>) -> Option<Value> {
+    const MAX_PASSWORD_HISTORY: usize = 5;
+
+    let old_data: Value = match serde_json::from_str(old_data) {
+        Ok(data) => data,
+        Err(_) => return history,
+    };
+
+    let old_password = &old_data["Password"];
+    let new_revision = &new_data["PasswordRevisionDate"];
+    if old_password.is_null() || new_revision.is_null() || *new_revision == old_data["PasswordRevisionDate"] {
+        return history;
+    }
+
+    // The client already added the previous password. The lengths can't be compared instead,
+    // as a full history keeps the same length when the client adds an entry.
+    if history.as_ref().and_then(|h| h.get(0)).map_or(false, |newest| newest["Password"] == *old_password) {
+        return history;
+    }
+
+    let mut entries = match history {
+        Some(Value::Array(entries)) => entries,
+        _ => Vec::new(),
+    };
+    entries.insert(
+        0,
+        json!({
+            "LastUsedDate": new_revision,
+            "Password": old_password,
+        }),
+    );
+    entries.truncate(MAX_PASSWORD_HISTORY);
+    Some(Value::Array(entries))
+}
+
 use super::folders::FolderData;
 
 #[derive(Deserialize)]
@@ -497,10 +925,11 @@ fn put_cipher_admin(
     uuid: String,
     data: JsonUpcase<CipherData>,
     headers: Headers,
+    unmodified_since: IfUnmodifiedSince,
     conn: DbConn,
     nt: Notify,
 ) -> JsonResult {
-    put_cipher(uuid, data, headers, conn, nt)
+    put_cipher(uuid, data, headers, unmodified_since, conn, nt)
 }
 
 #[post("/ciphers/<uuid>/admin", data = "<data>")]
@@ -508,19 +937,34 @@ fn post_cipher_admin(
     uuid: String,
     data: JsonUpcase<CipherData>,
     headers: Headers,
+    unmodified_since: IfUnmodifiedSince,
     conn: DbConn,
     nt: Notify,
 ) -> JsonResult {
-    post_cipher(uuid, data, headers, conn, nt)
+    post_cipher(uuid, data, headers, unmodified_since, conn, nt)
 }
 
 #[post("/ciphers/<uuid>", data = "<data>")]
-fn post_cipher(uuid: String, data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
-    put_cipher(uuid, data, headers, conn, nt)
+fn post_cipher(
+    uuid: String,
+    data: JsonUpcase<CipherData>,
+    headers: Headers,
+    unmodified_since: IfUnmodifiedSince,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    put_cipher(uuid, data, headers, unmodified_since, conn, nt)
 }
 
 #[put("/ciphers/<uuid>", data = "<data>")]
-fn put_cipher(uuid: String, data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+fn put_cipher(
+    uuid: String,
+    data: JsonUpcase<CipherData>,
+    headers: Headers,
+    unmodified_since: IfUnmodifiedSince,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
     let data: CipherData = data.into_inner().data;
 
     let mut cipher = match Cipher::find_by_uuid(&uuid, &conn) {
@@ -537,7 +981,83 @@ fn put_cipher(uuid: String, data: JsonUpcase<CipherData>, headers: Headers, conn
         err!("Cipher is not write accessible")
     }
 
+    if let Some(ref dt) = unmodified_since.date {
+        check_cipher_revision(&cipher, dt)?;
+    }
+
+    let revision = CipherHistory::new(&cipher);
     update_cipher_from_data(&mut cipher, data, &headers, false, &conn, &nt, UpdateType::CipherUpdate)?;
+    if !revision.matches(&cipher) {
+        revision.save(&conn)?;
+    }
+
+    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
+}
+
+/// Lists the previous revisions of a cipher, newest first, with the same format as the cipher itself.
+#[get("/ciphers/<uuid>/history")]
+fn get_cipher_history(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let mut snapshot = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !snapshot.is_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not owned by user")
+    }
+
+    let revisions_json: Vec<Value> = CipherHistory::find_by_cipher(&uuid, &conn)
+        .iter()
+        .map(|revision| {
+            revision.apply_to(&mut snapshot);
+            snapshot.updated_at = revision.revision_date;
+
+            let mut revision_json = snapshot.to_json(&headers.host, &headers.user.uuid, &conn);
+            revision_json["HistoryId"] = json!(revision.uuid);
+            revision_json["Object"] = json!("cipherHistory");
+            revision_json
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": revisions_json,
+        "Object": "list",
+        "ContinuationToken": null
+    })))
+}
+
+/// Restores a previous revision of a cipher. The current content is kept as a new revision,
+/// so the restore itself can be undone.
+#[post("/ciphers/<uuid>/history/<history_id>/restore")]
+fn post_cipher_history_restore(
+    uuid: String,
+    history_id: String,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let mut cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not write accessible")
+    }
+
+    let revision = match CipherHistory::find_by_uuid_and_cipher(&history_id, &uuid, &conn) {
+        Some(revision) => revision,
+        None => err!("Cipher revision doesn't exist"),
+    };
+
+    let current = CipherHistory::new(&cipher);
+    revision.apply_to(&mut cipher);
+    cipher.save(&conn)?;
+
+    revision.delete(&conn)?;
+    current.save(&conn)?;
+
+    nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(&conn));
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
 }
@@ -622,6 +1142,57 @@ fn post_collections_admin(
     Ok(())
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct BulkCollectionsData {
+    OrganizationId: String,
+    CipherIds: Vec<String>,
+    CollectionIds: Vec<String>,
+    RemoveCollections: Option<bool>,
+}
+
+/// Adds many ciphers of an organization to the given collections (or removes them from it) at once.
+/// The rights of the user are only checked once, and all the changes are applied in a single transaction.
+#[post("/ciphers/bulk-collections", data = "<data>")]
+fn post_ciphers_bulk_collections(data: JsonUpcase<BulkCollectionsData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    let data: BulkCollectionsData = data.into_inner().data;
+
+    match UserOrganization::find_by_user_and_org(&headers.user.uuid, &data.OrganizationId, &conn) {
+        Some(user_org) if user_org.has_status(UserOrgStatus::Confirmed) && user_org.atype >= UserOrgType::Manager => (),
+        _ => err!("You don't have permission to modify the collections of this organization"),
+    }
+
+    let mut collections = Vec::with_capacity(data.CollectionIds.len());
+    for collection_uuid in &data.CollectionIds {
+        match Collection::find_by_uuid_and_org(collection_uuid, &data.OrganizationId, &conn) {
+            Some(collection) if collection.is_writable_by_user(&headers.user.uuid, &conn) => {
+                collections.push(collection)
+            }
+            Some(_) => err!("No rights to modify the collection"),
+            None => err!("Invalid collection ID provided"),
+        }
+    }
+
+    let remove = data.RemoveCollections.unwrap_or(false);
+    conn.transaction(|| {
+        for cipher_uuid in &data.CipherIds {
+            match Cipher::find_by_uuid(cipher_uuid, &conn) {
+                Some(cipher) if cipher.organization_uuid.as_deref() == Some(data.OrganizationId.as_str()) => (),
+                _ => err!(format!("Cipher {} doesn't belong to the organization", cipher_uuid)),
+            }
+
+            for collection in &collections {
+                if remove {
+                    CollectionCipher::delete(cipher_uuid, &collection.uuid, &conn)?;
+                } else {
+                    CollectionCipher::save(cipher_uuid, &collection.uuid, &conn)?;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct ShareCipherData {
@@ -669,7 +1240,7 @@ fn put_cipher_share_selected(
     conn: DbConn,
     nt: Notify,
 ) -> EmptyResult {
-    let mut data: ShareSelectedCipherData = data.into_inner().data;
+    let data: ShareSelectedCipherData = data.into_inner().data;
     let mut cipher_ids: Vec<String> = Vec::new();
 
     if data.Ciphers.is_empty() {
@@ -687,25 +1258,30 @@ fn put_cipher_share_selected(
         };
     }
 
-    let attachments = Attachment::find_by_ciphers(cipher_ids, &conn);
+    let attachments = Attachment::find_by_ciphers(cipher_ids.clone(), &conn);
 
     if !attachments.is_empty() {
         err!("Ciphers should not have any attachments.")
     }
 
-    while let Some(cipher) = data.Ciphers.pop() {
-        let mut shared_cipher_data = ShareCipherData {
-            Cipher: cipher,
-            CollectionIds: data.CollectionIds.clone(),
+    let collection_ids = data.CollectionIds;
+    let mut ciphers: HashMap<String, CipherData> =
+        data.Ciphers.into_iter().filter_map(|mut c| c.Id.take().map(|id| (id, c))).collect();
+
+    _bulk_cipher_operation(&cipher_ids, &conn, &nt, |uuid, changes| {
+        let shared_cipher_data = match ciphers.remove(uuid) {
+            Some(cipher) => ShareCipherData {
+                Cipher: cipher,
+                CollectionIds: collection_ids.clone(),
+            },
+            None => err!("Cipher is included more than once"),
         };
 
-        match shared_cipher_data.Cipher.Id.take() {
-            Some(id) => share_cipher_by_uuid(&id, shared_cipher_data, &headers, &conn, &nt)?,
-            None => err!("Request missing ids field"),
-        };
-    }
-
-    Ok(())
+        let cipher = _share_cipher(uuid, shared_cipher_data, &headers, &conn, &nt, UpdateType::None)?;
+        let user_uuids = cipher.update_users_revision(&conn);
+        changes.notify(UpdateType::CipherUpdate, cipher, user_uuids);
+        Ok(())
+    })
 }
 
 fn share_cipher_by_uuid(
@@ -715,6 +1291,18 @@ fn share_cipher_by_uuid(
     conn: &DbConn,
     nt: &Notify,
 ) -> JsonResult {
+    let cipher = _share_cipher(uuid, data, headers, conn, nt, UpdateType::CipherUpdate)?;
+    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, conn)))
+}
+
+fn _share_cipher(
+    uuid: &str,
+    data: ShareCipherData,
+    headers: &Headers,
+    conn: &DbConn,
+    nt: &Notify,
+    ut: UpdateType,
+) -> ApiResult<Cipher> {
     let mut cipher = match Cipher::find_by_uuid(uuid, conn) {
         Some(cipher) => {
             if cipher.is_write_accessible_to_user(&headers.user.uuid, conn) {
@@ -749,6 +1337,9 @@ fn share_cipher_by_uuid(
         }
     };
 
+    // The previous revisions are encrypted with the user key and can't be restored in the organization
+    CipherHistory::delete_all_by_cipher(&cipher.uuid, conn)?;
+
     update_cipher_from_data(
         &mut cipher,
         data.Cipher,
@@ -756,10 +1347,10 @@ fn share_cipher_by_uuid(
         shared_to_collection,
         conn,
         nt,
-        UpdateType::CipherUpdate,
+        ut,
     )?;
 
-    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, conn)))
+    Ok(cipher)
 }
 
 /// v2 API for downloading an attachment. This just redirects the client to
@@ -777,6 +1368,9 @@ fn get_attachment(uuid: String, attachment_id: String, headers: Headers, conn: D
     }
 }
 
+/// Error returned by the Bitwarden server when an upload exceeds the storage quota
+const STORAGE_LIMIT_MSG: &str = "Not enough storage available.";
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct AttachmentRequestData {
@@ -789,7 +1383,7 @@ struct AttachmentRequestData {
     // AdminRequest: bool,
 }
 
-enum FileUploadType {
+pub enum FileUploadType {
     Direct = 0,
     // Azure = 1, // only used upstream
 }
@@ -814,22 +1408,67 @@ fn post_attachment_v2(
         err!("Cipher is not write accessible")
     }
 
-    let attachment_id = crypto::generate_attachment_id();
     let data: AttachmentRequestData = data.into_inner().data;
+
+    // Reject the upload early when the announced size already exceeds the remaining quota
+    let (limit_kb, used) = match (&cipher.user_uuid, &cipher.organization_uuid) {
+        (Some(user_uuid), _) => (CONFIG.user_attachment_limit(), Attachment::size_by_user(user_uuid, &conn)),
+        (None, Some(org_uuid)) => (CONFIG.org_attachment_limit(), Attachment::size_by_org(org_uuid, &conn)),
+        (None, None) => err!("Cipher is neither owned by a user nor an organization"),
+    };
+    match limit_kb {
+        Some(0) => err!("Attachments are disabled"),
+        Some(limit_kb) if used + data.FileSize as i64 > limit_kb * 1024 => {
+            err!(STORAGE_LIMIT_MSG)
+        }
+        _ => (),
+    }
+
+    let attachment_id = crypto::generate_attachment_id();
     let attachment =
         Attachment::new(attachment_id.clone(), cipher.uuid.clone(), data.FileName, data.FileSize, Some(data.Key));
     attachment.save(&conn).expect("Error saving attachment");
 
+    Ok(Json(attachment_upload_json(&cipher, &attachment_id, &headers, &conn)))
+}
+
+fn attachment_upload_json(cipher: &Cipher, attachment_id: &str, headers: &Headers, conn: &DbConn) -> Value {
     let url = format!("/ciphers/{}/attachment/{}", cipher.uuid, attachment_id);
 
-    Ok(Json(json!({ // AttachmentUploadDataResponseModel
+    json!({ // AttachmentUploadDataResponseModel
         "Object": "attachment-fileUpload",
         "AttachmentId": attachment_id,
         "Url": url,
         "FileUploadType": FileUploadType::Direct as i32,
-        "CipherResponse": cipher.to_json(&headers.host, &headers.user.uuid, &conn),
+        "CipherResponse": cipher.to_json(&headers.host, &headers.user.uuid, conn),
         "CipherMiniResponse": null,
-    })))
+    })
+}
+
+/// v2 API for getting the upload data of an attachment again, used by the clients
+/// to retry an upload whose file hasn't been received yet.
+#[get("/ciphers/<uuid>/attachment/<attachment_id>/renew")]
+fn get_attachment_v2_renew(uuid: String, attachment_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not write accessible")
+    }
+
+    let attachment = match Attachment::find_by_id(&attachment_id, &conn) {
+        Some(attachment) if uuid == attachment.cipher_uuid => attachment,
+        Some(_) => err!("Attachment doesn't belong to cipher"),
+        None => err!("Attachment doesn't exist"),
+    };
+
+    if get_storage(FileArea::Attachments).exists(&attachment.get_storage_path()) {
+        err!("Attachment has already been uploaded")
+    }
+
+    Ok(Json(attachment_upload_json(&cipher, &attachment.id, &headers, &conn)))
 }
 
 /// Saves the data content of an attachment to a file. This is common code
@@ -871,7 +1510,7 @@ fn save_attachment(
             Some(limit_kb) => {
                 let left = (limit_kb * 1024) - Attachment::size_by_user(user_uuid, conn) + size_adjust;
                 if left <= 0 {
-                    err_discard!("Attachment size limit reached! Delete some files to open space", data)
+                    err_discard!(STORAGE_LIMIT_MSG, data)
                 }
                 Some(left as u64)
             }
@@ -883,7 +1522,7 @@ fn save_attachment(
             Some(limit_kb) => {
                 let left = (limit_kb * 1024) - Attachment::size_by_org(org_uuid, conn) + size_adjust;
                 if left <= 0 {
-                    err_discard!("Attachment size limit reached! Delete some files to open space", data)
+                    err_discard!(STORAGE_LIMIT_MSG, data)
                 }
                 Some(left as u64)
             }
@@ -897,7 +1536,7 @@ fn save_attachment(
     let boundary_pair = params.next().expect("No boundary provided");
     let boundary = boundary_pair.1;
 
-    let base_path = Path::new(&CONFIG.attachments_folder()).join(&cipher_uuid);
+    let base_path = FileArea::Attachments.local_path(&cipher_uuid);
     let mut path = PathBuf::new();
 
     let mut attachment_key = None;
@@ -937,7 +1576,8 @@ fn save_attachment(
                                 return;
                             }
                             SaveResult::Partial(_, reason) => {
-                                error = Some(format!("Attachment size limit exceeded with this file: {:?}", reason));
+                                debug!("Attachment size limit exceeded with this file: {:?}", reason);
+                                error = Some(STORAGE_LIMIT_MSG.to_string());
                                 return;
                             }
                             SaveResult::Error(e) => {
@@ -1000,6 +1640,20 @@ fn save_attachment(
         err!(e);
     }
 
+    // The file is always written to the local disk first, and then moved to the configured storage
+    let file_id = path.file_name().and_then(|f| f.to_str()).unwrap_or_default();
+    if let Some(mut attachment) = Attachment::find_by_id(file_id, conn) {
+        let saved = match crypto::sha256_file(&path) {
+            Ok(hash) => attachment.save_file(&path, hash, conn),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = saved {
+            attachment.delete(conn).ok();
+            std::fs::remove_file(&path).ok();
+            return Err(e);
+        }
+    }
+
     nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(conn));
 
     Ok(cipher)
@@ -1030,6 +1684,162 @@ fn post_attachment_v2_data(
     Ok(())
 }
 
+/// Returns the cipher and the v2 attachment targeted by a chunked upload, the file
+/// of the attachment must not have been uploaded yet.
+fn get_chunked_upload_target(
+    uuid: &str,
+    attachment_id: &str,
+    headers: &Headers,
+    conn: &DbConn,
+) -> ApiResult<(Cipher, Attachment)> {
+    let cipher = match Cipher::find_by_uuid(uuid, conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, conn) {
+        err!("Cipher is not write accessible")
+    }
+
+    let attachment = match Attachment::find_by_id(attachment_id, conn) {
+        Some(attachment) if uuid == attachment.cipher_uuid => attachment,
+        Some(_) => err!("Attachment doesn't belong to cipher"),
+        None => err!("Attachment doesn't exist"),
+    };
+
+    if get_storage(FileArea::Attachments).exists(&attachment.get_storage_path()) {
+        err!("Attachment has already been uploaded")
+    }
+
+    Ok((cipher, attachment))
+}
+
+fn chunked_upload_json(attachment: &Attachment, offset: u64) -> Value {
+    json!({
+        "Object": "attachment-chunkedUpload",
+        "AttachmentId": attachment.id,
+        "Offset": offset,
+        "FileSize": attachment.file_size,
+    })
+}
+
+/// Returns how much of a chunked upload has been received, so the client can resume it after a failure.
+#[get("/ciphers/<uuid>/attachment/<attachment_id>/chunked")]
+fn get_attachment_chunked(uuid: String, attachment_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let (_, attachment) = get_chunked_upload_target(&uuid, &attachment_id, &headers, &conn)?;
+
+    match std::fs::metadata(attachment.get_upload_path()) {
+        Ok(metadata) => Ok(Json(chunked_upload_json(&attachment, metadata.len()))),
+        Err(_) => err!("The upload hasn't been started"),
+    }
+}
+
+/// Starts (or restarts from the beginning) the chunked upload of the file of an attachment
+/// created with the v2 API. This allows uploading large files over unreliable connections.
+#[post("/ciphers/<uuid>/attachment/<attachment_id>/chunked")]
+fn post_attachment_chunked(uuid: String, attachment_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let (_, attachment) = get_chunked_upload_target(&uuid, &attachment_id, &headers, &conn)?;
+
+    std::fs::create_dir_all(CONFIG.tmp_folder())?;
+    std::fs::File::create(attachment.get_upload_path())?;
+
+    Ok(Json(chunked_upload_json(&attachment, 0)))
+}
+
+/// Appends a chunk to a chunked upload. The offset must be the size received so far,
+/// otherwise the chunk is rejected and the client has to check the current offset.
+#[put("/ciphers/<uuid>/attachment/<attachment_id>/chunked?<offset>", data = "<data>")]
+fn put_attachment_chunk(
+    uuid: String,
+    attachment_id: String,
+    offset: u64,
+    data: Data,
+    headers: Headers,
+    conn: DbConn,
+) -> JsonResult {
+    let attachment = match get_chunked_upload_target(&uuid, &attachment_id, &headers, &conn) {
+        Ok((_, attachment)) => attachment,
+        Err(e) => err_discard!(e.message(), data),
+    };
+
+    let size = attachment.file_size.max(0) as u64;
+    let received = append_upload_chunk(&attachment.get_upload_path(), offset, size, data, "attachment")?;
+
+    Ok(Json(chunked_upload_json(&attachment, received)))
+}
+
+/// Appends a chunk to the file of a chunked upload, after checking that `offset` is the size received so far
+/// and that the file doesn't go over its announced `size`. Returns the size received with this chunk.
+pub fn append_upload_chunk(path: &Path, offset: u64, size: u64, data: Data, kind: &str) -> ApiResult<u64> {
+    use std::io::Read;
+
+    let received = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => err_discard!("The upload hasn't been started", data),
+    };
+
+    if offset != received {
+        let msg = format!("Invalid offset, {} bytes have been received so far", received);
+        err_discard!(&msg, data)
+    }
+
+    let remaining = size.saturating_sub(received);
+    let mut file = std::fs::OpenOptions::new().append(true).open(path)?;
+    let written = std::io::copy(&mut data.open().take(remaining + 1), &mut file)?;
+
+    if written > remaining {
+        file.set_len(received)?;
+        err!(format!("The uploaded data exceeds the size of the {}", kind))
+    }
+
+    Ok(received + written)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+pub struct ChunkedUploadFinalizeData {
+    // Hex encoded SHA-256 hash of the whole (encrypted) file
+    pub Hash: String,
+}
+
+/// Completes a chunked upload once all the chunks have been received, the file is checked
+/// against the size and hash provided by the client and then moved to the attachments storage.
+#[post("/ciphers/<uuid>/attachment/<attachment_id>/chunked/finalize", data = "<data>")]
+fn post_attachment_chunked_finalize(
+    uuid: String,
+    attachment_id: String,
+    data: JsonUpcase<ChunkedUploadFinalizeData>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: ChunkedUploadFinalizeData = data.into_inner().data;
+    let (cipher, mut attachment) = get_chunked_upload_target(&uuid, &attachment_id, &headers, &conn)?;
+
+    let path = attachment.get_upload_path();
+    let received = match std::fs::metadata(&path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => err!("The upload hasn't been started"),
+    };
+
+    if received != attachment.file_size.max(0) as u64 {
+        err!(format!("The upload is incomplete, {} of {} bytes have been received", received, attachment.file_size))
+    }
+
+    let hash = crypto::sha256_file(&path)?;
+    if !crypto::ct_eq(&hash, data.Hash.to_lowercase()) {
+        // The content is corrupted, the client needs to start the upload again
+        std::fs::remove_file(&path).ok();
+        err!("The hash of the uploaded data doesn't match")
+    }
+
+    attachment.save_file(&path, hash, &conn)?;
+
+    nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(&conn));
+
+    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
+}
+
 /// Legacy API for creating an attachment associated with a cipher.
 #[post("/ciphers/<uuid>/attachment", format = "multipart/form-data", data = "<data>")]
 fn post_attachment(
@@ -1198,6 +2008,28 @@ fn restore_cipher_selected(data: JsonUpcase<Value>, headers: Headers, conn: DbCo
     _restore_multiple_ciphers(data, &headers, &conn, &nt)
 }
 
+/// Archived ciphers are hidden from the default views of the clients, but unlike
+/// trashed ciphers they are never purged.
+#[put("/ciphers/<uuid>/archive")]
+fn archive_cipher_put(uuid: String, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+    _set_cipher_archived_by_uuid(&uuid, true, &headers, &conn, &nt)
+}
+
+#[put("/ciphers/archive", data = "<data>")]
+fn archive_cipher_selected(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+    _set_multiple_ciphers_archived(data, true, &headers, &conn, &nt)
+}
+
+#[put("/ciphers/<uuid>/unarchive")]
+fn unarchive_cipher_put(uuid: String, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+    _set_cipher_archived_by_uuid(&uuid, false, &headers, &conn, &nt)
+}
+
+#[put("/ciphers/unarchive", data = "<data>")]
+fn unarchive_cipher_selected(data: JsonUpcase<Value>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+    _set_multiple_ciphers_archived(data, false, &headers, &conn, &nt)
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct MoveCipherData {
@@ -1221,8 +2053,8 @@ fn move_cipher_selected(data: JsonUpcase<MoveCipherData>, headers: Headers, conn
         }
     }
 
-    for uuid in data.Ids {
-        let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+    _bulk_cipher_operation(&data.Ids, &conn, &nt, |uuid, changes| {
+        let cipher = match Cipher::find_by_uuid(uuid, &conn) {
             Some(cipher) => cipher,
             None => err!("Cipher doesn't exist"),
         };
@@ -1234,10 +2066,9 @@ fn move_cipher_selected(data: JsonUpcase<MoveCipherData>, headers: Headers, conn
         // Move cipher
         cipher.move_to_folder(data.FolderId.clone(), &user_uuid, &conn)?;
 
-        nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &[user_uuid.clone()]);
-    }
-
-    Ok(())
+        changes.notify(UpdateType::CipherUpdate, cipher, vec![user_uuid.clone()]);
+        Ok(())
+    })
 }
 
 #[put("/ciphers/move", data = "<data>")]
@@ -1280,7 +2111,9 @@ fn delete_all(
                 None => err!("You don't have permission to purge the organization vault"),
                 Some(user_org) => {
                     if user_org.atype == UserOrgType::Owner {
-                        Cipher::delete_all_by_organization(&org_data.org_id, &conn)?;
+                        let attachments =
+                            conn.transaction(|| Cipher::delete_all_by_organization(&org_data.org_id, &conn))?;
+                        Attachment::delete_files(&attachments, &conn);
                         nt.send_user_update(UpdateType::Vault, &user);
                         Ok(())
                     } else {
@@ -1291,15 +2124,17 @@ fn delete_all(
         }
         None => {
             // No organization ID in query params, purging user vault
-            // Delete ciphers and their attachments
-            for cipher in Cipher::find_owned_by_user(&user.uuid, &conn) {
-                cipher.delete(&conn)?;
-            }
+            let attachments = conn.transaction(|| {
+                // Delete ciphers and their attachments
+                let attachments = Cipher::delete_all_by_user(&user.uuid, &conn)?;
 
-            // Delete folders
-            for f in Folder::find_by_user(&user.uuid, &conn) {
-                f.delete(&conn)?;
-            }
+                // Delete folders
+                for f in Folder::find_by_user(&user.uuid, &conn) {
+                    f.delete(&conn)?;
+                }
+                Ok(attachments)
+            })?;
+            Attachment::delete_files(&attachments, &conn);
 
             user.update_revision(&conn)?;
             nt.send_user_update(UpdateType::Vault, &user);
@@ -1309,6 +2144,16 @@ fn delete_all(
 }
 
 fn _delete_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, soft_delete: bool, nt: &Notify) -> EmptyResult {
+    _deferred_cipher_operation(conn, nt, |changes| _delete_cipher(uuid, headers, conn, soft_delete, changes))
+}
+
+fn _delete_cipher(
+    uuid: &str,
+    headers: &Headers,
+    conn: &DbConn,
+    soft_delete: bool,
+    changes: &mut DeferredChanges,
+) -> EmptyResult {
     let mut cipher = match Cipher::find_by_uuid(uuid, conn) {
         Some(cipher) => cipher,
         None => err!("Cipher doesn't exist"),
@@ -1321,10 +2166,14 @@ fn _delete_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, soft_del
     if soft_delete {
         cipher.deleted_at = Some(Utc::now().naive_utc());
         cipher.save(conn)?;
-        nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(conn));
+        let user_uuids = cipher.update_users_revision(conn);
+        changes.notify(UpdateType::CipherUpdate, cipher, user_uuids);
     } else {
-        cipher.delete(conn)?;
-        nt.send_cipher_update(UpdateType::CipherDelete, &cipher, &cipher.update_users_revision(conn));
+        // The users have to be looked up before the collections of the cipher are removed
+        let user_uuids = cipher.update_users_revision(conn);
+        let attachments = cipher.delete_records(conn)?;
+        changes.delete_files(attachments);
+        changes.notify(UpdateType::CipherDelete, cipher, user_uuids);
     }
 
     Ok(())
@@ -1337,26 +2186,18 @@ fn _delete_multiple_ciphers(
     soft_delete: bool,
     nt: Notify,
 ) -> EmptyResult {
-    let data: Value = data.into_inner().data;
+    let uuids = _get_cipher_ids(data.into_inner().data)?;
 
-    let uuids = match data.get("Ids") {
-        Some(ids) => match ids.as_array() {
-            Some(ids) => ids.iter().filter_map(Value::as_str),
-            None => err!("Posted ids field is not an array"),
-        },
-        None => err!("Request missing ids field"),
-    };
-
-    for uuid in uuids {
-        if let error @ Err(_) = _delete_cipher_by_uuid(uuid, &headers, &conn, soft_delete, &nt) {
-            return error;
-        };
-    }
-
-    Ok(())
+    _bulk_cipher_operation(&uuids, &conn, &nt, |uuid, changes| {
+        _delete_cipher(uuid, &headers, &conn, soft_delete, changes)
+    })
 }
 
 fn _restore_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, nt: &Notify) -> JsonResult {
+    _deferred_cipher_operation(conn, nt, |changes| _restore_cipher(uuid, headers, conn, changes))
+}
+
+fn _restore_cipher(uuid: &str, headers: &Headers, conn: &DbConn, changes: &mut DeferredChanges) -> JsonResult {
     let mut cipher = match Cipher::find_by_uuid(uuid, conn) {
         Some(cipher) => cipher,
         None => err!("Cipher doesn't exist"),
@@ -1369,29 +2210,87 @@ fn _restore_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, nt: &No
     cipher.deleted_at = None;
     cipher.save(conn)?;
 
-    nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(conn));
-    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, conn)))
+    let json = cipher.to_json(&headers.host, &headers.user.uuid, conn);
+    let user_uuids = cipher.update_users_revision(conn);
+    changes.notify(UpdateType::CipherUpdate, cipher, user_uuids);
+    Ok(Json(json))
 }
 
 fn _restore_multiple_ciphers(data: JsonUpcase<Value>, headers: &Headers, conn: &DbConn, nt: &Notify) -> JsonResult {
-    let data: Value = data.into_inner().data;
+    let uuids = _get_cipher_ids(data.into_inner().data)?;
 
-    let uuids = match data.get("Ids") {
-        Some(ids) => match ids.as_array() {
-            Some(ids) => ids.iter().filter_map(Value::as_str),
-            None => err!("Posted ids field is not an array"),
-        },
-        None => err!("Request missing ids field"),
+    let mut ciphers: Vec<Value> = Vec::new();
+    _bulk_cipher_operation(&uuids, conn, nt, |uuid, changes| {
+        ciphers.push(_restore_cipher(uuid, headers, conn, changes)?.into_inner());
+        Ok(())
+    })?;
+
+    Ok(Json(json!({
+      "Data": ciphers,
+      "Object": "list",
+      "ContinuationToken": null
+    })))
+}
+
+fn _set_cipher_archived_by_uuid(
+    uuid: &str,
+    archived: bool,
+    headers: &Headers,
+    conn: &DbConn,
+    nt: &Notify,
+) -> JsonResult {
+    _deferred_cipher_operation(conn, nt, |changes| _set_cipher_archived(uuid, archived, headers, conn, changes))
+}
+
+fn _set_cipher_archived(
+    uuid: &str,
+    archived: bool,
+    headers: &Headers,
+    conn: &DbConn,
+    changes: &mut DeferredChanges,
+) -> JsonResult {
+    let mut cipher = match Cipher::find_by_uuid(uuid, conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
     };
 
-    let mut ciphers: Vec<Value> = Vec::new();
-    for uuid in uuids {
-        match _restore_cipher_by_uuid(uuid, headers, conn, nt) {
-            Ok(json) => ciphers.push(json.into_inner()),
-            err => return err,
-        }
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, conn) {
+        err!("Cipher is not write accessible")
+    }
+
+    if cipher.deleted_at.is_some() {
+        err!("Cipher is in the trash")
     }
 
+    // Archiving an already archived cipher keeps the original date
+    cipher.archived_at = if archived {
+        cipher.archived_at.or_else(|| Some(Utc::now().naive_utc()))
+    } else {
+        None
+    };
+    cipher.save(conn)?;
+
+    let json = cipher.to_json(&headers.host, &headers.user.uuid, conn);
+    let user_uuids = cipher.update_users_revision(conn);
+    changes.notify(UpdateType::CipherUpdate, cipher, user_uuids);
+    Ok(Json(json))
+}
+
+fn _set_multiple_ciphers_archived(
+    data: JsonUpcase<Value>,
+    archived: bool,
+    headers: &Headers,
+    conn: &DbConn,
+    nt: &Notify,
+) -> JsonResult {
+    let uuids = _get_cipher_ids(data.into_inner().data)?;
+
+    let mut ciphers: Vec<Value> = Vec::new();
+    _bulk_cipher_operation(&uuids, conn, nt, |uuid, changes| {
+        ciphers.push(_set_cipher_archived(uuid, archived, headers, conn, changes)?.into_inner());
+        Ok(())
+    })?;
+
     Ok(Json(json!({
       "Data": ciphers,
       "Object": "list",
@@ -1399,6 +2298,89 @@ fn _restore_multiple_ciphers(data: JsonUpcase<Value>, headers: &Headers, conn: &
     })))
 }
 
+/// Reads the `Ids` array sent by the clients for the bulk cipher operations.
+fn _get_cipher_ids(data: Value) -> ApiResult<Vec<String>> {
+    match data.get("Ids") {
+        Some(ids) => match ids.as_array() {
+            Some(ids) => Ok(ids.iter().filter_map(Value::as_str).map(String::from).collect()),
+            None => err!("Posted ids field is not an array"),
+        },
+        None => err!("Request missing ids field"),
+    }
+}
+
+/// Side effects of the cipher operations that can't be rolled back, like removing the attachment
+/// files or notifying the clients. They are collected while the transaction runs, and only
+/// applied once it has been committed.
+#[derive(Default)]
+struct DeferredChanges {
+    attachments: Vec<Attachment>,
+    notifications: Vec<(UpdateType, Cipher, Vec<String>)>,
+}
+
+impl DeferredChanges {
+    fn notify(&mut self, ut: UpdateType, cipher: Cipher, user_uuids: Vec<String>) {
+        self.notifications.push((ut, cipher, user_uuids));
+    }
+
+    fn delete_files(&mut self, attachments: Vec<Attachment>) {
+        self.attachments.extend(attachments);
+    }
+
+    fn apply(self, conn: &DbConn, nt: &Notify) {
+        Attachment::delete_files(&self.attachments, conn);
+        for (ut, cipher, user_uuids) in self.notifications {
+            nt.send_cipher_update(ut, &cipher, &user_uuids);
+        }
+    }
+}
+
+/// Runs a cipher operation in a transaction, applying its deferred changes after the commit.
+fn _deferred_cipher_operation<T, F>(conn: &DbConn, nt: &Notify, op: F) -> ApiResult<T>
+where
+    F: FnOnce(&mut DeferredChanges) -> ApiResult<T>,
+{
+    let mut changes = DeferredChanges::default();
+    let result = conn.transaction(|| op(&mut changes))?;
+    changes.apply(conn, nt);
+    Ok(result)
+}
+
+/// Runs a bulk operation on every cipher in a single transaction. All the ciphers are processed so
+/// every failure can be reported at once, keyed by cipher id, but if any of them fails none of the
+/// changes are saved.
+fn _bulk_cipher_operation<F>(uuids: &[String], conn: &DbConn, nt: &Notify, mut op: F) -> EmptyResult
+where
+    F: FnMut(&str, &mut DeferredChanges) -> EmptyResult,
+{
+    _deferred_cipher_operation(conn, nt, |changes| {
+        let mut errors = serde_json::Map::new();
+        for uuid in uuids {
+            if let Err(e) = op(uuid, changes) {
+                errors.insert(uuid.clone(), json!([e.message()]));
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        let msg = format!("{} of {} items could not be processed, no changes were saved", errors.len(), uuids.len());
+        err_json!(
+            json!({
+                "Message": msg,
+                "ValidationErrors": errors,
+                "ErrorModel": {
+                    "Message": msg,
+                    "Object": "error"
+                },
+                "Object": "error"
+            }),
+            msg
+        )
+    })
+}
+
 fn _delete_cipher_attachment_by_id(
     uuid: &str,
     attachment_id: &str,
@@ -1429,3 +2411,36 @@ fn _delete_cipher_attachment_by_id(
     nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(conn));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn full_history(newest: &str) -> Value {
+        let entries = [newest, "p4", "p3", "p2", "p1"];
+        Value::Array(entries.iter().map(|p| json!({ "LastUsedDate": "2021-01-01T00:00:00Z", "Password": p })).collect())
+    }
+
+    fn passwords(history: &Value) -> Vec<&str> {
+        history.as_array().unwrap().iter().map(|e| e["Password"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_full_history_updated_by_client_is_kept() {
+        let old_data = r#"{"Password":"p5","PasswordRevisionDate":"2021-01-01T00:00:00Z"}"#;
+        let new_data = json!({ "Password": "p6", "PasswordRevisionDate": "2021-02-01T00:00:00Z" });
+
+        // The client added p5 and dropped p0, so the history still has five entries
+        let history = track_password_change(old_data, &new_data, Some(full_history("p5"))).unwrap();
+        assert_eq!(passwords(&history), ["p5", "p4", "p3", "p2", "p1"]);
+    }
+
+    #[test]
+    fn test_full_history_without_client_entry() {
+        let old_data = r#"{"Password":"p6","PasswordRevisionDate":"2021-01-01T00:00:00Z"}"#;
+        let new_data = json!({ "Password": "p7", "PasswordRevisionDate": "2021-02-01T00:00:00Z" });
+
+        let history = track_password_change(old_data, &new_data, Some(full_history("p5"))).unwrap();
+        assert_eq!(passwords(&history), ["p6", "p5", "p4", "p3", "p2"]);
+    }
+}
@@ -8,7 +8,17 @@ use crate::{
 };
 
 pub fn routes() -> Vec<rocket::Route> {
-    routes![get_folders, get_folder, post_folders, post_folder, put_folder, delete_folder_post, delete_folder,]
+    routes![
+        get_folders,
+        get_folder,
+        post_folders,
+        post_folders_bulk,
+        post_folder,
+        put_folder,
+        post_folder_merge,
+        delete_folder_post,
+        delete_folder,
+    ]
 }
 
 #[get("/folders")]
@@ -56,6 +66,38 @@ fn post_folders(data: JsonUpcase<FolderData>, headers: Headers, conn: DbConn, nt
     Ok(Json(folder.to_json()))
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct BulkFolderData {
+    Folders: Vec<FolderData>,
+}
+
+/// Creates many folders at once, for the clients importing large vaults.
+/// Either all the folders are created or none of them.
+#[post("/folders/bulk", data = "<data>")]
+fn post_folders_bulk(data: JsonUpcase<BulkFolderData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+    let data: BulkFolderData = data.into_inner().data;
+
+    let folders = conn.transaction(|| {
+        let mut folders = Vec::with_capacity(data.Folders.len());
+        for folder_data in data.Folders {
+            let mut folder = Folder::new(headers.user.uuid.clone(), folder_data.Name);
+            folder.save(&conn)?;
+            folders.push(folder);
+        }
+        Ok(folders)
+    })?;
+
+    nt.send_user_update(UpdateType::Vault, &headers.user);
+
+    let folders_json: Vec<Value> = folders.iter().map(Folder::to_json).collect();
+    Ok(Json(json!({
+      "Data": folders_json,
+      "Object": "list",
+      "ContinuationToken": null,
+    })))
+}
+
 #[post("/folders/<uuid>", data = "<data>")]
 fn post_folder(uuid: String, data: JsonUpcase<FolderData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
     put_folder(uuid, data, headers, conn, nt)
@@ -82,6 +124,49 @@ fn put_folder(uuid: String, data: JsonUpcase<FolderData>, headers: Headers, conn
     Ok(Json(folder.to_json()))
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct MergeFolderData {
+    TargetFolderId: String,
+}
+
+/// Moves all the ciphers of a folder into another one, and deletes the now empty folder
+#[post("/folders/<uuid>/merge", data = "<data>")]
+fn post_folder_merge(
+    uuid: String,
+    data: JsonUpcase<MergeFolderData>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: MergeFolderData = data.into_inner().data;
+
+    if uuid == data.TargetFolderId {
+        err!("A folder can't be merged into itself")
+    }
+
+    let (folder, target) =
+        match (Folder::find_by_uuid(&uuid, &conn), Folder::find_by_uuid(&data.TargetFolderId, &conn)) {
+            (Some(folder), Some(target)) => (folder, target),
+            _ => err!("Invalid folder"),
+        };
+
+    if folder.user_uuid != headers.user.uuid || target.user_uuid != headers.user.uuid {
+        err!("Folder belongs to another user")
+    }
+
+    conn.transaction(|| {
+        for folder_cipher in FolderCipher::find_by_folder(&folder.uuid, &conn) {
+            FolderCipher::new(&target.uuid, &folder_cipher.cipher_uuid).save(&conn)?;
+        }
+        folder.delete(&conn)
+    })?;
+
+    nt.send_user_update(UpdateType::Vault, &headers.user);
+
+    Ok(Json(target.to_json()))
+}
+
 #[post("/folders/<uuid>/delete")]
 fn delete_folder_post(uuid: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
     delete_folder(uuid, headers, conn, nt)
@@ -5,7 +5,7 @@ mod organizations;
 mod sends;
 pub mod two_factor;
 
-pub use ciphers::purge_trashed_ciphers;
+pub use ciphers::{purge_orphaned_attachment_files, purge_orphaned_attachments, purge_trashed_ciphers};
 pub use sends::purge_sends;
 
 pub fn routes() -> Vec<Route> {
@@ -27,6 +27,14 @@ pub fn routes() -> Vec<Route> {
 //
 // Move this somewhere else
 //
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use rocket::Route;
 use rocket_contrib::json::Json;
 use serde_json::Value;
@@ -34,7 +42,7 @@ use serde_json::Value;
 use crate::{
     api::{JsonResult, JsonUpcase},
     auth::Headers,
-    db::DbConn,
+    db::{models::User, DbConn},
     error::Error,
     util::get_reqwest_client,
 };
@@ -82,11 +90,10 @@ const GLOBAL_DOMAINS: &str = include_str!("../../static/global_domains.json");
 
 #[get("/settings/domains")]
 fn get_eq_domains(headers: Headers) -> Json<Value> {
-    _get_eq_domains(headers, false)
+    _get_eq_domains(&headers.user, false)
 }
 
-fn _get_eq_domains(headers: Headers, no_excluded: bool) -> Json<Value> {
-    let user = headers.user;
+fn _get_eq_domains(user: &User, no_excluded: bool) -> Json<Value> {
     use serde_json::from_str;
 
     let equivalent_domains: Vec<Vec<String>> = from_str(&user.equivalent_domains).unwrap();
@@ -121,7 +128,21 @@ fn post_eq_domains(data: JsonUpcase<EquivDomainData>, headers: Headers, conn: Db
     let data: EquivDomainData = data.into_inner().data;
 
     let excluded_globals = data.ExcludedGlobalEquivalentDomains.unwrap_or_default();
-    let equivalent_domains = data.EquivalentDomains.unwrap_or_default();
+
+    // Normalize the custom groups, a group needs at least two domains to be useful
+    let equivalent_domains: Vec<Vec<String>> = data
+        .EquivalentDomains
+        .unwrap_or_default()
+        .into_iter()
+        .map(|group| {
+            let mut domains: Vec<String> =
+                group.iter().map(|d| d.trim().to_lowercase()).filter(|d| !d.is_empty()).collect();
+            domains.sort();
+            domains.dedup();
+            domains
+        })
+        .filter(|group| group.len() > 1)
+        .collect();
 
     let mut user = headers.user;
     use serde_json::to_string;
@@ -131,7 +152,7 @@ fn post_eq_domains(data: JsonUpcase<EquivDomainData>, headers: Headers, conn: Db
 
     user.save(&conn)?;
 
-    Ok(Json(json!({})))
+    Ok(_get_eq_domains(&user, false))
 }
 
 #[put("/settings/domains", data = "<data>")]
@@ -139,25 +160,51 @@ fn put_eq_domains(data: JsonUpcase<EquivDomainData>, headers: Headers, conn: DbC
     post_eq_domains(data, headers, conn)
 }
 
-#[get("/hibp/breach?<username>")]
-fn hibp_breach(username: String) -> JsonResult {
+/// Breach reports received from HIBP, by account. `None` means that the account has no breaches.
+static HIBP_CACHE: Lazy<RwLock<HashMap<String, (Instant, Option<Value>)>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn get_hibp_breaches(username: &str, api_key: &str) -> Result<Option<Value>, Error> {
+    let ttl = Duration::from_secs(crate::CONFIG.hibp_cache_ttl());
+    let key = username.to_lowercase();
+
+    if let Some((fetched, breaches)) = HIBP_CACHE.read().unwrap().get(&key) {
+        if fetched.elapsed() < ttl {
+            return Ok(breaches.clone());
+        }
+    }
+
     let url = format!(
         "https://haveibeenpwned.com/api/v3/breachedaccount/{}?truncateResponse=false&includeUnverified=false",
-        username
+        utf8_percent_encode(username, NON_ALPHANUMERIC)
     );
 
-    if let Some(api_key) = crate::CONFIG.hibp_api_key() {
-        let hibp_client = get_reqwest_client();
+    let hibp_client = get_reqwest_client();
+    let res = hibp_client.get(&url).header("hibp-api-key", api_key).send()?;
 
-        let res = hibp_client.get(&url).header("hibp-api-key", api_key).send()?;
+    // A 404 means no breached accounts
+    let breaches = if res.status() == 404 {
+        None
+    } else {
+        Some(res.error_for_status()?.json()?)
+    };
 
-        // If we get a 404, return a 404, it means no breached accounts
-        if res.status() == 404 {
-            return Err(Error::empty().with_code(404));
-        }
+    if ttl.as_secs() > 0 {
+        let mut cache = HIBP_CACHE.write().unwrap();
+        cache.retain(|_, (fetched, _)| fetched.elapsed() < ttl);
+        cache.insert(key, (Instant::now(), breaches.clone()));
+    }
 
-        let value: Value = res.error_for_status()?.json()?;
-        Ok(Json(value))
+    Ok(breaches)
+}
+
+#[get("/hibp/breach?<username>")]
+fn hibp_breach(username: String, _headers: Headers) -> JsonResult {
+    if let Some(api_key) = crate::CONFIG.hibp_api_key() {
+        match get_hibp_breaches(&username, &api_key)? {
+            Some(breaches) => Ok(Json(breaches)),
+            // If we get a 404, return a 404, it means no breached accounts
+            None => Err(Error::empty().with_code(404)),
+        }
     } else {
         Ok(Json(json!([{
             "Name": "HaveIBeenPwned",
@@ -24,6 +24,8 @@ pub fn routes() -> Vec<Route> {
         put_collection_users,
         put_organization,
         post_organization,
+        get_organization_email_message,
+        put_organization_email_message,
         post_organization_collections,
         delete_organization_collection_user,
         post_organization_collection_delete_user,
@@ -33,6 +35,7 @@ pub fn routes() -> Vec<Route> {
         post_organization_collection_delete,
         get_org_details,
         get_org_users,
+        get_org_member_activity,
         send_invite,
         reinvite_user,
         confirm_invite,
@@ -42,7 +45,9 @@ pub fn routes() -> Vec<Route> {
         put_organization_user,
         delete_user,
         post_delete_user,
+        transfer_ownership,
         post_org_import,
+        post_org_rotate_key,
         list_policies,
         list_policies_token,
         get_policy,
@@ -76,6 +81,7 @@ struct OrganizationUpdateData {
 #[allow(non_snake_case)]
 struct NewCollectionData {
     Name: String,
+    ExternalId: Option<String>,
 }
 
 #[post("/organizations", data = "<data>")]
@@ -137,13 +143,8 @@ fn leave_organization(org_id: String, headers: Headers, conn: DbConn) -> EmptyRe
     match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, &conn) {
         None => err!("User not part of organization"),
         Some(user_org) => {
-            if user_org.atype == UserOrgType::Owner {
-                let num_owners =
-                    UserOrganization::find_by_org_and_type(&org_id, UserOrgType::Owner as i32, &conn).len();
-
-                if num_owners <= 1 {
-                    err!("The last owner can't leave")
-                }
+            if user_org.is_last_confirmed_owner(&conn) {
+                err!("The last owner can't leave")
             }
 
             user_org.delete(&conn)
@@ -153,10 +154,22 @@ fn leave_organization(org_id: String, headers: Headers, conn: DbConn) -> EmptyRe
 
 #[get("/organizations/<org_id>")]
 fn get_organization(org_id: String, _headers: OwnerHeaders, conn: DbConn) -> JsonResult {
-    match Organization::find_by_uuid(&org_id, &conn) {
-        Some(organization) => Ok(Json(organization.to_json())),
+    let organization = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(organization) => organization,
         None => err!("Can't find organization details"),
+    };
+
+    // Report the attachment storage used by the organization and the configured quota
+    let storage_used = Attachment::size_by_org(&org_id, &conn);
+
+    let mut org_json = organization.to_json();
+    org_json["StorageName"] = json!(crate::util::get_display_size(storage_used));
+    org_json["StorageGb"] = json!(crate::util::get_size_gb(storage_used));
+    if let Some(limit_kb) = CONFIG.org_attachment_limit() {
+        org_json["MaxStorageGb"] = json!(crate::util::get_size_gb(limit_kb * 1024).ceil() as i64);
     }
+
+    Ok(Json(org_json))
 }
 
 #[put("/organizations/<org_id>", data = "<data>")]
@@ -190,6 +203,58 @@ fn post_organization(
     Ok(Json(org.to_json()))
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgEmailMessageData {
+    Message: Option<String>,
+}
+
+fn org_email_message_json(org: &Organization) -> Value {
+    json!({
+        "OrganizationId": org.uuid,
+        "Message": org.email_message,
+        "Object": "organizationEmailMessage",
+    })
+}
+
+#[get("/organizations/<org_id>/email-message")]
+fn get_organization_email_message(org_id: String, _headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+    match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => Ok(Json(org_email_message_json(&org))),
+        None => err!("Can't find organization details"),
+    }
+}
+
+/// Stores a custom message that is added to the invitation and removal emails sent for this organization.
+/// An empty message removes it.
+#[put("/organizations/<org_id>/email-message", data = "<data>")]
+fn put_organization_email_message(
+    org_id: String,
+    _headers: OwnerHeaders,
+    data: JsonUpcase<OrgEmailMessageData>,
+    conn: DbConn,
+) -> JsonResult {
+    const MAX_MESSAGE_LENGTH: usize = 1000;
+
+    let data: OrgEmailMessageData = data.into_inner().data;
+
+    let mut org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(organization) => organization,
+        None => err!("Can't find organization details"),
+    };
+
+    let message = data.Message.map(|m| m.trim().to_string()).filter(|m| !m.is_empty());
+    if let Some(ref message) = message {
+        if message.chars().count() > MAX_MESSAGE_LENGTH {
+            err!(format!("The message can't be longer than {} characters", MAX_MESSAGE_LENGTH))
+        }
+    }
+
+    org.email_message = message;
+    org.save(&conn)?;
+    Ok(Json(org_email_message_json(&org)))
+}
+
 // GET /api/collections?writeOnly=false
 #[get("/collections")]
 fn get_user_collections(headers: Headers, conn: DbConn) -> Json<Value> {
@@ -205,10 +270,17 @@ fn get_user_collections(headers: Headers, conn: DbConn) -> Json<Value> {
 }
 
 #[get("/organizations/<org_id>/collections")]
-fn get_org_collections(org_id: String, _headers: AdminHeaders, conn: DbConn) -> Json<Value> {
+fn get_org_collections(org_id: String, headers: ManagerHeadersLoose, conn: DbConn) -> Json<Value> {
+    // Managers without access to all collections can only see the ones they are assigned to
+    let collections = if headers.org_user.has_full_access() {
+        Collection::find_by_organization(&org_id, &conn)
+    } else {
+        Collection::find_by_organization_and_user_uuid(&org_id, &headers.user.uuid, &conn)
+    };
+
     Json(json!({
         "Data":
-            Collection::find_by_organization(&org_id, &conn)
+            collections
             .iter()
             .map(Collection::to_json)
             .collect::<Value>(),
@@ -231,19 +303,14 @@ fn post_organization_collections(
         None => err!("Can't find organization details"),
     };
 
-    // Get the user_organization record so that we can check if the user has access to all collections.
-    let user_org = match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, &conn) {
-        Some(u) => u,
-        None => err!("User is not part of organization"),
-    };
-
-    let collection = Collection::new(org.uuid, data.Name);
+    let mut collection = Collection::new(org.uuid, data.Name);
+    collection.external_id = data.ExternalId;
     collection.save(&conn)?;
 
     // If the user doesn't have access to all collections, only in case of a Manger,
     // then we need to save the creating user uuid (Manager) to the users_collection table.
     // Else the user will not have access to his own created collection.
-    if !user_org.access_all {
+    if !headers.org_user.access_all {
         CollectionUser::save(&headers.user.uuid, &collection.uuid, false, false, &conn)?;
     }
 
@@ -286,6 +353,7 @@ fn post_organization_collection_update(
     }
 
     collection.name = data.Name;
+    collection.external_id = data.ExternalId;
     collection.save(&conn)?;
 
     Ok(Json(collection.to_json()))
@@ -296,7 +364,7 @@ fn delete_organization_collection_user(
     org_id: String,
     col_id: String,
     org_user_id: String,
-    _headers: AdminHeaders,
+    _headers: ManagerHeaders,
     conn: DbConn,
 ) -> EmptyResult {
     let collection = match Collection::find_by_uuid(&col_id, &conn) {
@@ -326,7 +394,7 @@ fn post_organization_collection_delete_user(
     org_id: String,
     col_id: String,
     org_user_id: String,
-    headers: AdminHeaders,
+    headers: ManagerHeaders,
     conn: DbConn,
 ) -> EmptyResult {
     delete_organization_collection_user(org_id, col_id, org_user_id, headers, conn)
@@ -422,7 +490,7 @@ fn put_collection_users(
 
     // And then add all the received ones (except if the user has access_all)
     for d in data.iter().map(|d| &d.data) {
-        let user = match UserOrganization::find_by_uuid(&d.Id, &conn) {
+        let user = match UserOrganization::find_by_uuid_and_org(&d.Id, &org_id, &conn) {
             Some(u) => u,
             None => err!("User is not part of organization"),
         };
@@ -443,8 +511,11 @@ struct OrgIdData {
     organization_id: String,
 }
 
+/// Returns all the ciphers of the organization, including the ones not assigned to any collection.
+/// The clients use them for the organization vault of the admins and to build the vault health reports
+/// (exposed, reused and weak passwords, inactive 2FA), which need the decrypted data and are computed client-side.
 #[get("/ciphers/organization-details?<data..>")]
-fn get_org_details(data: Form<OrgIdData>, headers: Headers, conn: DbConn) -> Json<Value> {
+fn get_org_details(data: Form<OrgIdData>, headers: AdminHeaders, conn: DbConn) -> Json<Value> {
     let ciphers = Cipher::find_by_org(&data.organization_id, &conn);
     let ciphers_json: Vec<Value> =
         ciphers.iter().map(|c| c.to_json(&headers.host, &headers.user.uuid, &conn)).collect();
@@ -468,6 +539,41 @@ fn get_org_users(org_id: String, _headers: ManagerHeadersLoose, conn: DbConn) ->
     }))
 }
 
+/// Summarizes the recent activity of every member, to help spotting dormant accounts.
+/// There is no event log, so the cipher activity only covers the ciphers owned by the member,
+/// changes to organization ciphers can't be attributed to a specific user.
+#[get("/organizations/<org_id>/reports/member-activity")]
+fn get_org_member_activity(org_id: String, _headers: AdminHeaders, conn: DbConn) -> Json<Value> {
+    use crate::util::format_date;
+
+    let users_json: Vec<Value> = UserOrganization::find_by_org(&org_id, &conn)
+        .iter()
+        .filter_map(|user_org| {
+            let user = User::find_by_uuid(&user_org.user_uuid, &conn)?;
+            let last_cipher_activity = Cipher::last_updated_owned_by_user(&user.uuid, &conn);
+            Some(json!({
+                "Id": user_org.uuid,
+                "UserId": user.uuid,
+                "Name": user.name,
+                "Email": user.email,
+                "Status": user_org.status,
+                "Type": user_org.atype,
+                "LastLoginDate": user.last_active(&conn).map(|dt| format_date(&dt)),
+                "DeviceCount": Device::find_by_user(&user.uuid, &conn).len(),
+                "TwoFactorEnabled": !TwoFactor::find_by_user(&user.uuid, &conn).is_empty(),
+                "LastCipherActivityDate": last_cipher_activity.map(|dt| format_date(&dt)),
+                "Object": "organizationMemberActivity",
+            }))
+        })
+        .collect();
+
+    Json(json!({
+        "Data": users_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    }))
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct CollectionData {
@@ -554,8 +660,8 @@ fn send_invite(org_id: String, data: JsonUpcase<InviteData>, headers: AdminHeade
         new_user.save(&conn)?;
 
         if CONFIG.mail_enabled() {
-            let org_name = match Organization::find_by_uuid(&org_id, &conn) {
-                Some(org) => org.name,
+            let org = match Organization::find_by_uuid(&org_id, &conn) {
+                Some(org) => org,
                 None => err!("Error looking up organization"),
             };
 
@@ -564,8 +670,9 @@ fn send_invite(org_id: String, data: JsonUpcase<InviteData>, headers: AdminHeade
                 &user.uuid,
                 Some(org_id.clone()),
                 Some(new_user.uuid),
-                &org_name,
+                &org.name,
                 Some(headers.user.email.clone()),
+                org.email_message.as_deref(),
             )?;
         }
     }
@@ -597,8 +704,8 @@ fn reinvite_user(org_id: String, user_org: String, headers: AdminHeaders, conn:
         None => err!("User not found."),
     };
 
-    let org_name = match Organization::find_by_uuid(&org_id, &conn) {
-        Some(org) => org.name,
+    let org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => org,
         None => err!("Error looking up organization."),
     };
 
@@ -608,8 +715,9 @@ fn reinvite_user(org_id: String, user_org: String, headers: AdminHeaders, conn:
             &user.uuid,
             Some(org_id),
             Some(user_org.uuid),
-            &org_name,
+            &org.name,
             Some(headers.user.email),
+            org.email_message.as_deref(),
         )?;
     } else {
         let invitation = Invitation::new(user.email);
@@ -777,13 +885,9 @@ fn edit_user(
         err!("Only Owners can edit Owner users")
     }
 
-    if user_to_edit.atype == UserOrgType::Owner && new_type != UserOrgType::Owner {
+    if new_type != UserOrgType::Owner && user_to_edit.is_last_confirmed_owner(&conn) {
         // Removing owner permmission, check that there are at least another owner
-        let num_owners = UserOrganization::find_by_org_and_type(&org_id, UserOrgType::Owner as i32, &conn).len();
-
-        if num_owners <= 1 {
-            err!("Can't delete the last owner")
-        }
+        err!("Can't delete the last owner")
     }
 
     user_to_edit.access_all = data.AccessAll;
@@ -826,16 +930,28 @@ fn delete_user(org_id: String, org_user_id: String, headers: AdminHeaders, conn:
         err!("Only Owners can delete Admins or Owners")
     }
 
-    if user_to_delete.atype == UserOrgType::Owner {
+    if user_to_delete.is_last_confirmed_owner(&conn) {
         // Removing owner, check that there are at least another owner
-        let num_owners = UserOrganization::find_by_org_and_type(&org_id, UserOrgType::Owner as i32, &conn).len();
+        err!("Can't delete the last owner")
+    }
+
+    let was_member = user_to_delete.status != UserOrgStatus::Invited as i32;
+    let user_uuid = user_to_delete.user_uuid.clone();
+    user_to_delete.delete(&conn)?;
 
-        if num_owners <= 1 {
-            err!("Can't delete the last owner")
+    // Only let members that already joined know they were removed, pending invites are just revoked.
+    // The removal is done at this point, so a failed email doesn't make the request fail.
+    if CONFIG.mail_enabled() && was_member {
+        if let (Some(user), Some(org)) =
+            (User::find_by_uuid(&user_uuid, &conn), Organization::find_by_uuid(&org_id, &conn))
+        {
+            if let Err(e) = mail::send_removed_from_org(&user.email, &org.name, org.email_message.as_deref()) {
+                error!("Error sending the removal email to {}: {:#?}", user.email, e);
+            }
         }
     }
 
-    user_to_delete.delete(&conn)
+    Ok(())
 }
 
 #[post("/organizations/<org_id>/users/<org_user_id>/delete")]
@@ -843,6 +959,74 @@ fn post_delete_user(org_id: String, org_user_id: String, headers: AdminHeaders,
     delete_user(org_id, org_user_id, headers, conn)
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OwnershipTransferData {
+    // The new type of the current owner, when not set it remains an owner
+    NewType: Option<NumberOrString>,
+    // Whether the current owner leaves the organization after the transfer
+    Leave: Option<bool>,
+}
+
+/// Promotes a confirmed member to owner and optionally demotes or removes the current owner.
+/// All the changes are saved together, and rolled back if they would leave the organization
+/// without a confirmed owner.
+#[post("/organizations/<org_id>/users/<org_user_id>/transfer-ownership", data = "<data>")]
+fn transfer_ownership(
+    org_id: String,
+    org_user_id: String,
+    data: JsonUpcase<OwnershipTransferData>,
+    headers: OwnerHeaders,
+    conn: DbConn,
+) -> EmptyResult {
+    let data: OwnershipTransferData = data.into_inner().data;
+
+    let mut new_owner = match UserOrganization::find_by_uuid_and_org(&org_user_id, &org_id, &conn) {
+        Some(user) => user,
+        None => err!("The specified user isn't member of the organization"),
+    };
+
+    if new_owner.user_uuid == headers.user.uuid {
+        err!("You are already an owner of this organization")
+    }
+
+    if !new_owner.has_status(UserOrgStatus::Confirmed) {
+        err!("Ownership can only be transferred to a confirmed member")
+    }
+
+    let mut current_owner = match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, &conn) {
+        Some(user) => user,
+        None => err!("User is not part of organization"),
+    };
+
+    let leave = data.Leave.unwrap_or(false);
+    let new_type = match data.NewType {
+        Some(new_type) => match UserOrgType::from_str(&new_type.into_string()) {
+            Some(new_type) => Some(new_type),
+            None => err!("Invalid type"),
+        },
+        None => None,
+    };
+
+    conn.transaction(|| {
+        new_owner.atype = UserOrgType::Owner as i32;
+        new_owner.save(&conn)?;
+
+        if leave {
+            current_owner.delete(&conn)?;
+        } else if let Some(new_type) = new_type {
+            current_owner.atype = new_type as i32;
+            current_owner.save(&conn)?;
+        }
+
+        if UserOrganization::count_confirmed_by_org_and_type(&org_id, UserOrgType::Owner as i32, &conn) < 1 {
+            err!("The organization needs at least one confirmed owner")
+        }
+
+        Ok(())
+    })
+}
+
 use super::ciphers::update_cipher_from_data;
 use super::ciphers::CipherData;
 
@@ -879,7 +1063,8 @@ fn post_org_import(
         .Collections
         .into_iter()
         .map(|coll| {
-            let collection = Collection::new(org_id.clone(), coll.Name);
+            let mut collection = Collection::new(org_id.clone(), coll.Name);
+            collection.external_id = coll.ExternalId;
             if collection.save(&conn).is_err() {
                 err!("Failed to create Collection");
             }
@@ -924,6 +1109,119 @@ fn post_org_import(
     user.update_revision(&conn)
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgMemberKeyData {
+    // Organization user id
+    Id: String,
+    // The new organization key, encrypted with the public key of the member
+    Key: String,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgCollectionKeyData {
+    Id: String,
+    Name: String,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgKeyRotationData {
+    MasterPasswordHash: String,
+    Keys: Vec<OrgMemberKeyData>,
+    Collections: Vec<OrgCollectionKeyData>,
+    Ciphers: Vec<CipherData>,
+}
+
+/// Replaces the organization key with a new one. The request needs to contain the new key for every
+/// confirmed member and all the collections and ciphers of the organization re-encrypted with it,
+/// otherwise some data would become unreadable. Everything is saved in a single transaction.
+#[post("/organizations/<org_id>/keys/rotate", data = "<data>")]
+fn post_org_rotate_key(
+    org_id: String,
+    data: JsonUpcase<OrgKeyRotationData>,
+    headers: OwnerHeaders,
+    conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
+    use std::collections::HashSet;
+
+    let data: OrgKeyRotationData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    // Only confirmed members have a copy of the organization key
+    let members: Vec<UserOrganization> = UserOrganization::find_by_org(&org_id, &conn)
+        .into_iter()
+        .filter(|m| m.has_status(UserOrgStatus::Confirmed))
+        .collect();
+    let member_ids: HashSet<&str> = members.iter().map(|m| m.uuid.as_str()).collect();
+    let key_ids: HashSet<&str> = data.Keys.iter().map(|k| k.Id.as_str()).collect();
+    if member_ids != key_ids || data.Keys.len() != key_ids.len() {
+        err!("A new key needs to be provided for every confirmed member of the organization")
+    }
+
+    let collections = Collection::find_by_organization(&org_id, &conn);
+    let collection_ids: HashSet<&str> = collections.iter().map(|c| c.uuid.as_str()).collect();
+    let data_collection_ids: HashSet<&str> = data.Collections.iter().map(|c| c.Id.as_str()).collect();
+    if collection_ids != data_collection_ids || data.Collections.len() != data_collection_ids.len() {
+        err!("All the collections of the organization need to be re-encrypted")
+    }
+
+    let cipher_ids: HashSet<String> = Cipher::find_by_org(&org_id, &conn).into_iter().map(|c| c.uuid).collect();
+    let data_cipher_ids: HashSet<String> = data.Ciphers.iter().filter_map(|c| c.Id.clone()).collect();
+    if cipher_ids != data_cipher_ids || data.Ciphers.len() != data_cipher_ids.len() {
+        err!("All the ciphers of the organization need to be re-encrypted")
+    }
+
+    let headers: Headers = headers.into();
+
+    conn.transaction(|| {
+        for key_data in &data.Keys {
+            let mut member = match UserOrganization::find_by_uuid_and_org(&key_data.Id, &org_id, &conn) {
+                Some(member) => member,
+                None => err!("Member doesn't exist"),
+            };
+            member.akey = key_data.Key.clone();
+            member.save(&conn)?;
+        }
+
+        for collection_data in &data.Collections {
+            let mut collection = match Collection::find_by_uuid_and_org(&collection_data.Id, &org_id, &conn) {
+                Some(collection) => collection,
+                None => err!("Collection doesn't exist"),
+            };
+            collection.name = collection_data.Name.clone();
+            collection.save(&conn)?;
+        }
+
+        for mut cipher_data in data.Ciphers {
+            let mut cipher = match Cipher::find_by_uuid(cipher_data.Id.as_ref().unwrap(), &conn) {
+                Some(cipher) => cipher,
+                None => err!("Cipher doesn't exist"),
+            };
+
+            // Folders are per user, keep the current folder of the owner untouched
+            cipher_data.FolderId = cipher.get_folder_uuid(&headers.user.uuid, &conn);
+            update_cipher_from_data(&mut cipher, cipher_data, &headers, false, &conn, &nt, UpdateType::None)?;
+        }
+
+        Ok(())
+    })?;
+
+    // The clients of all the members need to sync to get the new key and data
+    for member in members {
+        if let Some(user) = User::find_by_uuid(&member.user_uuid, &conn) {
+            nt.send_user_update(UpdateType::Vault, &user);
+        }
+    }
+
+    Ok(())
+}
+
 #[get("/organizations/<org_id>/policies")]
 fn list_policies(org_id: String, _headers: AdminHeaders, conn: DbConn) -> Json<Value> {
     let policies = OrgPolicy::find_by_org(&org_id, &conn);
@@ -998,6 +1296,10 @@ fn put_policy(
         None => err!("Invalid policy type"),
     };
 
+    if data.enabled && !OrgPolicy::is_valid_data(pol_type_enum, &data.data) {
+        err!("Invalid policy data")
+    }
+
     let mut policy = match OrgPolicy::find_by_org_and_type(&org_id, pol_type, &conn) {
         Some(p) => p,
         None => OrgPolicy::new(org_id, pol_type_enum, "{}".to_string()),
@@ -1107,11 +1409,6 @@ struct OrgImportData {
 fn import(org_id: String, data: JsonUpcase<OrgImportData>, headers: Headers, conn: DbConn) -> EmptyResult {
     let data = data.into_inner().data;
 
-    // TODO: Currently we aren't storing the externalId's anywhere, so we also don't have a way
-    // to differentiate between auto-imported users and manually added ones.
-    // This means that this endpoint can end up removing users that were added manually by an admin,
-    // as opposed to upstream which only removes auto-imported users.
-
     // User needs to be admin or owner to use the Directry Connector
     match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, &conn) {
         Some(user_org) if user_org.atype >= UserOrgType::Admin => { /* Okay, nothing to do */ }
@@ -1126,48 +1423,58 @@ fn import(org_id: String, data: JsonUpcase<OrgImportData>, headers: Headers, con
                 user_org.delete(&conn)?;
             }
 
+        // If user is already part of the organization, keep its external ID up to date
+        } else if let Some(mut user_org) = UserOrganization::find_by_email_and_org(&user_data.Email, &org_id, &conn) {
+            if user_org.external_id.as_ref() != Some(&user_data.ExternalId) {
+                user_org.external_id = Some(user_data.ExternalId.clone());
+                user_org.save(&conn)?;
+            }
+
         // If user is not part of the organization, but it exists
-        } else if UserOrganization::find_by_email_and_org(&user_data.Email, &org_id, &conn).is_none() {
-            if let Some(user) = User::find_by_mail(&user_data.Email, &conn) {
-                let user_org_status = if CONFIG.mail_enabled() {
-                    UserOrgStatus::Invited as i32
-                } else {
-                    UserOrgStatus::Accepted as i32 // Automatically mark user as accepted if no email invites
+        } else if let Some(user) = User::find_by_mail(&user_data.Email, &conn) {
+            let user_org_status = if CONFIG.mail_enabled() {
+                UserOrgStatus::Invited as i32
+            } else {
+                UserOrgStatus::Accepted as i32 // Automatically mark user as accepted if no email invites
+            };
+
+            let mut new_org_user = UserOrganization::new(user.uuid.clone(), org_id.clone());
+            new_org_user.access_all = false;
+            new_org_user.atype = UserOrgType::User as i32;
+            new_org_user.status = user_org_status;
+            new_org_user.external_id = Some(user_data.ExternalId.clone());
+
+            new_org_user.save(&conn)?;
+
+            if CONFIG.mail_enabled() {
+                let org = match Organization::find_by_uuid(&org_id, &conn) {
+                    Some(org) => org,
+                    None => err!("Error looking up organization"),
                 };
 
-                let mut new_org_user = UserOrganization::new(user.uuid.clone(), org_id.clone());
-                new_org_user.access_all = false;
-                new_org_user.atype = UserOrgType::User as i32;
-                new_org_user.status = user_org_status;
-
-                new_org_user.save(&conn)?;
-
-                if CONFIG.mail_enabled() {
-                    let org_name = match Organization::find_by_uuid(&org_id, &conn) {
-                        Some(org) => org.name,
-                        None => err!("Error looking up organization"),
-                    };
-
-                    mail::send_invite(
-                        &user_data.Email,
-                        &user.uuid,
-                        Some(org_id.clone()),
-                        Some(new_org_user.uuid),
-                        &org_name,
-                        Some(headers.user.email.clone()),
-                    )?;
-                }
+                mail::send_invite(
+                    &user_data.Email,
+                    &user.uuid,
+                    Some(org_id.clone()),
+                    Some(new_org_user.uuid),
+                    &org.name,
+                    Some(headers.user.email.clone()),
+                    org.email_message.as_deref(),
+                )?;
             }
         }
     }
 
     // If this flag is enabled, any user that isn't provided in the Users list will be removed (by default they will be kept unless they have Deleted == true)
+    // Only users that were previously imported (and thus have an external ID) are removed, manually added users are kept.
     if data.OverwriteExisting {
         for user_org in UserOrganization::find_by_org_and_type(&org_id, UserOrgType::User as i32, &conn) {
-            if let Some(user_email) = User::find_by_uuid(&user_org.user_uuid, &conn).map(|u| u.email) {
-                if !data.Users.iter().any(|u| u.Email == user_email) {
-                    user_org.delete(&conn)?;
-                }
+            let external_id = match user_org.external_id {
+                Some(ref external_id) => external_id.clone(),
+                None => continue,
+            };
+            if !data.Users.iter().any(|u| u.ExternalId == external_id) {
+                user_org.delete(&conn)?;
             }
         }
     }
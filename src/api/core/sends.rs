@@ -1,24 +1,41 @@
-use std::{io::Read, path::Path};
+use std::{
+    io::Read,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
 
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, Local, Utc};
 use multipart::server::{save::SavedData, Multipart, SaveResult};
-use rocket::{http::ContentType, response::NamedFile, Data};
+use rocket::{http::ContentType, Data};
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
 use crate::{
-    api::{ApiResult, EmptyResult, JsonResult, JsonUpcase, Notify, UpdateType},
-    auth::{Headers, Host},
+    api::{ApiResult, EmptyResult, JsonResult, JsonUpcase, Notify, NumberOrString, UpdateType},
+    auth::{ClientIp, Headers, Host},
     db::{models::*, DbConn, DbPool},
+    mail, ratelimit,
+    storage::{get_storage, FileArea, StoredFile},
     CONFIG,
 };
 
+use super::ciphers::{append_upload_chunk, ChunkedUploadFinalizeData, FileUploadType};
+
 const SEND_INACCESSIBLE_MSG: &str = "Send does not exist or is no longer available";
 
 pub fn routes() -> Vec<rocket::Route> {
     routes![
+        get_sends,
+        get_send,
         post_send,
         post_send_file,
+        post_send_file_v2,
+        post_send_file_v2_data,
+        get_send_file_v2_renew,
+        get_send_file_chunked,
+        post_send_file_chunked,
+        put_send_file_chunk,
+        post_send_file_chunked_finalize,
         post_access,
         post_access_file,
         put_send,
@@ -31,15 +48,57 @@ pub fn routes() -> Vec<rocket::Route> {
 pub fn purge_sends(pool: DbPool) {
     debug!("Purging sends");
     if let Ok(conn) = pool.get() {
-        Send::purge(&conn);
+        let purged = Send::purge(&conn);
+        if purged > 0 {
+            info!("Purged {} sends past their deletion date", purged);
+        }
     } else {
         error!("Failed to get DB connection while purging sends")
     }
+
+    let purged = purge_stale_uploads();
+    if purged > 0 {
+        info!("Purged {} abandoned Send uploads", purged);
+    }
+}
+
+/// Chunked uploads which haven't received anything for this long are considered abandoned
+const STALE_UPLOAD_MIN_AGE: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
+/// Deletes the files of the chunked Send uploads which were never finalized, and returns how many were deleted
+fn purge_stale_uploads() -> usize {
+    let entries = match std::fs::read_dir(CONFIG.tmp_folder()) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    let mut purged = 0;
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with("send-") {
+            continue;
+        }
+
+        let modified = entry.metadata().and_then(|metadata| metadata.modified());
+        let is_stale = modified.map_or(false, |modified| {
+            modified.elapsed().map_or(false, |age| age >= STALE_UPLOAD_MIN_AGE)
+        });
+        if !is_stale {
+            continue;
+        }
+
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => purged += 1,
+            Err(e) => warn!("Failed to delete abandoned Send upload {}: {}", entry.path().display(), e),
+        }
+    }
+    purged
 }
 
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 pub struct SendData {
+    // Only present when the Send is sent back as part of an account key rotation
+    pub Id: Option<String>,
     pub Type: i32,
     pub Key: String,
     pub Password: Option<String>,
@@ -54,6 +113,8 @@ pub struct SendData {
     pub Notes: Option<String>,
     pub Text: Option<Value>,
     pub File: Option<Value>,
+    // Only present in the v2 file Sends, before the file is uploaded
+    pub FileLength: Option<NumberOrString>,
 }
 
 /// Enforces the `Disable Send` policy. A non-owner/admin user belonging to
@@ -80,6 +141,11 @@ fn enforce_disable_send_policy(headers: &Headers, conn: &DbConn) -> EmptyResult
 ///
 /// Ref: https://bitwarden.com/help/article/policies/#send-options
 fn enforce_disable_hide_email_policy(data: &SendData, headers: &Headers, conn: &DbConn) -> EmptyResult {
+    // The email is hidden on all the sends anyway, and the clients send the option back as it's shown to them
+    if CONFIG.sends_hide_email() {
+        return Ok(());
+    }
+
     let user_uuid = &headers.user.uuid;
     let hide_email = data.HideEmail.unwrap_or(false);
     if hide_email && OrgPolicy::is_hide_email_disabled(user_uuid, conn) {
@@ -91,6 +157,35 @@ fn enforce_disable_hide_email_policy(data: &SendData, headers: &Headers, conn: &
     Ok(())
 }
 
+/// Returns the maximum size in bytes of a new Send file of the user, based on the configured quota
+fn get_send_size_limit(user_uuid: &str, conn: &DbConn) -> ApiResult<i64> {
+    let max_file_size = CONFIG.send_max_file_size() * 1024;
+
+    match CONFIG.user_send_limit() {
+        Some(0) => err!("File Sends are disabled"),
+        Some(limit_kb) => {
+            let left = (limit_kb * 1024) - Send::size_by_user(user_uuid, conn);
+            if left <= 0 {
+                err!("Send size limit reached! Delete some files to open space")
+            }
+            Ok(left.min(max_file_size))
+        }
+        None => Ok(max_file_size),
+    }
+}
+
+/// Checks the deletion date of a Send against the maximum lifetime configured for the instance
+fn check_deletion_date(deletion_date: &DateTime<Utc>) -> EmptyResult {
+    let max_days = CONFIG.send_max_days();
+    if *deletion_date > Utc::now() + Duration::days(max_days) {
+        err!(format!(
+            "You cannot have a Send with a deletion date that far into the future. Adjust the Deletion Date to a value less than {} days from now and try again.",
+            max_days
+        ));
+    }
+    Ok(())
+}
+
 fn create_send(data: SendData, user_uuid: String) -> ApiResult<Send> {
     let data_val = if data.Type == SendType::Text as i32 {
         data.Text
@@ -107,11 +202,7 @@ fn create_send(data: SendData, user_uuid: String) -> ApiResult<Send> {
         err!("Send data not provided");
     };
 
-    if data.DeletionDate > Utc::now() + Duration::days(31) {
-        err!(
-            "You cannot have a Send with a deletion date that far into the future. Adjust the Deletion Date to a value less than 31 days from now and try again."
-        );
-    }
+    check_deletion_date(&data.DeletionDate)?;
 
     let mut send = Send::new(data.Type, data.Name, data_str, data.Key, data.DeletionDate.naive_utc());
     send.user_uuid = Some(user_uuid);
@@ -127,6 +218,32 @@ fn create_send(data: SendData, user_uuid: String) -> ApiResult<Send> {
     Ok(send)
 }
 
+#[get("/sends")]
+fn get_sends(headers: Headers, conn: DbConn) -> Json<Value> {
+    let sends = Send::find_by_user(&headers.user.uuid, &conn);
+    let sends_json: Vec<Value> = sends.iter().map(|s| s.to_json()).collect();
+
+    Json(json!({
+      "Data": sends_json,
+      "Object": "list",
+      "ContinuationToken": null,
+    }))
+}
+
+#[get("/sends/<uuid>")]
+fn get_send(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let send = match Send::find_by_uuid(&uuid, &conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    if send.user_uuid.as_ref() != Some(&headers.user.uuid) {
+        err!("Send is not owned by user")
+    }
+
+    Ok(Json(send.to_json()))
+}
+
 #[post("/sends", data = "<data>")]
 fn post_send(data: JsonUpcase<SendData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
     enforce_disable_send_policy(&headers, &conn)?;
@@ -165,20 +282,7 @@ fn post_send_file(data: Data, content_type: &ContentType, headers: Headers, conn
     let data = serde_json::from_str::<crate::util::UpCase<SendData>>(&buf)?;
     enforce_disable_hide_email_policy(&data.data, &headers, &conn)?;
 
-    // Get the file length and add an extra 10% to avoid issues
-    const SIZE_110_MB: u64 = 115_343_360;
-
-    let size_limit = match CONFIG.user_attachment_limit() {
-        Some(0) => err!("File uploads are disabled"),
-        Some(limit_kb) => {
-            let left = (limit_kb * 1024) - Attachment::size_by_user(&headers.user.uuid, &conn);
-            if left <= 0 {
-                err!("Attachment size limit reached! Delete some files to open space")
-            }
-            std::cmp::Ord::max(left as u64, SIZE_110_MB)
-        }
-        None => SIZE_110_MB,
-    };
+    let size_limit = get_send_size_limit(&headers.user.uuid, &conn)? as u64;
 
     // Create the Send
     let mut send = create_send(data.data, headers.user.uuid.clone())?;
@@ -188,7 +292,8 @@ fn post_send_file(data: Data, content_type: &ContentType, headers: Headers, conn
         err!("Send content is not a file");
     }
 
-    let file_path = Path::new(&CONFIG.sends_folder()).join(&send.uuid).join(&file_id);
+    let storage_path = format!("{}/{}", send.uuid, file_id);
+    let file_path = FileArea::Sends.local_path(&storage_path);
 
     // Read the data entry and save the file
     let mut data_entry = match mpart.read_entry()? {
@@ -218,10 +323,16 @@ fn post_send_file(data: Data, content_type: &ContentType, headers: Headers, conn
     if let Some(o) = data_value.as_object_mut() {
         o.insert(String::from("Id"), Value::String(file_id));
         o.insert(String::from("Size"), Value::Number(size.into()));
-        o.insert(String::from("SizeName"), Value::String(crate::util::get_display_size(size)));
+        o.insert(String::from("SizeName"), Value::String(crate::util::get_display_size(size as i64)));
     }
     send.data = serde_json::to_string(&data_value)?;
 
+    // The file is always written to the local disk first, and then moved to the configured storage
+    if let Err(e) = get_storage(FileArea::Sends).save(&storage_path, &file_path) {
+        std::fs::remove_file(&file_path).ok();
+        return Err(e);
+    }
+
     // Save the changes in the database
     send.save(&conn)?;
     nt.send_user_update(UpdateType::SyncSendCreate, &headers.user);
@@ -229,14 +340,337 @@ fn post_send_file(data: Data, content_type: &ContentType, headers: Headers, conn
     Ok(Json(send.to_json()))
 }
 
+/// v2 API for creating a file Send, the file is uploaded afterwards to the returned URL
+#[post("/sends/file/v2", data = "<data>")]
+fn post_send_file_v2(data: JsonUpcase<SendData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+    enforce_disable_send_policy(&headers, &conn)?;
+
+    let data: SendData = data.into_inner().data;
+    enforce_disable_hide_email_policy(&data, &headers, &conn)?;
+
+    if data.Type != SendType::File as i32 {
+        err!("Send content is not a file")
+    }
+
+    let file_length = match data.FileLength {
+        Some(ref length) => length.into_i32()?,
+        None => err!("Invalid Send length"),
+    };
+    if file_length < 0 {
+        err!("Send size can't be negative")
+    }
+    if file_length as i64 > get_send_size_limit(&headers.user.uuid, &conn)? {
+        err!("Send size limit exceeded with this file")
+    }
+
+    let mut send = create_send(data, headers.user.uuid.clone())?;
+    let file_id = crate::crypto::generate_send_id();
+
+    let mut data_value: Value = serde_json::from_str(&send.data)?;
+    if let Some(o) = data_value.as_object_mut() {
+        o.insert(String::from("Id"), Value::String(file_id.clone()));
+        o.insert(String::from("Size"), Value::Number(file_length.into()));
+        o.insert(String::from("SizeName"), Value::String(crate::util::get_display_size(file_length as i64)));
+    }
+    send.data = serde_json::to_string(&data_value)?;
+    send.save(&conn)?;
+    nt.send_user_update(UpdateType::SyncSendCreate, &headers.user);
+
+    Ok(Json(send_upload_json(&send, &file_id)))
+}
+
+fn send_upload_json(send: &Send, file_id: &str) -> Value {
+    json!({ // SendFileUploadDataResponseModel
+        "Object": "send-fileUpload",
+        "Url": format!("/sends/{}/file/{}", send.uuid, file_id),
+        "FileUploadType": FileUploadType::Direct as i32,
+        "SendResponse": send.to_json(),
+    })
+}
+
+/// Returns the file Send of the user and its announced file size, as long as the file hasn't been uploaded yet
+fn get_send_upload_target(send_uuid: &str, file_id: &str, headers: &Headers, conn: &DbConn) -> ApiResult<(Send, i64)> {
+    let send = match Send::find_by_uuid(send_uuid, conn) {
+        Some(send) => send,
+        None => err!("Send not found"),
+    };
+
+    if send.user_uuid.as_ref() != Some(&headers.user.uuid) {
+        err!("Send is not owned by user")
+    }
+
+    let data: Value = serde_json::from_str(&send.data)?;
+    if send.atype != SendType::File as i32 || data["Id"].as_str() != Some(file_id) {
+        err!("Send file doesn't exist")
+    }
+
+    if get_storage(FileArea::Sends).exists(&format!("{}/{}", send_uuid, file_id)) {
+        err!("Send file has already been uploaded")
+    }
+
+    Ok((send, data["Size"].as_i64().unwrap_or_default()))
+}
+
+/// v2 API for getting the upload data of a file Send again, used by the clients to retry a failed upload
+#[get("/sends/<send_uuid>/file/<file_id>")]
+fn get_send_file_v2_renew(send_uuid: String, file_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let (send, _) = get_send_upload_target(&send_uuid, &file_id, &headers, &conn)?;
+    Ok(Json(send_upload_json(&send, &file_id)))
+}
+
+/// v2 API for uploading the file of a file Send
+#[post("/sends/<send_uuid>/file/<file_id>", format = "multipart/form-data", data = "<data>")]
+fn post_send_file_v2_data(
+    send_uuid: String,
+    file_id: String,
+    data: Data,
+    content_type: &ContentType,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
+    enforce_disable_send_policy(&headers, &conn)?;
+
+    let (send, announced_size) = match get_send_upload_target(&send_uuid, &file_id, &headers, &conn) {
+        Ok(target) => target,
+        Err(e) => {
+            // Read the whole body, so the client receives the error instead of a broken connection
+            std::io::copy(&mut data.open(), &mut std::io::sink()).ok();
+            return Err(e);
+        }
+    };
+
+    let boundary = content_type.params().next().expect("No boundary provided").1;
+    let mut mpart = Multipart::with_body(data.open(), boundary);
+
+    let mut data_entry = match mpart.read_entry()? {
+        Some(e) if &*e.headers.name == "data" => e,
+        Some(_) => err!("Invalid entry name"),
+        None => err!("No data entry present"),
+    };
+
+    // The file has to match the size announced when the Send was created
+    let storage_path = format!("{}/{}", send_uuid, file_id);
+    let file_path = FileArea::Sends.local_path(&storage_path);
+    let size_limit = announced_size as u64;
+
+    let size = match data_entry.data.save().memory_threshold(0).size_limit(size_limit).with_path(&file_path) {
+        SaveResult::Full(SavedData::File(_, size)) => size as i64,
+        SaveResult::Full(other) => {
+            std::fs::remove_file(&file_path).ok();
+            err!(format!("Send file is not a file: {:?}", other));
+        }
+        SaveResult::Partial(_, reason) => {
+            std::fs::remove_file(&file_path).ok();
+            err!(format!("Send size limit exceeded with this file: {:?}", reason));
+        }
+        SaveResult::Error(e) => {
+            std::fs::remove_file(&file_path).ok();
+            err!(format!("Error: {:?}", e));
+        }
+    };
+
+    if size != announced_size {
+        std::fs::remove_file(&file_path).ok();
+        err!("Send file size doesn't match the announced size")
+    }
+
+    // The file is always written to the local disk first, and then moved to the configured storage
+    if let Err(e) = get_storage(FileArea::Sends).save(&storage_path, &file_path) {
+        std::fs::remove_file(&file_path).ok();
+        return Err(e);
+    }
+
+    send.update_users_revision(&conn);
+    nt.send_user_update(UpdateType::SyncSendUpdate, &headers.user);
+
+    Ok(())
+}
+
+/// Path on the local disk where the file of a Send is written while it's uploaded in chunks
+fn get_send_upload_path(send_uuid: &str, file_id: &str) -> PathBuf {
+    Path::new(&CONFIG.tmp_folder()).join(format!("send-{}-{}", send_uuid, file_id))
+}
+
+fn send_chunked_upload_json(send: &Send, file_id: &str, offset: u64, file_size: i64) -> Value {
+    json!({
+        "Object": "send-chunkedUpload",
+        "SendId": send.uuid,
+        "FileId": file_id,
+        "Offset": offset,
+        "FileSize": file_size,
+    })
+}
+
+/// Returns how much of a chunked upload has been received, so the client can resume it after a failure.
+#[get("/sends/<send_uuid>/file/<file_id>/chunked")]
+fn get_send_file_chunked(send_uuid: String, file_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let (send, announced_size) = get_send_upload_target(&send_uuid, &file_id, &headers, &conn)?;
+
+    match std::fs::metadata(get_send_upload_path(&send_uuid, &file_id)) {
+        Ok(metadata) => Ok(Json(send_chunked_upload_json(&send, &file_id, metadata.len(), announced_size))),
+        Err(_) => err!("The upload hasn't been started"),
+    }
+}
+
+/// Starts (or restarts from the beginning) the chunked upload of the file of a Send created with the v2 API,
+/// so large files don't need to be sent in a single request.
+#[post("/sends/<send_uuid>/file/<file_id>/chunked")]
+fn post_send_file_chunked(send_uuid: String, file_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    enforce_disable_send_policy(&headers, &conn)?;
+    let (send, announced_size) = get_send_upload_target(&send_uuid, &file_id, &headers, &conn)?;
+
+    std::fs::create_dir_all(CONFIG.tmp_folder())?;
+    std::fs::File::create(get_send_upload_path(&send_uuid, &file_id))?;
+
+    Ok(Json(send_chunked_upload_json(&send, &file_id, 0, announced_size)))
+}
+
+/// Appends a chunk to a chunked upload. The offset must be the size received so far,
+/// otherwise the chunk is rejected and the client has to check the current offset.
+#[put("/sends/<send_uuid>/file/<file_id>/chunked?<offset>", data = "<data>")]
+fn put_send_file_chunk(
+    send_uuid: String,
+    file_id: String,
+    offset: u64,
+    data: Data,
+    headers: Headers,
+    conn: DbConn,
+) -> JsonResult {
+    let (send, announced_size) = match get_send_upload_target(&send_uuid, &file_id, &headers, &conn) {
+        Ok(target) => target,
+        Err(e) => err_discard!(e.message(), data),
+    };
+
+    let path = get_send_upload_path(&send_uuid, &file_id);
+    let received = append_upload_chunk(&path, offset, announced_size.max(0) as u64, data, "Send file")?;
+
+    Ok(Json(send_chunked_upload_json(&send, &file_id, received, announced_size)))
+}
+
+/// Completes a chunked upload once all the chunks have been received, the file is checked
+/// against the announced size and the hash provided by the client and then moved to the sends storage.
+#[post("/sends/<send_uuid>/file/<file_id>/chunked/finalize", data = "<data>")]
+fn post_send_file_chunked_finalize(
+    send_uuid: String,
+    file_id: String,
+    data: JsonUpcase<ChunkedUploadFinalizeData>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: ChunkedUploadFinalizeData = data.into_inner().data;
+    let (send, announced_size) = get_send_upload_target(&send_uuid, &file_id, &headers, &conn)?;
+
+    let path = get_send_upload_path(&send_uuid, &file_id);
+    let received = match std::fs::metadata(&path) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => err!("The upload hasn't been started"),
+    };
+
+    if received != announced_size.max(0) as u64 {
+        err!(format!("The upload is incomplete, {} of {} bytes have been received", received, announced_size))
+    }
+
+    let hash = match crate::crypto::sha256_file(&path) {
+        Ok(hash) => hash,
+        Err(e) => {
+            std::fs::remove_file(&path).ok();
+            return Err(e.into());
+        }
+    };
+    if !crate::crypto::ct_eq(&hash, data.Hash.to_lowercase()) {
+        // The content is corrupted, the client needs to start the upload again
+        std::fs::remove_file(&path).ok();
+        err!("The hash of the uploaded data doesn't match")
+    }
+
+    if let Err(e) = get_storage(FileArea::Sends).save(&format!("{}/{}", send_uuid, file_id), &path) {
+        std::fs::remove_file(&path).ok();
+        return Err(e);
+    }
+
+    send.update_users_revision(&conn);
+    nt.send_user_update(UpdateType::SyncSendUpdate, &headers.user);
+
+    Ok(Json(send.to_json()))
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 pub struct SendAccessData {
     pub Password: Option<String>,
 }
 
+/// Checks the password of a protected Send. Every wrong password is counted, and once the configured
+/// number of attempts is reached the Send is disabled until its owner enables it again.
+fn check_send_password(send: &mut Send, password: Option<String>, conn: &DbConn) -> EmptyResult {
+    if send.password_hash.is_none() {
+        return Ok(());
+    }
+
+    match password {
+        Some(ref p) if send.check_password(p) => {
+            if send.password_failures > 0 {
+                send.reset_password_failures(conn)?;
+            }
+            Ok(())
+        }
+        Some(_) => {
+            send.add_password_failure(CONFIG.send_password_max_attempts(), conn)?;
+            if send.disabled {
+                warn!("Send {} disabled after {} failed password attempts", send.uuid, send.password_failures);
+            }
+            err!("Invalid password.")
+        }
+        None => err_code!("Password not provided", 401),
+    }
+}
+
+/// Returns the network of an IP address, a /24 for IPv4 and a /48 for IPv6, so the recipients aren't fully identified
+fn coarse_ip(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.0/24", o[0], o[1], o[2])
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+        }
+    }
+}
+
+/// Notifies the creator of the send the first time it's accessed, when enabled
+fn notify_first_access(send: &Send, ip: &ClientIp, conn: &DbConn, nt: &Notify) {
+    if !CONFIG.send_access_notification() || send.access_count != 1 {
+        return;
+    }
+
+    let user = match send.user_uuid.as_ref().and_then(|uuid| User::find_by_uuid(uuid, conn)) {
+        Some(user) => user,
+        None => return,
+    };
+
+    nt.send_user_update(UpdateType::SyncSendUpdate, &user);
+
+    if CONFIG.mail_enabled() {
+        if let Err(e) = mail::send_send_accessed(&user.email, &coarse_ip(&ip.ip), &Local::now()) {
+            error!("Error sending the Send access notification email: {:#?}", e);
+        }
+    }
+}
+
 #[post("/sends/access/<access_id>", data = "<data>")]
-fn post_access(access_id: String, data: JsonUpcase<SendAccessData>, conn: DbConn) -> JsonResult {
+fn post_access(
+    access_id: String,
+    data: JsonUpcase<SendAccessData>,
+    ip: ClientIp,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    ratelimit::check_limit_send_access(&ip.ip)?;
+
     let mut send = match Send::find_by_access_id(&access_id, &conn) {
         Some(s) => s,
         None => err_code!(SEND_INACCESSIBLE_MSG, 404),
@@ -262,21 +696,16 @@ fn post_access(access_id: String, data: JsonUpcase<SendAccessData>, conn: DbConn
         err_code!(SEND_INACCESSIBLE_MSG, 404)
     }
 
-    if send.password_hash.is_some() {
-        match data.into_inner().data.Password {
-            Some(ref p) if send.check_password(p) => { /* Nothing to do here */ }
-            Some(_) => err!("Invalid password."),
-            None => err_code!("Password not provided", 401),
-        }
-    }
+    check_send_password(&mut send, data.into_inner().data.Password, &conn)?;
 
     // Files are incremented during the download
     if send.atype == SendType::Text as i32 {
-        send.access_count += 1;
+        if !send.increment_access_count(&conn)? {
+            err_code!(SEND_INACCESSIBLE_MSG, 404)
+        }
+        notify_first_access(&send, &ip, &conn, &nt);
     }
 
-    send.save(&conn)?;
-
     Ok(Json(send.to_json_access(&conn)))
 }
 
@@ -286,8 +715,12 @@ fn post_access_file(
     file_id: String,
     data: JsonUpcase<SendAccessData>,
     host: Host,
+    ip: ClientIp,
     conn: DbConn,
+    nt: Notify,
 ) -> JsonResult {
+    ratelimit::check_limit_send_access(&ip.ip)?;
+
     let mut send = match Send::find_by_uuid(&send_id, &conn) {
         Some(s) => s,
         None => err_code!(SEND_INACCESSIBLE_MSG, 404),
@@ -313,17 +746,12 @@ fn post_access_file(
         err_code!(SEND_INACCESSIBLE_MSG, 404)
     }
 
-    if send.password_hash.is_some() {
-        match data.into_inner().data.Password {
-            Some(ref p) if send.check_password(p) => { /* Nothing to do here */ }
-            Some(_) => err!("Invalid password."),
-            None => err_code!("Password not provided", 401),
-        }
-    }
+    check_send_password(&mut send, data.into_inner().data.Password, &conn)?;
 
-    send.access_count += 1;
-
-    send.save(&conn)?;
+    if !send.increment_access_count(&conn)? {
+        err_code!(SEND_INACCESSIBLE_MSG, 404)
+    }
+    notify_first_access(&send, &ip, &conn, &nt);
 
     let token_claims = crate::auth::generate_send_claims(&send_id, &file_id);
     let token = crate::auth::encode_jwt(&token_claims);
@@ -335,31 +763,21 @@ fn post_access_file(
 }
 
 #[get("/sends/<send_id>/<file_id>?<t>")]
-fn download_send(send_id: String, file_id: String, t: String) -> Option<NamedFile> {
+fn download_send(send_id: String, file_id: String, t: String, conn: DbConn) -> Option<StoredFile> {
     if let Ok(claims) = crate::auth::decode_send(&t) {
         if claims.sub == format!("{}/{}", send_id, file_id) {
-            return NamedFile::open(Path::new(&CONFIG.sends_folder()).join(send_id).join(file_id)).ok();
+            // The Send could have been disabled or deleted since the download token was generated
+            match Send::find_by_uuid(&send_id, &conn) {
+                Some(send) if !send.disabled => return get_storage(FileArea::Sends).download(&claims.sub),
+                _ => return None,
+            }
         }
     }
     None
 }
 
-#[put("/sends/<id>", data = "<data>")]
-fn put_send(id: String, data: JsonUpcase<SendData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
-    enforce_disable_send_policy(&headers, &conn)?;
-
-    let data: SendData = data.into_inner().data;
-    enforce_disable_hide_email_policy(&data, &headers, &conn)?;
-
-    let mut send = match Send::find_by_uuid(&id, &conn) {
-        Some(s) => s,
-        None => err!("Send not found"),
-    };
-
-    if send.user_uuid.as_ref() != Some(&headers.user.uuid) {
-        err!("Send is not owned by user")
-    }
-
+/// Updates an existing Send with the data sent by the client, the type and file of a Send can't change.
+pub fn update_send_from_data(send: &mut Send, data: SendData) -> EmptyResult {
     if send.atype != data.Type {
         err!("Sends can't change type")
     }
@@ -376,11 +794,7 @@ fn put_send(id: String, data: JsonUpcase<SendData>, headers: Headers, conn: DbCo
         send.data = data_str;
     }
 
-    if data.DeletionDate > Utc::now() + Duration::days(31) {
-        err!(
-            "You cannot have a Send with a deletion date that far into the future. Adjust the Deletion Date to a value less than 31 days from now and try again."
-        );
-    }
+    check_deletion_date(&data.DeletionDate)?;
     send.name = data.Name;
     send.akey = data.Key;
     send.deletion_date = data.DeletionDate.naive_utc();
@@ -388,6 +802,11 @@ fn put_send(id: String, data: JsonUpcase<SendData>, headers: Headers, conn: DbCo
     send.max_access_count = data.MaxAccessCount;
     send.expiration_date = data.ExpirationDate.map(|d| d.naive_utc());
     send.hide_email = data.HideEmail;
+
+    // Enabling the Send again also clears the failed password attempts which may have disabled it
+    if send.disabled && !data.Disabled {
+        send.password_failures = 0;
+    }
     send.disabled = data.Disabled;
 
     // Only change the value if it's present
@@ -395,6 +814,27 @@ fn put_send(id: String, data: JsonUpcase<SendData>, headers: Headers, conn: DbCo
         send.set_password(Some(&password));
     }
 
+    Ok(())
+}
+
+#[put("/sends/<id>", data = "<data>")]
+fn put_send(id: String, data: JsonUpcase<SendData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+    enforce_disable_send_policy(&headers, &conn)?;
+
+    let data: SendData = data.into_inner().data;
+    enforce_disable_hide_email_policy(&data, &headers, &conn)?;
+
+    let mut send = match Send::find_by_uuid(&id, &conn) {
+        Some(s) => s,
+        None => err!("Send not found"),
+    };
+
+    if send.user_uuid.as_ref() != Some(&headers.user.uuid) {
+        err!("Send is not owned by user")
+    }
+
+    update_send_from_data(&mut send, data)?;
+
     send.save(&conn)?;
     nt.send_user_update(UpdateType::SyncSendUpdate, &headers.user);
 
@@ -56,6 +56,12 @@ fn _refresh_login(data: ConnectData, conn: DbConn) -> JsonResult {
 
     // COMMON
     let user = User::find_by_uuid(&device.user_uuid, &conn).unwrap();
+
+    // A disabled user can't get new access tokens, their data is kept until they're enabled again
+    if !user.enabled {
+        err!("This user has been disabled")
+    }
+
     let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
 
     let (access_token, expires_in) = device.refresh_tokens(&user, orgs);
@@ -72,6 +78,7 @@ fn _refresh_login(data: ConnectData, conn: DbConn) -> JsonResult {
         "Kdf": user.client_kdf_type,
         "KdfIterations": user.client_kdf_iter,
         "ResetMasterPassword": false, // TODO: according to official server seems something like: user.password_hash.is_empty(), but would need testing
+        "ForcePasswordReset": user.force_password_reset,
         "scope": "api offline_access",
         "unofficialServer": true,
     })))
@@ -136,7 +143,9 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
 
     let twofactor_token = twofactor_auth(&user.uuid, &data, &mut device, ip, &conn)?;
 
-    if CONFIG.mail_enabled() && new_device {
+    let new_ip = LoginIp::record(&user.uuid, &util::ip_network_of(&ip.ip), &conn);
+
+    if CONFIG.mail_enabled() && new_device && CONFIG.new_device_email() {
         if let Err(e) = mail::send_new_device_logged_in(&user.email, &ip.ip.to_string(), &now, &device.name) {
             error!("Error sending new device email: {:#?}", e);
 
@@ -144,6 +153,10 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
                 err!("Could not send login notification email. Please contact your administrator.")
             }
         }
+    } else if CONFIG.mail_enabled() && new_ip && CONFIG.new_ip_email() {
+        if let Err(e) = mail::send_new_ip_logged_in(&user.email, &ip.ip.to_string(), &now, &device.name) {
+            error!("Error sending new IP email: {:#?}", e);
+        }
     }
 
     // Common
@@ -164,6 +177,7 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
         "Kdf": user.client_kdf_type,
         "KdfIterations": user.client_kdf_iter,
         "ResetMasterPassword": false,// TODO: Same as above
+        "ForcePasswordReset": user.force_password_reset,
         "scope": "api offline_access",
         "unofficialServer": true,
     });
@@ -172,6 +186,7 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
         result["TwoFactorToken"] = Value::String(token);
     }
 
+    crate::stats::incr("logins");
     info!("User {} logged in successfully. IP: {}", username, ip.ip);
     Ok(Json(result))
 }
@@ -1,18 +1,31 @@
 use std::path::{Path, PathBuf};
 
-use rocket::{http::ContentType, response::content::Content, response::NamedFile, Route};
+use rocket::{
+    http::{ContentType, Status},
+    response::content::{Content, Html},
+    response::status::Custom,
+    response::NamedFile,
+    Route,
+};
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
-use crate::{error::Error, util::Cached, CONFIG};
+use crate::{
+    api::EmptyResult,
+    db::{models::Attachment, DbConn},
+    error::Error,
+    storage::{get_storage, FileArea, StoredFile},
+    util::Cached,
+    CONFIG,
+};
 
 pub fn routes() -> Vec<Route> {
     // If addding more routes here, consider also adding them to
     // crate::utils::LOGGED_ROUTES to make sure they appear in the log
     if CONFIG.web_vault_enabled() {
-        routes![web_index, app_id, web_files, attachments, alive, static_files]
+        routes![web_index, app_id, web_files, attachments, alive, static_files, maintenance_json, maintenance_html]
     } else {
-        routes![attachments, alive, static_files]
+        routes![attachments, alive, static_files, maintenance_json, maintenance_html]
     }
 }
 
@@ -56,8 +69,23 @@ fn web_files(p: PathBuf) -> Cached<Option<NamedFile>> {
 }
 
 #[get("/attachments/<uuid>/<file_id>")]
-fn attachments(uuid: String, file_id: String) -> Option<NamedFile> {
-    NamedFile::open(Path::new(&CONFIG.attachments_folder()).join(uuid).join(file_id)).ok()
+fn attachments(uuid: String, file_id: String, conn: DbConn) -> Result<StoredFile, Status> {
+    let attachment = match Attachment::find_by_id(&file_id, &conn) {
+        Some(attachment) if attachment.cipher_uuid == uuid => attachment,
+        _ => return Err(Status::NotFound),
+    };
+
+    let mut file = get_storage(FileArea::Attachments).download(&attachment.get_storage_path()).ok_or(Status::NotFound)?;
+
+    // Files in the object storage are served by the storage itself and can't be checked here
+    if let StoredFile::Local(ref mut local_file) = file {
+        if !local_file.matches(attachment.file_size.max(0) as u64, attachment.file_hash.clone()) {
+            error!("The file of attachment {} doesn't match its size, it may be corrupted", attachment.id);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    Ok(file)
 }
 
 #[get("/alive")]
@@ -68,6 +96,37 @@ fn alive() -> Json<String> {
     Json(format_date(&Utc::now().naive_utc()))
 }
 
+// The requests blocked by the maintenance mode are redirected here by util::MaintenanceMode
+#[get("/bwrs_maintenance/json")]
+fn maintenance_json() -> EmptyResult {
+    let msg = CONFIG.maintenance_message();
+    Err(Error::new(msg.clone(), msg).with_code(Status::ServiceUnavailable.code))
+}
+
+#[get("/bwrs_maintenance/html")]
+fn maintenance_html() -> Custom<Html<String>> {
+    let page = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8">
+    <title>Maintenance</title>
+    <link rel="stylesheet" href="{urlpath}/bwrs_static/bootstrap.css" />
+</head>
+<body class="bg-light">
+    <main class="container my-5 p-3 bg-white rounded shadow text-center">
+        <img src="{urlpath}/bwrs_static/logo-gray.png" alt="logo" />
+        <h4 class="mt-3">Maintenance</h4>
+        <p class="mb-0">{message}</p>
+    </main>
+</body>
+</html>"#,
+        urlpath = CONFIG.domain_path(),
+        message = handlebars::html_escape(&CONFIG.maintenance_message()),
+    );
+    Custom(Status::ServiceUnavailable, Html(page))
+}
+
 #[get("/bwrs_static/<filename>")]
 fn static_files(filename: String) -> Result<Content<&'static [u8]>, Error> {
     match filename.as_ref() {
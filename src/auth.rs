@@ -4,6 +4,7 @@
 use chrono::{Duration, Utc};
 use num_traits::FromPrimitive;
 use once_cell::sync::Lazy;
+use std::{collections::HashMap, sync::Mutex};
 
 use jsonwebtoken::{self, Algorithm, DecodingKey, EncodingKey, Header};
 use serde::de::DeserializeOwned;
@@ -26,6 +27,7 @@ static JWT_DELETE_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|delete", CONFI
 static JWT_VERIFYEMAIL_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|verifyemail", CONFIG.domain_origin()));
 static JWT_ADMIN_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|admin", CONFIG.domain_origin()));
 static JWT_SEND_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|send", CONFIG.domain_origin()));
+static JWT_ADMIN_SSO_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|adminsso", CONFIG.domain_origin()));
 
 static PRIVATE_RSA_KEY_VEC: Lazy<Vec<u8>> = Lazy::new(|| {
     read_file(&CONFIG.private_rsa_key()).unwrap_or_else(|e| panic!("Error loading private RSA Key.\n{}", e))
@@ -83,14 +85,22 @@ pub fn decode_verify_email(token: &str) -> Result<BasicJwtClaims, Error> {
     decode_jwt(token, JWT_VERIFYEMAIL_ISSUER.to_string())
 }
 
-pub fn decode_admin(token: &str) -> Result<BasicJwtClaims, Error> {
-    decode_jwt(token, JWT_ADMIN_ISSUER.to_string())
+pub fn decode_admin(token: &str) -> Result<AdminJwtClaims, Error> {
+    let claims: AdminJwtClaims = decode_jwt(token, JWT_ADMIN_ISSUER.to_string())?;
+    if is_admin_session_revoked(&claims.jti) {
+        err!("The admin session has been closed")
+    }
+    Ok(claims)
 }
 
 pub fn decode_send(token: &str) -> Result<BasicJwtClaims, Error> {
     decode_jwt(token, JWT_SEND_ISSUER.to_string())
 }
 
+pub fn decode_admin_sso(token: &str) -> Result<AdminSsoJwtClaims, Error> {
+    decode_jwt(token, JWT_ADMIN_SSO_ISSUER.to_string())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginJwtClaims {
     // Not before
@@ -191,13 +201,82 @@ pub fn generate_verify_email_claims(uuid: String) -> BasicJwtClaims {
     }
 }
 
-pub fn generate_admin_claims() -> BasicJwtClaims {
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminJwtClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject
+    pub sub: String,
+    // Id of the session, kept when the token is renewed so the logout closes the whole session
+    pub jti: String,
+}
+
+/// Generates the claims of a new admin session, or renews those of an existing one when `session_id` is given
+pub fn generate_admin_claims(session_id: Option<String>) -> AdminJwtClaims {
     let time_now = Utc::now().naive_utc();
-    BasicJwtClaims {
+    AdminJwtClaims {
         nbf: time_now.timestamp(),
-        exp: (time_now + Duration::minutes(20)).timestamp(),
+        exp: (time_now + Duration::minutes(CONFIG.admin_session_lifetime())).timestamp(),
         iss: JWT_ADMIN_ISSUER.to_string(),
         sub: "admin_panel".to_string(),
+        jti: session_id.unwrap_or_else(crate::util::get_uuid),
+    }
+}
+
+impl AdminJwtClaims {
+    /// The token is renewed once half of its lifetime has passed, so the session only expires after being idle
+    pub fn needs_renewal(&self) -> bool {
+        let half_lifetime = Duration::minutes(CONFIG.admin_session_lifetime()).num_seconds() / 2;
+        self.exp - Utc::now().naive_utc().timestamp() < half_lifetime
+    }
+}
+
+/// Sessions closed with the logout, with the time after which none of their tokens can be valid anymore.
+/// The list is only kept in memory, as it's only needed for the lifetime of a session. It's lost on restart,
+/// so a token copied from a closed session is accepted again until it expires, within `ADMIN_SESSION_LIFETIME`.
+static REVOKED_ADMIN_SESSIONS: Lazy<Mutex<HashMap<String, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Revokes the session of the token with the given expiration date, `exp`
+pub fn revoke_admin_session(session_id: &str, exp: i64) {
+    let now = Utc::now().naive_utc();
+    // Any token of the session expires before this, even if it was renewed just before the logout.
+    // The expiration of the current token is kept if it's later, in case the session lifetime was reduced since.
+    let revoked_until = (now + Duration::minutes(CONFIG.admin_session_lifetime())).timestamp().max(exp);
+
+    let mut revoked = REVOKED_ADMIN_SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    revoked.retain(|_, until| *until > now.timestamp());
+    revoked.insert(session_id.to_string(), revoked_until);
+}
+
+fn is_admin_session_revoked(session_id: &str) -> bool {
+    REVOKED_ADMIN_SESSIONS.lock().unwrap_or_else(|e| e.into_inner()).contains_key(session_id)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminSsoJwtClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Random values sent to the SSO provider, to check that its response belongs to this login
+    pub state: String,
+    pub nonce: String,
+}
+
+pub fn generate_admin_sso_claims(state: String, nonce: String) -> AdminSsoJwtClaims {
+    let time_now = Utc::now().naive_utc();
+    AdminSsoJwtClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::minutes(10)).timestamp(),
+        iss: JWT_ADMIN_SSO_ISSUER.to_string(),
+        state,
+        nonce,
     }
 }
 
@@ -234,8 +313,10 @@ impl<'a, 'r> FromRequest<'a, 'r> for Host {
         // Get host
         let host = if CONFIG.domain_set() {
             CONFIG.domain()
-        } else if let Some(referer) = headers.get_one("Referer") {
-            referer.to_string()
+        } else if let Some(referer) = headers.get_one("Referer").and_then(|r| url::Url::parse(r).ok()) {
+            // The referer contains the path of the page doing the request, like the anonymous Send page of the
+            // web vault, and only its origin can be used as the base of the generated urls
+            referer.origin().ascii_serialization()
         } else {
             // Try to guess from the headers
             use std::env;
@@ -316,6 +397,10 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
             None => err_handler!("Device has no user associated"),
         };
 
+        if !user.enabled {
+            err_handler!("This user has been disabled")
+        }
+
         if user.security_stamp != claims.sstamp {
             if let Some(stamp_exception) =
                 user.stamp_exception.as_deref().and_then(|s| serde_json::from_str::<UserStampException>(s).ok())
@@ -551,6 +636,7 @@ pub struct ManagerHeadersLoose {
     pub host: String,
     pub device: Device,
     pub user: User,
+    pub org_user: UserOrganization,
     pub org_user_type: UserOrgType,
 }
 
@@ -567,6 +653,7 @@ impl<'a, 'r> FromRequest<'a, 'r> for ManagerHeadersLoose {
                         host: headers.host,
                         device: headers.device,
                         user: headers.user,
+                        org_user: headers.org_user,
                         org_user_type: headers.org_user_type,
                     })
                 } else {
@@ -615,6 +702,16 @@ impl<'a, 'r> FromRequest<'a, 'r> for OwnerHeaders {
     }
 }
 
+impl From<OwnerHeaders> for Headers {
+    fn from(h: OwnerHeaders) -> Headers {
+        Headers {
+            host: h.host,
+            device: h.device,
+            user: h.user,
+        }
+    }
+}
+
 //
 // Client IP address detection
 //
@@ -649,3 +746,30 @@ impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
         })
     }
 }
+
+//
+// Conditional update header
+//
+use chrono::{DateTime, NaiveDateTime};
+
+/// The date sent by the client in the `If-Unmodified-Since` header, if any.
+pub struct IfUnmodifiedSince {
+    pub date: Option<NaiveDateTime>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfUnmodifiedSince {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let date = req.headers().get_one("If-Unmodified-Since").and_then(|date| {
+            DateTime::parse_from_rfc2822(date)
+                .map(|date| date.naive_utc())
+                .map_err(|_| warn!("'If-Unmodified-Since' header is malformed: {}", date))
+                .ok()
+        });
+
+        Outcome::Success(IfUnmodifiedSince {
+            date,
+        })
+    }
+}
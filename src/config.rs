@@ -33,11 +33,24 @@ const PRIVACY_CONFIG: &[&str] = &[
     "helo_name",
     "org_creation_users",
     "signups_domains_whitelist",
+    "s3_access_key_id",
+    "s3_endpoint",
     "smtp_from",
     "smtp_host",
+    "smtp_oauth2_client_id",
+    "smtp_oauth2_tenant",
     "smtp_username",
 ];
 
+/// Settings which aren't of the `Pass` type, but can still contain credentials,
+/// so they are never included in exported configuration profiles.
+const PROFILE_SECRETS: &[&str] = &["database_url"];
+
+/// Placeholder used in configuration profiles instead of the value of a secret.
+fn secret_reference(name: &str) -> String {
+    format!("${{{}}}", name.to_uppercase())
+}
+
 pub type Pass = String;
 
 macro_rules! make_config {
@@ -133,6 +146,18 @@ macro_rules! make_config {
                 builder
             }
 
+            /// Returns a copy of this builder where the values that can't be edited from the admin panel
+            /// are replaced by the ones of `current`, so they can only be changed in the environment
+            fn keep_non_editable(&self, current: &Self) -> Self {
+                let mut builder = self.clone();
+                $($(
+                    if !$editable {
+                        builder.$name = current.$name.clone();
+                    }
+                )+)+
+                builder
+            }
+
             /// Returns a new builder with all the elements from self,
             /// except those that are equal in both sides
             fn _remove(&self, other: &Self) -> Self {
@@ -223,6 +248,25 @@ macro_rules! make_config {
                 )+)+ })
             }
 
+            /// Exports the explicitly configured values (from the environment and the config file) as a
+            /// configuration profile. Secrets are not included, they are replaced by a reference to their name.
+            pub fn export_profile(&self) -> serde_json::Value {
+                let builder = {
+                    let inner = &self.inner.read().unwrap();
+                    let mut _overrides = Vec::new();
+                    inner._env.merge(&inner._usr, false, &mut _overrides)
+                };
+
+                let mut profile = serde_json::Map::new();
+                $($(
+                    if let Some(ref value) = builder.$name {
+                        profile.insert(stringify!($name).to_string(), make_config!{ @profile $name, value, $ty });
+                    }
+                )+)+
+
+                serde_json::Value::Object(profile)
+            }
+
             pub fn get_overrides(&self) -> Vec<String> {
                 let overrides = {
                     let inner = &self.inner.read().unwrap();
@@ -252,6 +296,16 @@ macro_rules! make_config {
          }
     };
 
+    // Profile export, secrets are replaced by a reference to their name
+    ( @profile $name:ident, $value:expr, Pass ) => {{ let _ = $value; json!(secret_reference(stringify!($name))) }};
+    ( @profile $name:ident, $value:expr, $ty:ty ) => {
+        if PROFILE_SECRETS.contains(&stringify!($name)) {
+            json!(secret_reference(stringify!($name)))
+        } else {
+            json!($value)
+        }
+    };
+
     // Group or empty string
     ( @show ) => { "" };
     ( @show $lit:literal ) => { $lit };
@@ -308,6 +362,10 @@ make_config! {
         attachments_folder:     String, false,  auto,   |c| format!("{}/{}", c.data_folder, "attachments");
         /// Sends folder
         sends_folder:           String, false,  auto,   |c| format!("{}/{}", c.data_folder, "sends");
+        /// Temporary files folder |> Used for the attachments uploaded in chunks until they are complete
+        tmp_folder:             String, false,  auto,   |c| format!("{}/{}", c.data_folder, "tmp");
+        /// Backups folder |> Where the database backups created from the admin panel are written
+        backup_folder:          String, false,  auto,   |c| format!("{}/{}", c.data_folder, "backups");
         /// Templates folder
         templates_folder:       String, false,  auto,   |c| format!("{}/{}", c.data_folder, "templates");
         /// Session JWT key
@@ -333,6 +391,15 @@ make_config! {
         /// Trash purge schedule |> Cron schedule of the job that checks for trashed items to delete permanently.
         /// Defaults to daily. Set blank to disable this job.
         trash_purge_schedule:   String, false,  def,    "0 5 0 * * *".to_string();
+        /// Orphaned attachments purge schedule |> Cron schedule of the job that removes the attachment files left
+        /// behind by deleted items. Defaults to weekly. Set blank to disable this job.
+        orphaned_attachments_purge_schedule: String, false, def, "0 25 3 * * Sun".to_string();
+        /// Stats flush schedule |> Cron schedule of the job that saves the usage stats shown in the admin panel,
+        /// like the number of requests and logins. Defaults to every 5 minutes. Set blank to disable this job.
+        stats_flush_schedule:   String, false,  def,    "0 */5 * * * *".to_string();
+        /// Mail queue schedule |> Cron schedule of the job that retries sending the queued emails.
+        /// Defaults to every minute. Set blank to disable this job.
+        mail_queue_schedule:    String, false,  def,    "30 * * * * *".to_string();
     },
 
     /// General settings
@@ -352,20 +419,50 @@ make_config! {
         /// Allow Sends |> Controls whether users are allowed to create Bitwarden Sends.
         /// This setting applies globally to all users. To control this on a per-org basis instead, use the "Disable Send" org policy.
         sends_allowed:          bool,   true,   def,    true;
+        /// Hide Send emails |> Hides the email of the creator from the recipients of all the Sends,
+        /// regardless of the option chosen by the creator.
+        sends_hide_email:       bool,   true,   def,    false;
+        /// Send access notifications |> Notify the creator of a Send the first time it's accessed by a recipient,
+        /// by email when it's enabled, with the date and the network of the recipient's IP address
+        send_access_notification: bool, true,   def,    false;
+        /// Send access rate limit seconds |> Average number of seconds between the anonymous accesses to the Sends
+        /// from the same IP address. Set to 0 to disable the rate limit.
+        send_access_ratelimit_seconds: u64, true, def, 10;
+        /// Send access rate limit burst |> Number of anonymous accesses to the Sends allowed in a row from the same IP address
+        send_access_ratelimit_max_burst: u32, true, def, 10;
+        /// Send password attempts |> Number of wrong passwords in a row after which a password protected Send is disabled,
+        /// until its owner enables it again. Set to 0 to never disable them.
+        send_password_max_attempts: u32, true, def,    10;
 
         /// HIBP Api Key |> HaveIBeenPwned API Key, request it here: https://haveibeenpwned.com/API/Key
         hibp_api_key:           Pass,   true,   option;
+        /// HIBP cache time-to-live |> Number of seconds the breach reports of an account are cached, 0 disables the cache
+        hibp_cache_ttl:         u64,    true,   def,    21600;
 
         /// Per-user attachment limit (KB) |> Limit in kilobytes for a users attachments, once the limit is exceeded it won't be possible to upload more
         user_attachment_limit:  i64,    true,   option;
         /// Per-organization attachment limit (KB) |> Limit in kilobytes for an organization attachments, once the limit is exceeded it won't be possible to upload more
         org_attachment_limit:   i64,    true,   option;
+        /// Per-user Send limit (KB) |> Limit in kilobytes for the files of a users Sends, once the limit is exceeded it won't be possible to create more file Sends
+        user_send_limit:        i64,    true,   option;
+        /// Maximum Send file size (KB) |> Limit in kilobytes for the file of a single Send, 100 MB with an extra 10% by default
+        send_max_file_size:     i64,    true,   def,    112_640;
+        /// Maximum Send lifetime |> Maximum number of days between the creation of a Send and its deletion date
+        send_max_days:          i64,    true,   def,    31;
 
         /// Trash auto-delete days |> Number of days to wait before auto-deleting a trashed item.
         /// If unset, trashed items are not auto-deleted. This setting applies globally, so make
-        /// sure to inform all users of any changes to this setting.
+        /// sure to inform all users of any changes to this setting. Users can choose a shorter
+        /// retention for their own items, but never a longer one.
         trash_auto_delete_days: i64,    true,   option;
 
+        /// Cipher history revisions |> Number of previous revisions kept for each item, so accidental changes can be undone.
+        /// Set to 0 to disable the history.
+        cipher_history_max_revisions: u32, true, def,   5;
+        /// Track item usage |> Record when each user last opened an item, for the unused items report.
+        /// Disabled by default for privacy reasons.
+        cipher_access_tracking: bool,   true,   def,    false;
+
         /// Disable icon downloads |> Set to true to disable icon downloading, this would still serve icons from
         /// $ICON_CACHE_FOLDER, but it won't produce any external network request. Needs to set $ICON_CACHE_TTL to 0,
         /// otherwise it will delete them and they won't be downloaded again.
@@ -394,9 +491,22 @@ make_config! {
 
         /// Admin page token |> The token used to authenticate in this very same page. Changing it here won't deauthorize the current session
         admin_token:            Pass,   true,   option;
+        /// Admin session lifetime |> Minutes of inactivity after which the admin has to log in again in the admin panel.
+        /// The session is renewed while it's being used, up to 30 days. The logouts are only remembered until a restart
+        admin_session_lifetime: i64,    true,   def,    20;
+        /// Admin API token |> Bearer token of the JSON admin API under /admin/api, used for scripted provisioning. The API is disabled when it's not set
+        admin_api_token:        Pass,   true,   option;
+        /// Admin allowed IPs |> Comma-separated list of IPs or networks in CIDR notation (like 192.168.1.0/24) allowed to reach the admin panel
+        /// and its API. Blank allows any IP. It can only be set in the environment, so a leaked admin token can't be used to change it
+        admin_allowed_ips:      String, false,  def,    String::new();
 
         /// Invitation organization name |> Name shown in the invitation emails that don't come from a specific organization
         invitation_org_name:    String, true,   def,    "Vaultwarden".to_string();
+        /// Send welcome emails |> Send an email with the vault URL and some security recommendations when a user completes
+        /// their registration. With signups verification enabled, it's sent once the email address has been verified
+        welcome_email:          bool,   true,   def,    true;
+        /// Welcome email contact |> Administrator contact shown in the welcome email, like an email address or a name. Blank doesn't show any
+        welcome_email_contact:  String, true,   option;
     },
 
     /// Advanced settings
@@ -430,6 +540,11 @@ make_config! {
         /// Require new device emails |> When a user logs in an email is required to be sent.
         /// If sending the email fails the login attempt will fail.
         require_device_email:   bool,   true,   def,     false;
+        /// New device emails |> Send an email to the user when they log in from a new device
+        new_device_email:       bool,   true,   def,     true;
+        /// New IP emails |> Send an email to the user when they log in from a network they never logged in from before,
+        /// checking the /24 of IPv4 addresses and the /48 of IPv6 addresses. Not sent for the logins which already send a new device email
+        new_ip_email:           bool,   true,   def,     true;
 
         /// Reload templates (Dev) |> When this is set to true, the templates get reloaded with every request.
         /// ONLY use this during development, as it can slow down the server
@@ -449,6 +564,9 @@ make_config! {
         /// that do not support WAL. Please make sure you read project wiki on the topic before changing this setting.
         enable_db_wal:          bool,   false,  def,    true;
 
+        /// Database backups to keep |> Number of backups kept in the backups folder, the oldest ones are deleted after creating a new one. Set to 0 to keep all of them
+        backup_retention:       u32,    true,   def,    0;
+
         /// Max database connection retries |> Number of times to retry the database connection during startup, with 1 second between each retry, set to 0 to retry indefinitely
         db_connection_retries:  u32,    false,  def,    15;
 
@@ -459,6 +577,18 @@ make_config! {
         allowed_iframe_ancestors: String, true, def,    String::new();
     },
 
+    /// Maintenance mode
+    maintenance {
+        /// Maintenance mode |> Answers the requests outside of the admin panel with an error,
+        /// for example while doing a backup or a migration
+        maintenance_mode:       bool,   true,   def,    false;
+        /// Allow reads during maintenance |> Keeps serving the requests which don't change any data,
+        /// so the clients can still log in and sync the vault
+        maintenance_allow_reads: bool,  true,   def,    false;
+        /// Maintenance message |> Shown to the users during the maintenance
+        maintenance_message:    String, true,   def,    "The server is under maintenance, please try again later".to_string();
+    },
+
     /// Yubikey settings
     yubico: _enable_yubico {
         /// Enabled
@@ -485,18 +615,66 @@ make_config! {
         _duo_akey:              Pass,   false,  option;
     },
 
+    /// Admin panel single sign-on
+    admin_sso: _enable_admin_sso {
+        /// Enabled |> Allow logging in the admin panel with an OpenID Connect provider, as an alternative to the admin token
+        _enable_admin_sso:      bool,   true,   def,    true;
+        /// Authority |> Issuer URL of the provider, its configuration is read from /.well-known/openid-configuration
+        admin_sso_authority:    String, true,   option;
+        /// Client ID
+        admin_sso_client_id:    String, true,   option;
+        /// Client Secret
+        admin_sso_client_secret: Pass,  true,   option;
+        /// Allowed subjects |> Comma-separated list of the subjects (the `sub` claim) of the accounts which can log in
+        admin_sso_allowed_subjects: String, true, def,  String::new();
+    },
+
+    /// S3 Storage Settings
+    s3: _enable_s3 {
+        /// Enabled |> Store the attachments and Send files in an S3 compatible object storage instead of the local disk. Existing files are not moved when changing these settings.
+        _enable_s3:             bool,   false,  def,    true;
+        /// Endpoint |> URL of the service, for example https://s3.eu-west-1.amazonaws.com or http://minio:9000
+        s3_endpoint:            String, false,  option;
+        /// Bucket
+        s3_bucket:              String, false,  option;
+        /// Region
+        s3_region:              String, false,  def,    "us-east-1".to_string();
+        /// Key prefix |> Prefix added to the keys of all the stored files
+        s3_prefix:              String, false,  def,    String::new();
+        /// Store attachments |> Store the attachments in the object storage, otherwise they are kept in the attachments folder
+        s3_store_attachments:   bool,   false,  def,    true;
+        /// Store Send files |> Store the Send files in the object storage, otherwise they are kept in the sends folder
+        s3_store_sends:         bool,   false,  def,    true;
+        /// Send files bucket |> Bucket for the Send files, by default the same bucket as the attachments is used
+        s3_sends_bucket:        String, false,  option;
+        /// Send files key prefix |> Prefix of the keys of the Send files, by default it's the key prefix followed by /sends
+        s3_sends_prefix:        String, false,  option;
+        /// Access key ID
+        s3_access_key_id:       String, false,  option;
+        /// Secret access key
+        s3_secret_access_key:   Pass,   false,  option;
+    },
+
     /// SMTP Email Settings
     smtp: _enable_smtp {
         /// Enabled
         _enable_smtp:                  bool,   true,   def,     true;
+        /// Use Sendmail |> Send the emails by piping them to the local sendmail command instead of connecting to an SMTP server
+        use_sendmail:                  bool,   true,   def,     false;
+        /// Sendmail Command |> Path of the sendmail command of the local MTA. It can only be set in the environment,
+        /// so the admin token can't be used to run another command
+        sendmail_command:              String, false,  def,     "/usr/sbin/sendmail".to_string();
         /// Host
         smtp_host:                     String, true,   option;
-        /// Enable Secure SMTP |> (Explicit) - Enabling this by default would use STARTTLS (Standard ports 587 or 25)
-        smtp_ssl:                      bool,   true,   def,     true;
-        /// Force TLS |> (Implicit) - Enabling this would force the use of an SSL/TLS connection, instead of upgrading an insecure one with STARTTLS (Standard port 465)
-        smtp_explicit_tls:             bool,   true,   def,     false;
+        /// DEPRECATED smtp_ssl |> DEPRECATED - Please use SMTP_SECURITY
+        smtp_ssl:                      bool,   false,  option;
+        /// DEPRECATED smtp_explicit_tls |> DEPRECATED - Please use SMTP_SECURITY
+        smtp_explicit_tls:             bool,   false,  option;
+        /// Secure SMTP |> ("starttls", "force_tls", "off") Security of the connection: "starttls" upgrades the connection with STARTTLS
+        /// (Explicit - ports 587 or 25), "force_tls" uses an SSL/TLS connection from the start (Implicit - port 465), "off" doesn't encrypt it
+        smtp_security:                 String, true,   auto,    |c| smtp_convert_deprecated_ssl_options(c.smtp_ssl, c.smtp_explicit_tls);
         /// Port
-        smtp_port:                     u16,    true,   auto,    |c| if c.smtp_explicit_tls {465} else if c.smtp_ssl {587} else {25};
+        smtp_port:                     u16,    true,   auto,    |c| if c.smtp_security == *"force_tls" {465} else if c.smtp_security == *"starttls" {587} else {25};
         /// From Address
         smtp_from:                     String, true,   def,     String::new();
         /// From Name
@@ -507,9 +685,23 @@ make_config! {
         smtp_password:                 Pass,   true,   option;
         /// SMTP Auth mechanism |> Defaults for SSL is "Plain" and "Login" and nothing for Non-SSL connections. Possible values: ["Plain", "Login", "Xoauth2"]. Multiple options need to be separated by a comma ','.
         smtp_auth_mechanism:           String, true,   option;
-        /// SMTP connection timeout |> Number of seconds when to stop trying to connect to the SMTP server
+        /// OAuth2 client ID |> Authenticate with XOAUTH2 using an access token of this OAuth2 client instead of the password,
+        /// for the servers like Microsoft 365 or Gmail which require it. The access token is refreshed automatically
+        smtp_oauth2_client_id:         String, true,   option;
+        /// OAuth2 client secret
+        smtp_oauth2_client_secret:     Pass,   true,   option;
+        /// OAuth2 refresh token |> Used to get the access tokens when set, otherwise the client credentials are used (for Microsoft 365 applications)
+        smtp_oauth2_refresh_token:     Pass,   true,   option;
+        /// OAuth2 Microsoft tenant |> Microsoft 365 tenant ID or domain, used to build the token URL when it's not set
+        smtp_oauth2_tenant:            String, true,   option;
+        /// OAuth2 token URL |> Token endpoint of the OAuth2 provider, like https://oauth2.googleapis.com/token for Gmail
+        smtp_oauth2_token_url:         String, true,   auto,    |c| c.smtp_oauth2_tenant.as_ref().map_or(String::new(), |t| format!("https://login.microsoftonline.com/{}/oauth2/v2.0/token", t));
+        /// OAuth2 scope |> Scope requested for the access token, like https://outlook.office365.com/.default for Microsoft 365
+        /// applications or https://mail.google.com/ for Gmail. Blank uses the scope of the refresh token
+        smtp_oauth2_scope:             String, true,   option;
+        /// SMTP connection timeout |> Number of seconds to wait for the SMTP server, both when connecting and for the answer to each command
         smtp_timeout:                  u64,    true,   def,     15;
-        /// Server name sent during HELO |> By default this value should be is on the machine's hostname, but might need to be changed in case it trips some anti-spam filters
+        /// Server name sent during EHLO/HELO |> By default this value should be is on the machine's hostname, but might need to be changed in case it trips some anti-spam filters
         helo_name:                     String, true,   option;
         /// Enable SMTP debugging (Know the risks!) |> DANGEROUS: Enabling this will output very detailed SMTP messages. This could contain sensitive information like passwords and usernames! Only enable this during troubleshooting!
         smtp_debug:                    bool,   false,  def,     false;
@@ -517,21 +709,47 @@ make_config! {
         smtp_accept_invalid_certs:     bool,   true,   def,     false;
         /// Accept Invalid Hostnames (Know the risks!) |> DANGEROUS: Allow invalid hostnames. This option introduces significant vulnerabilities to man-in-the-middle attacks!
         smtp_accept_invalid_hostnames: bool,   true,   def,     false;
+        /// Queue the emails |> Save the emails in the database and send them in the background, retrying the failed ones,
+        /// so an unavailable SMTP server doesn't make the user actions fail
+        mail_queue:                    bool,   true,   def,     true;
+        /// Email sending attempts |> Number of attempts to send a queued email before it's marked as failed, the wait
+        /// between attempts doubles every time, starting at 1 minute
+        mail_queue_max_attempts:       u32,    true,   def,     8;
     },
 
     /// Email 2FA Settings
     email_2fa: _enable_email_2fa {
         /// Enabled |> Disabling will prevent users from setting up new email 2FA and using existing email 2FA configured
-        _enable_email_2fa:      bool,   true,   auto,    |c| c._enable_smtp && c.smtp_host.is_some();
+        _enable_email_2fa:      bool,   true,   auto,    |c| c._enable_smtp && (c.smtp_host.is_some() || c.use_sendmail);
         /// Email token size |> Number of digits in an email token (min: 6, max: 19). Note that the Bitwarden clients are hardcoded to mention 6 digit codes regardless of this setting.
         email_token_size:       u32,    true,   def,      6;
         /// Token expiration time |> Maximum time in seconds a token is valid. The time the user has to open email client and copy token.
+        /// Also applies to the code sent to the new address when changing the email
         email_expiration_time:  u64,    true,   def,      600;
-        /// Maximum attempts |> Maximum attempts before an email token is reset and a new email will need to be sent
+        /// Maximum attempts |> Maximum attempts before an email token is reset and a new email will need to be sent.
+        /// Also applies to the code sent to the new address when changing the email
         email_attempts_limit:   u64,    true,   def,      3;
     },
 }
 
+/// Converts the deprecated `SMTP_SSL` and `SMTP_EXPLICIT_TLS` options to the matching `SMTP_SECURITY` value,
+/// `SMTP_EXPLICIT_TLS` actually enabled implicit TLS
+fn smtp_convert_deprecated_ssl_options(smtp_ssl: Option<bool>, smtp_explicit_tls: Option<bool>) -> String {
+    if smtp_explicit_tls == Some(true) {
+        "force_tls".to_string()
+    } else if smtp_ssl == Some(false) {
+        "off".to_string()
+    } else {
+        "starttls".to_string()
+    }
+}
+
+/// Longest admin session allowed, in minutes
+const MAX_ADMIN_SESSION_LIFETIME: i64 = 30 * 24 * 60;
+
+/// Longest Send lifetime allowed, in days. Larger values would overflow the deletion date checks
+const MAX_SEND_DAYS: i64 = 36_500;
+
 fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
     // Validate connection URL is valid and DB feature is enabled
     DbConnType::from_url(&cfg.database_url)?;
@@ -541,6 +759,24 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!(format!("`DATABASE_MAX_CONNS` contains an invalid value. Ensure it is between 1 and {}.", limit,));
     }
 
+    if cfg.require_device_email && !cfg.new_device_email {
+        err!("`REQUIRE_DEVICE_EMAIL` can't be enabled when `NEW_DEVICE_EMAIL` is disabled")
+    }
+
+    if cfg.admin_session_lifetime < 1 {
+        err!("`ADMIN_SESSION_LIFETIME` must be at least 1 minute")
+    }
+
+    if cfg.admin_session_lifetime > MAX_ADMIN_SESSION_LIFETIME {
+        err!(format!("`ADMIN_SESSION_LIFETIME` can't be longer than {} minutes (30 days)", MAX_ADMIN_SESSION_LIFETIME))
+    }
+
+    if let Some(network) =
+        cfg.admin_allowed_ips.split(',').find(|n| !n.trim().is_empty() && crate::util::parse_ip_network(n).is_none())
+    {
+        err!(format!("`ADMIN_ALLOWED_IPS` contains an invalid IP or network: {}", network.trim()))
+    }
+
     let dom = cfg.domain.to_lowercase();
     if !dom.starts_with("http://") && !dom.starts_with("https://") {
         err!(
@@ -548,6 +784,15 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         );
     }
 
+    match Url::parse(&cfg.domain) {
+        Ok(url) if url.host_str().is_none() => err!("`DOMAIN` doesn't contain a host name"),
+        Ok(url) if url.query().is_some() || url.fragment().is_some() => {
+            err!("`DOMAIN` can't contain a query string or a fragment")
+        }
+        Ok(_) => (),
+        Err(e) => err!(format!("`DOMAIN` is not a valid URL: {}", e)),
+    }
+
     let whitelist = &cfg.signups_domains_whitelist;
     if !whitelist.is_empty() && whitelist.split(',').any(|d| d.trim().is_empty()) {
         err!("`SIGNUPS_DOMAINS_WHITELIST` contains empty tokens");
@@ -574,24 +819,115 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         err!("All Duo options need to be set for global Duo support")
     }
 
+    if cfg._enable_admin_sso && cfg.admin_sso_authority.is_some() {
+        if cfg.admin_sso_client_id.is_none() || cfg.admin_sso_client_secret.is_none() {
+            err!("`ADMIN_SSO_CLIENT_ID` and `ADMIN_SSO_CLIENT_SECRET` need to be set for the admin panel SSO")
+        }
+
+        if !cfg.domain_set {
+            err!("`DOMAIN` needs to be set for the admin panel SSO, it's used in the redirect URI")
+        }
+
+        if cfg.admin_sso_allowed_subjects.split(',').all(|s| s.trim().is_empty()) {
+            err!("`ADMIN_SSO_ALLOWED_SUBJECTS` needs to contain at least one subject")
+        }
+
+        if let Some(ref authority) = cfg.admin_sso_authority {
+            if Url::parse(authority).is_err() {
+                err!("`ADMIN_SSO_AUTHORITY` is not a valid URL")
+            }
+        }
+    }
+
     if cfg._enable_yubico && cfg.yubico_client_id.is_some() != cfg.yubico_secret_key.is_some() {
         err!("Both `YUBICO_CLIENT_ID` and `YUBICO_SECRET_KEY` need to be set for Yubikey OTP support")
     }
 
+    if cfg.send_max_file_size < 1 {
+        err!("`SEND_MAX_FILE_SIZE` must be at least 1 KB")
+    }
+
+    if cfg.send_max_days < 1 {
+        err!("`SEND_MAX_DAYS` must be at least 1 day")
+    }
+
+    if cfg.send_max_days > MAX_SEND_DAYS {
+        err!(format!("`SEND_MAX_DAYS` can't be more than {} days", MAX_SEND_DAYS))
+    }
+
+    if matches!(cfg.trash_auto_delete_days, Some(days) if days < 1) {
+        err!("`TRASH_AUTO_DELETE_DAYS` must be at least 1 day")
+    }
+
+    let s3_bucket_set = cfg.s3_bucket.is_some() || cfg.s3_sends_bucket.is_some();
+    if cfg._enable_s3
+        && (cfg.s3_endpoint.is_some() || s3_bucket_set)
+        && !(cfg.s3_endpoint.is_some()
+            && s3_bucket_set
+            && cfg.s3_access_key_id.is_some()
+            && cfg.s3_secret_access_key.is_some())
+    {
+        err!("`S3_ENDPOINT`, `S3_BUCKET`, `S3_ACCESS_KEY_ID` and `S3_SECRET_ACCESS_KEY` need to be set for S3 storage")
+    }
+
+    if let Some(ref endpoint) = cfg.s3_endpoint {
+        if Url::parse(endpoint).is_err() {
+            err!("`S3_ENDPOINT` is not a valid URL")
+        }
+    }
+
     if cfg._enable_smtp {
-        if cfg.smtp_host.is_some() == cfg.smtp_from.is_empty() {
-            err!("Both `SMTP_HOST` and `SMTP_FROM` need to be set for email support")
+        if (cfg.smtp_host.is_some() || cfg.use_sendmail) == cfg.smtp_from.is_empty() {
+            err!("Both `SMTP_HOST` (or `USE_SENDMAIL`) and `SMTP_FROM` need to be set for email support")
         }
 
-        if cfg.smtp_host.is_some() && !cfg.smtp_from.contains('@') {
+        if (cfg.smtp_host.is_some() || cfg.use_sendmail) && !cfg.smtp_from.contains('@') {
             err!("SMTP_FROM does not contain a mandatory @ sign")
         }
 
-        if cfg.smtp_username.is_some() != cfg.smtp_password.is_some() {
+        if cfg.use_sendmail {
+            let path = std::path::Path::new(&cfg.sendmail_command);
+            // A bare command name is searched in the PATH when sending, only full paths can be checked here
+            if path.is_absolute() && !path.is_file() {
+                err!(format!("`SENDMAIL_COMMAND` ({}) doesn't exist", cfg.sendmail_command))
+            }
+        }
+
+        if !["starttls", "force_tls", "off"].contains(&cfg.smtp_security.as_str()) {
+            err!("`SMTP_SECURITY` must be one of \"starttls\", \"force_tls\" or \"off\"")
+        }
+
+        if let Some(ref helo_name) = cfg.helo_name {
+            if helo_name.is_empty() || helo_name.contains(char::is_whitespace) {
+                err!("`HELO_NAME` must be a hostname without spaces")
+            }
+        }
+
+        if cfg.smtp_ssl.is_some() || cfg.smtp_explicit_tls.is_some() {
+            println!("[DEPRECATED]: `SMTP_SSL` and `SMTP_EXPLICIT_TLS` are deprecated, please use `SMTP_SECURITY` instead");
+        }
+
+        if cfg.smtp_oauth2_client_id.is_some() {
+            if cfg.smtp_username.is_none() {
+                err!("`SMTP_USERNAME` needs to be set to enable the OAuth2 authentication")
+            }
+
+            if cfg.smtp_oauth2_token_url.is_empty() {
+                err!("Either `SMTP_OAUTH2_TOKEN_URL` or `SMTP_OAUTH2_TENANT` need to be set for OAuth2")
+            }
+
+            if Url::parse(&cfg.smtp_oauth2_token_url).is_err() {
+                err!("`SMTP_OAUTH2_TOKEN_URL` is not a valid URL")
+            }
+
+            if cfg.smtp_oauth2_refresh_token.is_none() && cfg.smtp_oauth2_client_secret.is_none() {
+                err!("Either `SMTP_OAUTH2_REFRESH_TOKEN` or `SMTP_OAUTH2_CLIENT_SECRET` need to be set for OAuth2")
+            }
+        } else if cfg.smtp_username.is_some() != cfg.smtp_password.is_some() {
             err!("Both `SMTP_USERNAME` and `SMTP_PASSWORD` need to be set to enable email authentication")
         }
 
-        if cfg._enable_email_2fa && (!cfg._enable_smtp || cfg.smtp_host.is_none()) {
+        if cfg._enable_email_2fa && (!cfg._enable_smtp || (cfg.smtp_host.is_none() && !cfg.use_sendmail)) {
             err!("To enable email 2FA, SMTP must be configured")
         }
 
@@ -655,7 +991,7 @@ impl Config {
 
         Ok(Config {
             inner: RwLock::new(Inner {
-                templates: load_templates(&config.templates_folder),
+                templates: load_templates(Some(&config.templates_folder))?,
                 config,
                 _env,
                 _usr,
@@ -664,12 +1000,17 @@ impl Config {
         })
     }
 
+    /// Replaces the config file with `other`, the values which aren't editable keep their current value
     pub fn update_config(&self, other: ConfigBuilder) -> Result<(), Error> {
+        let builder = other.keep_non_editable(&self.inner.read().unwrap()._usr);
+        self.save_config(builder)
+    }
+
+    fn save_config(&self, builder: ConfigBuilder) -> Result<(), Error> {
         // Remove default values
         //let builder = other.remove(&self.inner.read().unwrap()._env);
 
         // TODO: Remove values that are defaults, above only checks those set by env and not the defaults
-        let builder = other;
 
         // Serialize now before we consume the builder
         let config_str = serde_json::to_string_pretty(&builder)?;
@@ -682,6 +1023,18 @@ impl Config {
         };
         validate_config(&config)?;
 
+        // Save to file before applying the changes, so a failed write doesn't leave unsaved changes active.
+        // The file is written to a temporary one first, a crash while writing would otherwise truncate it
+        // and the server wouldn't start anymore.
+        use std::{fs::File, io::Write};
+        let tmp_file = format!("{}.tmp", &*CONFIG_FILE);
+        {
+            let mut file = File::create(&tmp_file)?;
+            file.write_all(config_str.as_bytes())?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp_file, &*CONFIG_FILE)?;
+
         // Save both the user and the combined config
         {
             let mut writer = self.inner.write().unwrap();
@@ -690,21 +1043,42 @@ impl Config {
             writer._overrides = overrides;
         }
 
-        //Save to file
-        use std::{fs::File, io::Write};
-        let mut file = File::create(&*CONFIG_FILE)?;
-        file.write_all(config_str.as_bytes())?;
-
         Ok(())
     }
 
+    /// Merges `other` into the config file. Unlike `update_config()`, the values which aren't editable can be changed,
+    /// so `other` must not come from the user
     pub fn update_config_partial(&self, other: ConfigBuilder) -> Result<(), Error> {
         let builder = {
             let usr = &self.inner.read().unwrap()._usr;
             let mut _overrides = Vec::new();
             usr.merge(&other, false, &mut _overrides)
         };
-        self.update_config(builder)
+        self.save_config(builder)
+    }
+
+    /// Imports a configuration profile created by `export_profile()`. The values are merged into the
+    /// current config file. Secrets which are only referenced by name aren't overwritten, so they keep
+    /// the value from the environment or the existing config file of this instance, neither are the values
+    /// which aren't editable.
+    pub fn import_profile(&self, mut profile: serde_json::Value) -> Result<(), Error> {
+        let entries = match profile.as_object_mut() {
+            Some(entries) => entries,
+            None => err!("The configuration profile needs to be a JSON object"),
+        };
+
+        let references: Vec<String> = entries
+            .iter()
+            .filter(|(name, value)| value.as_str() == Some(secret_reference(name).as_str()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in references {
+            entries.remove(&name);
+        }
+
+        let builder: ConfigBuilder = serde_json::from_value(profile)?;
+        let builder = builder.keep_non_editable(&self.inner.read().unwrap()._usr);
+        self.update_config_partial(builder)
     }
 
     /// Tests whether an email's domain is allowed. A domain is allowed if it
@@ -777,7 +1151,7 @@ impl Config {
     }
     pub fn mail_enabled(&self) -> bool {
         let inner = &self.inner.read().unwrap().config;
-        inner._enable_smtp && inner.smtp_host.is_some()
+        inner._enable_smtp && (inner.smtp_host.is_some() || inner.use_sendmail)
     }
 
     pub fn get_duo_akey(&self) -> String {
@@ -798,6 +1172,25 @@ impl Config {
         }
     }
 
+    /// Tests whether the admins can log in to the admin panel through the SSO provider
+    pub fn is_admin_sso_enabled(&self) -> bool {
+        let inner = &self.inner.read().unwrap().config;
+        inner._enable_admin_sso && inner.admin_sso_authority.is_some()
+    }
+
+    /// Tests whether the IP can reach the admin panel, according to `admin_allowed_ips`
+    pub fn is_admin_ip_allowed(&self, ip: &std::net::IpAddr) -> bool {
+        let allowed_ips = self.admin_allowed_ips();
+        if allowed_ips.trim().is_empty() {
+            return true;
+        }
+
+        allowed_ips
+            .split(',')
+            .filter_map(crate::util::parse_ip_network)
+            .any(|network| crate::util::is_ip_in_network(ip, &network))
+    }
+
     /// Tests whether the admin token is set to a non-empty value.
     pub fn is_admin_token_set(&self) -> bool {
         let token = self.admin_token();
@@ -810,23 +1203,71 @@ impl Config {
         name: &str,
         data: &T,
     ) -> Result<String, crate::error::Error> {
-        if CONFIG.reload_templates() {
+        let result = if CONFIG.reload_templates() {
             warn!("RELOADING TEMPLATES");
-            let hb = load_templates(CONFIG.templates_folder());
-            hb.render(name, data).map_err(Into::into)
+            let hb = load_templates(Some(&CONFIG.templates_folder()))?;
+            hb.render(name, data)
         } else {
             let hb = &CONFIG.inner.read().unwrap().templates;
-            hb.render(name, data).map_err(Into::into)
+            hb.render(name, data)
+        };
+
+        match result {
+            Ok(text) => Ok(text),
+            // A broken override shouldn't prevent sending the emails, fall back to the embedded template
+            Err(e) if self.template_overrides().iter().any(|o| o == name) => {
+                error!("Error rendering the template override {}, using the default one: {}", name, e);
+                DEFAULT_TEMPLATES.render(name, data).map_err(Into::into)
+            }
+            Err(e) => Err(e.into()),
         }
     }
+
+    /// Reloads the templates, so the changes to the overrides in `templates_folder` are applied without restarting.
+    /// The current templates are kept if the new ones can't be loaded, returns the names of the overridden templates
+    pub fn reload_templates_folder(&self) -> Result<Vec<String>, Error> {
+        let hb = load_templates(Some(&self.templates_folder()))?;
+        self.inner.write().unwrap().templates = hb;
+
+        let overrides = self.template_overrides();
+        info!("Templates reloaded, {} overridden", overrides.len());
+        Ok(overrides)
+    }
+
+    /// Returns the names of the templates of `templates_folder` overriding an embedded one
+    pub fn template_overrides(&self) -> Vec<String> {
+        fn list_templates(dir: &std::path::Path, prefix: &str, names: &mut Vec<String>) {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => return,
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                if path.is_dir() {
+                    list_templates(&path, &format!("{}{}/", prefix, file_name), names);
+                } else if let Some(name) = file_name.strip_suffix(".hbs") {
+                    names.push(format!("{}{}", prefix, name));
+                }
+            }
+        }
+
+        let mut names = Vec::new();
+        list_templates(std::path::Path::new(&self.templates_folder()), "", &mut names);
+        names.retain(|name| DEFAULT_TEMPLATES.has_template(name));
+        names.sort();
+        names
+    }
 }
 
+/// The embedded templates, without the overrides of `templates_folder`
+static DEFAULT_TEMPLATES: Lazy<Handlebars<'static>> =
+    Lazy::new(|| load_templates(None).expect("The embedded templates are valid"));
+
 use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError, Renderable};
 
-fn load_templates<P>(path: P) -> Handlebars<'static>
-where
-    P: AsRef<std::path::Path>,
-{
+/// Loads the embedded templates, overridden by the ones of the `path` folder if given
+fn load_templates(path: Option<&str>) -> Result<Handlebars<'static>, Error> {
     let mut hb = Handlebars::new();
     // Error on missing params
     hb.set_strict_mode(true);
@@ -855,8 +1296,11 @@ where
     reg!("email/invite_accepted", ".html");
     reg!("email/invite_confirmed", ".html");
     reg!("email/new_device_logged_in", ".html");
+    reg!("email/new_ip_logged_in", ".html");
     reg!("email/pw_hint_none", ".html");
     reg!("email/pw_hint_some", ".html");
+    reg!("email/removed_from_org", ".html");
+    reg!("email/send_accessed", ".html");
     reg!("email/send_org_invite", ".html");
     reg!("email/twofactor_email", ".html");
     reg!("email/verify_email", ".html");
@@ -869,14 +1313,22 @@ where
     reg!("admin/settings");
     reg!("admin/users");
     reg!("admin/organizations");
+    reg!("admin/sends");
+    reg!("admin/storage");
+    reg!("admin/metrics");
+    reg!("admin/mails");
     reg!("admin/diagnostics");
+    reg!("admin/setup");
 
     // And then load user templates to overwrite the defaults
     // Use .hbs extension for the files
     // Templates get registered with their relative name
-    hb.register_templates_directory(".hbs", path).unwrap();
+    if let Some(path) = path {
+        hb.register_templates_directory(".hbs", path)
+            .map_err(|e| Error::new("Error loading the templates folder", e.to_string()))?;
+    }
 
-    hb
+    Ok(hb)
 }
 
 fn case_helper<'reg, 'rc>(
@@ -918,3 +1370,27 @@ fn js_escape_helper<'reg, 'rc>(
     out.write(&escaped_value)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_posted_non_editable_values_are_ignored() {
+        let current = ConfigBuilder {
+            admin_allowed_ips: Some("10.0.0.0/8".to_string()),
+            ..Default::default()
+        };
+        let posted = ConfigBuilder {
+            admin_allowed_ips: Some("0.0.0.0/0".to_string()),
+            sendmail_command: Some("/tmp/evil".to_string()),
+            signups_allowed: Some(false),
+            ..Default::default()
+        };
+
+        let builder = posted.keep_non_editable(&current);
+        assert_eq!(builder.admin_allowed_ips.as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(builder.sendmail_command, None);
+        assert_eq!(builder.signups_allowed, Some(false));
+    }
+}
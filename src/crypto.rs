@@ -1,7 +1,7 @@
 //
 // PBKDF2 derivation
 //
-use std::num::NonZeroU32;
+use std::{fs::File, io::Read, num::NonZeroU32, path::Path};
 
 use data_encoding::HEXLOWER;
 use ring::{digest, hmac, pbkdf2};
@@ -35,6 +35,28 @@ pub fn hmac_sign(key: &str, data: &str) -> String {
     HEXLOWER.encode(signature.as_ref())
 }
 
+//
+// SHA-256
+//
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    sha256_reader(&mut File::open(path)?)
+}
+
+pub fn sha256_reader<R: Read>(reader: &mut R) -> std::io::Result<String> {
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut buffer = [0u8; 64 * 1024];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        context.update(&buffer[..read]);
+    }
+
+    Ok(HEXLOWER.encode(context.finish().as_ref()))
+}
+
 //
 // Random values
 //
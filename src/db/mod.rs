@@ -225,21 +225,113 @@ macro_rules! db_object {
 // Reexport the models, needs to be after the macros are defined so it can access them
 pub mod models;
 
-/// Creates a back-up of the sqlite database
-/// MySQL/MariaDB and PostgreSQL are not supported.
-pub fn backup_database(conn: &DbConn) -> Result<(), Error> {
+/// Creates a timestamped backup of the database in the backups folder, and returns its path.
+/// SQLite is copied with `VACUUM INTO`, MySQL/MariaDB and PostgreSQL are dumped with
+/// `mysqldump` and `pg_dump`, which need to be installed.
+pub fn backup_database(conn: &DbConn) -> Result<String, Error> {
+    let backup_folder = CONFIG.backup_folder();
+    std::fs::create_dir_all(&backup_folder)?;
+    let file_date = chrono::Utc::now().format("%Y%m%d_%H%M%S").to_string();
+
+    let backup_file = dump_database(conn, &backup_folder, &file_date)?;
+    delete_old_backups(&backup_folder);
+    Ok(backup_file)
+}
+
+fn dump_database(conn: &DbConn, backup_folder: &str, file_date: &str) -> Result<String, Error> {
     db_run! {@raw conn:
-        postgresql, mysql {
-            err!("PostgreSQL and MySQL/MariaDB do not support this backup feature");
-        }
         sqlite {
-            use std::path::Path;
-            let db_url = CONFIG.database_url();
-            let db_path = Path::new(&db_url).parent().unwrap().to_string_lossy();
-            let file_date = chrono::Utc::now().format("%Y%m%d_%H%M%S").to_string();
-            diesel::sql_query(format!("VACUUM INTO '{}/db_{}.sqlite3'", db_path, file_date)).execute(conn)?;
-            Ok(())
+            let backup_file = format!("{}/db_{}.sqlite3", backup_folder, file_date);
+            diesel::sql_query(format!("VACUUM INTO '{}'", backup_file.replace('\'', "''"))).execute(conn)?;
+            Ok(backup_file)
+        }
+        mysql {
+            use percent_encoding::percent_decode_str;
+            let backup_file = format!("{}/db_{}.sql", backup_folder, file_date);
+            let url = url::Url::parse(&CONFIG.database_url()).ok().map_res("Invalid DATABASE_URL")?;
+            let password = percent_decode_str(url.password().unwrap_or_default()).decode_utf8_lossy();
+
+            let mut command = std::process::Command::new("mysqldump");
+            command
+                .arg("--single-transaction")
+                .arg(format!("--host={}", url.host_str().unwrap_or("localhost")))
+                .arg(format!("--port={}", url.port().unwrap_or(3306)))
+                .arg(format!("--user={}", percent_decode_str(url.username()).decode_utf8_lossy()))
+                .arg(format!("--result-file={}", backup_file))
+                .arg(url.path().trim_start_matches('/'))
+                // Passed in the environment so it's not visible in the process list
+                .env("MYSQL_PWD", password.as_ref());
+            run_backup_command("mysqldump", command)?;
+            Ok(backup_file)
+        }
+        postgresql {
+            use percent_encoding::percent_decode_str;
+            let backup_file = format!("{}/db_{}.sql", backup_folder, file_date);
+            let mut url = url::Url::parse(&CONFIG.database_url()).ok().map_res("Invalid DATABASE_URL")?;
+            let password = percent_decode_str(url.password().unwrap_or_default()).decode_utf8_lossy().into_owned();
+            // Passed in the environment so it's not visible in the process list
+            let _ = url.set_password(None);
+
+            let mut command = std::process::Command::new("pg_dump");
+            command
+                .arg(format!("--dbname={}", url))
+                .arg(format!("--file={}", backup_file))
+                .env("PGPASSWORD", password);
+            run_backup_command("pg_dump", command)?;
+            Ok(backup_file)
+        }
+    }
+}
+
+#[cfg(any(mysql, postgresql))]
+fn run_backup_command(name: &str, mut command: std::process::Command) -> Result<(), Error> {
+    match command.output() {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => err!(format!("The backup failed: {}", String::from_utf8_lossy(&output.stderr).trim())),
+        Err(e) => err!(format!("Unable to run {}, make sure it's installed: {}", name, e)),
+    }
+}
+
+/// Keeps only the newest backups, as configured with `BACKUP_RETENTION`
+fn delete_old_backups(backup_folder: &str) {
+    let retention = CONFIG.backup_retention() as usize;
+    if retention == 0 {
+        return;
+    }
+
+    let mut backups: Vec<std::path::PathBuf> = match std::fs::read_dir(backup_folder) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.file_name().map_or(false, |n| n.to_string_lossy().starts_with("db_")))
+            .collect(),
+        Err(e) => {
+            warn!("Unable to list the database backups: {}", e);
+            return;
         }
+    };
+
+    // The names contain the date, so they are sorted from the oldest to the newest
+    backups.sort();
+    let excess = backups.len().saturating_sub(retention);
+    for backup in &backups[..excess] {
+        if let Err(e) = std::fs::remove_file(backup) {
+            warn!("Unable to delete the old database backup {}: {}", backup.display(), e);
+        }
+    }
+}
+
+impl DbConn {
+    /// Runs the provided closure inside a database transaction.
+    /// All the changes are rolled back if the closure returns an error.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        let conn = self;
+        db_run! {@raw conn: {
+            conn.transaction(f)
+        }}
     }
 }
 
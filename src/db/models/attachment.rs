@@ -1,9 +1,18 @@
-use std::io::ErrorKind;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
 
+use once_cell::sync::Lazy;
 use serde_json::Value;
 
 use super::Cipher;
-use crate::CONFIG;
+use crate::{
+    storage::{get_storage, FileArea},
+    CONFIG,
+};
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, Associations, AsChangeset)]
@@ -17,9 +26,12 @@ db_object! {
         pub file_name: String, // encrypted
         pub file_size: i32,
         pub akey: Option<String>,
+        pub file_hash: Option<String>, // SHA-256 of the stored file, shared by the attachments with the same content
     }
 }
 
+static ATTACHMENT_FILES_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
 /// Local methods
 impl Attachment {
     pub const fn new(id: String, cipher_uuid: String, file_name: String, file_size: i32, akey: Option<String>) -> Self {
@@ -29,11 +41,22 @@ impl Attachment {
             file_name,
             file_size,
             akey,
+            file_hash: None,
         }
     }
 
-    pub fn get_file_path(&self) -> String {
-        format!("{}/{}/{}", CONFIG.attachments_folder(), self.cipher_uuid, self.id)
+    /// Path on the local disk where the file is written while it's uploaded in chunks
+    pub fn get_upload_path(&self) -> PathBuf {
+        Path::new(&CONFIG.tmp_folder()).join(&self.id)
+    }
+
+    /// Path of the file in the attachments storage. Files with a known hash are only stored
+    /// once, and that copy is used by all the attachments with the same content.
+    pub fn get_storage_path(&self) -> String {
+        match self.file_hash {
+            Some(ref hash) => format!("blobs/{}", hash),
+            None => format!("{}/{}", self.cipher_uuid, self.id),
+        }
     }
 
     pub fn get_url(&self, host: &str) -> String {
@@ -46,8 +69,9 @@ impl Attachment {
             "Url": self.get_url(host),
             "FileName": self.file_name,
             "Size": self.file_size.to_string(),
-            "SizeName": crate::util::get_display_size(self.file_size),
+            "SizeName": crate::util::get_display_size(self.file_size as i64),
             "Key": self.akey,
+            "Sha256": self.file_hash,
             "Object": "attachment"
         })
     }
@@ -93,34 +117,86 @@ impl Attachment {
     }
 
     pub fn delete(&self, conn: &DbConn) -> EmptyResult {
+        self.delete_record(conn)?;
+        self.delete_file(conn)
+    }
+
+    /// Deletes the attachment from the database, leaving its file in the storage
+    pub fn delete_record(&self, conn: &DbConn) -> EmptyResult {
         db_run! { conn: {
             crate::util::retry(
                 || diesel::delete(attachments::table.filter(attachments::id.eq(&self.id))).execute(conn),
                 10,
             )
-            .map_res("Error deleting attachment")?;
-
-            let file_path = &self.get_file_path();
-
-            match crate::util::delete_file(file_path) {
-                // Ignore "file not found" errors. This can happen when the
-                // upstream caller has already cleaned up the file as part of
-                // its own error handling.
-                Err(e) if e.kind() == ErrorKind::NotFound => {
-                    debug!("File '{}' already deleted.", file_path);
-                    Ok(())
-                }
-                Err(e) => Err(e.into()),
-                _ => Ok(()),
-            }
+            .map_res("Error deleting attachment")
         }}
     }
 
-    pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
-        for attachment in Attachment::find_by_cipher(cipher_uuid, conn) {
-            attachment.delete(conn)?;
+    /// Deletes the file of an attachment which was already deleted from the database
+    pub fn delete_file(&self, conn: &DbConn) -> EmptyResult {
+        std::fs::remove_file(self.get_upload_path()).ok();
+
+        // Keep the file while other attachments still use it
+        let _lock = Attachment::lock_files();
+        if let Some(ref hash) = self.file_hash {
+            if Attachment::count_by_hash(hash, conn) > 0 {
+                return Ok(());
+            }
+        }
+
+        get_storage(FileArea::Attachments).delete(&self.get_storage_path())
+    }
+
+    /// Moves the uploaded file of the attachment from `local_path` into the storage, or discards it
+    /// when a file with the same content is already stored, and saves the attachment.
+    pub fn save_file(&mut self, local_path: &Path, hash: String, conn: &DbConn) -> EmptyResult {
+        self.file_size = match i32::try_from(std::fs::metadata(local_path)?.len()) {
+            Ok(size) => size,
+            Err(_) => err!("Attachments can't be larger than 2 GiB"),
+        };
+        self.file_hash = Some(hash);
+        let storage = get_storage(FileArea::Attachments);
+        let storage_path = self.get_storage_path();
+
+        // Held until the attachment is saved, so the existing file can't be deleted in the meantime
+        let _lock = Attachment::lock_files();
+
+        if storage.exists(&storage_path) {
+            crate::util::delete_file(&local_path.to_string_lossy())?;
+        } else {
+            storage.save(&storage_path, local_path)?;
+        }
+
+        self.save(conn)
+    }
+
+    /// Serializes the checks of whether a file is still used with the deletion and reuse of the deduplicated files.
+    /// Without it, a file could be deleted right after a new attachment with the same content decided to reuse it.
+    /// This only covers a single instance of the server, the files are not locked in the storage.
+    pub fn lock_files() -> MutexGuard<'static, ()> {
+        ATTACHMENT_FILES_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Deletes the files of attachments once their deletion is committed to the database.
+    /// Failures are only logged, the files left behind are removed by `purge_orphaned_attachments`.
+    pub fn delete_files(attachments: &[Self], conn: &DbConn) {
+        for attachment in attachments {
+            if let Err(e) = attachment.delete_file(conn) {
+                warn!("Failed to delete the file of attachment {}: {:#?}", attachment.id, e);
+            }
+        }
+    }
+
+    /// Returns whether the file at `path` in the attachments storage is still used by an attachment
+    pub fn is_file_used(path: &str, conn: &DbConn) -> bool {
+        match path.split_once('/') {
+            Some(("blobs", hash)) => Attachment::count_by_hash(hash, conn) > 0,
+            Some((cipher_uuid, id)) => match Attachment::find_by_id(id, conn) {
+                Some(attachment) => attachment.cipher_uuid == cipher_uuid && attachment.file_hash.is_none(),
+                None => false,
+            },
+            None => false,
         }
-        Ok(())
     }
 
     pub fn find_by_id(id: &str, conn: &DbConn) -> Option<Self> {
@@ -153,6 +229,35 @@ impl Attachment {
         }}
     }
 
+    /// Returns the attachments whose cipher doesn't exist anymore, left behind when the foreign keys weren't enforced
+    pub fn find_orphaned(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            attachments::table
+                .left_join(ciphers::table.on(ciphers::uuid.eq(attachments::cipher_uuid)))
+                .filter(ciphers::uuid.is_null())
+                .select(attachments::all_columns)
+                .load::<AttachmentDb>(conn)
+                .expect("Error loading attachments")
+                .from_db()
+        }}
+    }
+
+    pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            attachments::table.load::<AttachmentDb>(conn).expect("Error loading attachments").from_db()
+        }}
+    }
+
+    pub fn count_by_hash(hash: &str, conn: &DbConn) -> i64 {
+        db_run! { conn: {
+            attachments::table
+                .filter(attachments::file_hash.eq(hash))
+                .count()
+                .first(conn)
+                .unwrap_or(0)
+        }}
+    }
+
     pub fn size_by_user(user_uuid: &str, conn: &DbConn) -> i64 {
         db_run! { conn: {
             let result: Option<i64> = attachments::table
@@ -176,6 +281,46 @@ impl Attachment {
         }}
     }
 
+    /// Returns the number of attachments and their total size of every user with attachments, in a single query
+    pub fn storage_by_user(conn: &DbConn) -> HashMap<String, (i64, i64)> {
+        db_run! { conn: {
+            attachments::table
+                .inner_join(ciphers::table.on(ciphers::uuid.eq(attachments::cipher_uuid)))
+                .filter(ciphers::user_uuid.is_not_null())
+                .group_by(ciphers::user_uuid)
+                .select((
+                    ciphers::user_uuid,
+                    diesel::dsl::count_star(),
+                    diesel::dsl::sum(attachments::file_size),
+                ))
+                .load::<(Option<String>, i64, Option<i64>)>(conn)
+                .expect("Error loading attachment storage by user")
+                .into_iter()
+                .filter_map(|(user_uuid, count, size)| user_uuid.map(|uuid| (uuid, (count, size.unwrap_or(0)))))
+                .collect()
+        }}
+    }
+
+    /// Returns the number of attachments and their total size of every organization with attachments, in a single query
+    pub fn storage_by_org(conn: &DbConn) -> HashMap<String, (i64, i64)> {
+        db_run! { conn: {
+            attachments::table
+                .inner_join(ciphers::table.on(ciphers::uuid.eq(attachments::cipher_uuid)))
+                .filter(ciphers::organization_uuid.is_not_null())
+                .group_by(ciphers::organization_uuid)
+                .select((
+                    ciphers::organization_uuid,
+                    diesel::dsl::count_star(),
+                    diesel::dsl::sum(attachments::file_size),
+                ))
+                .load::<(Option<String>, i64, Option<i64>)>(conn)
+                .expect("Error loading attachment storage by organization")
+                .into_iter()
+                .filter_map(|(org_uuid, count, size)| org_uuid.map(|uuid| (uuid, (count, size.unwrap_or(0)))))
+                .collect()
+        }}
+    }
+
     pub fn size_by_org(org_uuid: &str, conn: &DbConn) -> i64 {
         db_run! { conn: {
             let result: Option<i64> = attachments::table
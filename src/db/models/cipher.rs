@@ -1,11 +1,12 @@
 use chrono::{Duration, NaiveDateTime, Utc};
 use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 
 use crate::CONFIG;
 
 use super::{
-    Attachment, CollectionCipher, Favorite, FolderCipher, Organization, User, UserOrgStatus, UserOrgType,
-    UserOrganization,
+    Attachment, CipherAccess, CipherHistory, CollectionCipher, CollectionUser, Favorite, FolderCipher, Organization,
+    User, UserOrgStatus, UserOrgType, UserOrganization,
 };
 
 db_object! {
@@ -39,6 +40,7 @@ db_object! {
         pub password_history: Option<String>,
         pub deleted_at: Option<NaiveDateTime>,
         pub reprompt: Option<i32>,
+        pub archived_at: Option<NaiveDateTime>,
     }
 }
 
@@ -71,21 +73,101 @@ impl Cipher {
             password_history: None,
             deleted_at: None,
             reprompt: None,
+            archived_at: None,
         }
     }
 }
 
 use crate::db::DbConn;
 
-use crate::api::EmptyResult;
+use crate::api::{ApiResult, EmptyResult};
 use crate::error::MapResult;
 
+/// The per-user data needed to serialize many ciphers, like in a sync. It's loaded with a few queries
+/// for the whole vault instead of several queries for every cipher.
+pub struct CipherSyncData {
+    attachments: HashMap<String, Vec<Attachment>>,
+    folders: HashMap<String, String>,
+    favorites: HashSet<String>,
+    collections: HashMap<String, Vec<String>>,
+    user_organizations: HashMap<String, UserOrganization>,
+    user_collections: HashMap<String, CollectionUser>,
+}
+
+impl CipherSyncData {
+    pub fn new(user_uuid: &str, conn: &DbConn) -> Self {
+        let mut collections: HashMap<String, Vec<String>> = HashMap::new();
+        for (cipher_uuid, collection_uuid) in Cipher::get_collections_by_user(user_uuid, conn) {
+            collections.entry(cipher_uuid).or_default().push(collection_uuid);
+        }
+
+        Self {
+            attachments: HashMap::new(),
+            folders: FolderCipher::find_by_user(user_uuid, conn).into_iter().collect(),
+            favorites: Favorite::get_all_cipher_uuid_by_user(user_uuid, conn).into_iter().collect(),
+            collections,
+            user_organizations: UserOrganization::find_by_user(user_uuid, conn)
+                .into_iter()
+                .map(|uo| (uo.org_uuid.clone(), uo))
+                .collect(),
+            user_collections: CollectionUser::find_by_user(user_uuid, conn)
+                .into_iter()
+                .map(|uc| (uc.collection_uuid.clone(), uc))
+                .collect(),
+        }
+    }
+
+    /// Loads the attachments of a chunk of ciphers, replacing the ones of the previous chunk.
+    /// The chunks need to be small enough to stay below the bound parameters limit of SQLite.
+    pub fn load_attachments(&mut self, ciphers: &[&Cipher], conn: &DbConn) {
+        self.attachments.clear();
+        let cipher_uuids = ciphers.iter().map(|c| c.uuid.clone()).collect();
+        for attachment in Attachment::find_by_ciphers(cipher_uuids, conn) {
+            self.attachments.entry(attachment.cipher_uuid.clone()).or_default().push(attachment);
+        }
+    }
+
+    pub fn get_folder(&self, cipher_uuid: &str) -> Option<&String> {
+        self.folders.get(cipher_uuid)
+    }
+
+    pub fn is_favorite(&self, cipher_uuid: &str) -> bool {
+        self.favorites.contains(cipher_uuid)
+    }
+
+    pub fn get_collections(&self, cipher_uuid: &str) -> &[String] {
+        self.collections.get(cipher_uuid).map_or(&[], Vec::as_slice)
+    }
+
+    /// Only valid for the ciphers of the last chunk loaded with `load_attachments`
+    pub fn has_attachments(&self, cipher_uuid: &str) -> bool {
+        self.attachments.contains_key(cipher_uuid)
+    }
+}
+
 /// Database methods
 impl Cipher {
     pub fn to_json(&self, host: &str, user_uuid: &str, conn: &DbConn) -> Value {
+        self.to_json_with_sync_data(host, user_uuid, None, conn)
+    }
+
+    pub fn to_json_with_sync_data(
+        &self,
+        host: &str,
+        user_uuid: &str,
+        sync_data: Option<&CipherSyncData>,
+        conn: &DbConn,
+    ) -> Value {
         use crate::util::format_date;
 
-        let attachments = Attachment::find_by_cipher(&self.uuid, conn);
+        let loaded_attachments;
+        let attachments: &[Attachment] = match sync_data {
+            Some(sync_data) => sync_data.attachments.get(&self.uuid).map_or(&[], Vec::as_slice),
+            None => {
+                loaded_attachments = Attachment::find_by_cipher(&self.uuid, conn);
+                &loaded_attachments
+            }
+        };
         // When there are no attachments use null instead of an empty array
         let attachments_json = if attachments.is_empty() {
             Value::Null
@@ -97,7 +179,11 @@ impl Cipher {
         let password_history_json =
             self.password_history.as_ref().and_then(|s| serde_json::from_str(s).ok()).unwrap_or(Value::Null);
 
-        let (read_only, hide_passwords) = match self.get_access_restrictions(user_uuid, conn) {
+        let access_restrictions = match sync_data {
+            Some(sync_data) => self.get_access_restrictions_from_sync_data(user_uuid, sync_data),
+            None => self.get_access_restrictions(user_uuid, conn),
+        };
+        let (read_only, hide_passwords) = match access_restrictions {
             Some((ro, hp)) => (ro, hp),
             None => {
                 error!("Cipher ownership assertion failure");
@@ -131,6 +217,19 @@ impl Cipher {
         data_json["Notes"] = json!(self.notes);
         data_json["PasswordHistory"] = json!(password_history_json);
 
+        let (folder_uuid, favorite, collection_uuids) = match sync_data {
+            Some(sync_data) => (
+                sync_data.folders.get(&self.uuid).cloned(),
+                sync_data.favorites.contains(&self.uuid),
+                sync_data.collections.get(&self.uuid).cloned().unwrap_or_default(),
+            ),
+            None => (
+                self.get_folder_uuid(user_uuid, conn),
+                self.is_favorite(user_uuid, conn),
+                self.get_collections(user_uuid, conn),
+            ),
+        };
+
         // There are three types of cipher response models in upstream
         // Bitwarden: "cipherMini", "cipher", and "cipherDetails" (in order
         // of increasing level of detail). vaultwarden currently only
@@ -144,8 +243,9 @@ impl Cipher {
             "Type": self.atype,
             "RevisionDate": format_date(&self.updated_at),
             "DeletedDate": self.deleted_at.map_or(Value::Null, |d| Value::String(format_date(&d))),
-            "FolderId": self.get_folder_uuid(user_uuid, conn),
-            "Favorite": self.is_favorite(user_uuid, conn),
+            "ArchivedDate": self.archived_at.map_or(Value::Null, |d| Value::String(format_date(&d))),
+            "FolderId": folder_uuid,
+            "Favorite": favorite,
             "Reprompt": self.reprompt.unwrap_or(RepromptType::None as i32),
             "OrganizationId": self.organization_uuid,
             "Attachments": attachments_json,
@@ -154,7 +254,7 @@ impl Cipher {
             "OrganizationUseTotp": true,
 
             // This field is specific to the cipherDetails type.
-            "CollectionIds": self.get_collections(user_uuid, conn),
+            "CollectionIds": collection_uuids,
 
             "Name": self.name,
             "Notes": self.notes,
@@ -245,52 +345,91 @@ impl Cipher {
     }
 
     pub fn delete(&self, conn: &DbConn) -> EmptyResult {
+        let attachments = conn.transaction(|| self.delete_records(conn))?;
+        Attachment::delete_files(&attachments, conn);
+        Ok(())
+    }
+
+    /// Deletes the cipher and everything linked to it from the database, without touching the attachment files.
+    /// Returns the deleted attachments, whose files have to be removed with `Attachment::delete_files`
+    /// once the surrounding transaction is committed.
+    pub fn delete_records(&self, conn: &DbConn) -> ApiResult<Vec<Attachment>> {
         self.update_users_revision(conn);
 
+        let attachments = Attachment::find_by_cipher(&self.uuid, conn);
+        for attachment in &attachments {
+            attachment.delete_record(conn)?;
+        }
+
         FolderCipher::delete_all_by_cipher(&self.uuid, conn)?;
         CollectionCipher::delete_all_by_cipher(&self.uuid, conn)?;
-        Attachment::delete_all_by_cipher(&self.uuid, conn)?;
         Favorite::delete_all_by_cipher(&self.uuid, conn)?;
+        CipherHistory::delete_all_by_cipher(&self.uuid, conn)?;
+        CipherAccess::delete_all_by_cipher(&self.uuid, conn)?;
 
         db_run! { conn: {
             diesel::delete(ciphers::table.filter(ciphers::uuid.eq(&self.uuid)))
                 .execute(conn)
-                .map_res("Error deleting cipher")
+                .map_res("Error deleting cipher")?;
         }}
+        Ok(attachments)
     }
 
-    pub fn delete_all_by_organization(org_uuid: &str, conn: &DbConn) -> EmptyResult {
+    /// Like `delete_records`, for all the ciphers of the organization
+    pub fn delete_all_by_organization(org_uuid: &str, conn: &DbConn) -> ApiResult<Vec<Attachment>> {
+        let mut attachments = Vec::new();
         for cipher in Self::find_by_org(org_uuid, conn) {
-            cipher.delete(conn)?;
+            attachments.append(&mut cipher.delete_records(conn)?);
         }
-        Ok(())
+        Ok(attachments)
     }
 
-    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+    /// Like `delete_records`, for all the ciphers owned by the user
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> ApiResult<Vec<Attachment>> {
+        let mut attachments = Vec::new();
         for cipher in Self::find_owned_by_user(user_uuid, conn) {
-            cipher.delete(conn)?;
+            attachments.append(&mut cipher.delete_records(conn)?);
         }
-        Ok(())
+        Ok(attachments)
     }
 
-    /// Purge all ciphers that are old enough to be auto-deleted.
-    pub fn purge_trash(conn: &DbConn) {
+    /// Permanently deletes the trashed ciphers past their retention, and returns how many were deleted
+    pub fn purge_trash(conn: &DbConn) -> usize {
+        let now = Utc::now().naive_utc();
+        let mut purged = 0;
         if let Some(auto_delete_days) = CONFIG.trash_auto_delete_days() {
-            let now = Utc::now().naive_utc();
             let dt = now - Duration::days(auto_delete_days);
             for cipher in Self::find_deleted_before(&dt, conn) {
-                cipher.delete(conn).ok();
+                if cipher.delete(conn).is_ok() {
+                    purged += 1;
+                }
+            }
+        }
+
+        // Users can only shorten the instance retention, so their own ciphers need a second pass
+        for user in User::find_with_trash_auto_delete_days(conn) {
+            if let Some(auto_delete_days) = user.get_trash_auto_delete_days() {
+                let dt = now - Duration::days(auto_delete_days);
+                for cipher in Self::find_deleted_before_by_user(&user.uuid, &dt, conn) {
+                    if cipher.delete(conn).is_ok() {
+                        purged += 1;
+                    }
+                }
             }
         }
+        purged
     }
 
     pub fn move_to_folder(&self, folder_uuid: Option<String>, user_uuid: &str, conn: &DbConn) -> EmptyResult {
-        User::update_uuid_revision(user_uuid, conn);
+        let old_folder_uuid = self.get_folder_uuid(user_uuid, conn);
+        if old_folder_uuid == folder_uuid {
+            return Ok(());
+        }
+        User::reset_uuid_sync(user_uuid, conn);
 
-        match (self.get_folder_uuid(user_uuid, conn), folder_uuid) {
-            // No changes
+        match (old_folder_uuid, folder_uuid) {
+            // No changes, already handled above
             (None, None) => Ok(()),
-            (Some(ref old), Some(ref new)) if old == new => Ok(()),
 
             // Add to folder
             (None, Some(new)) => FolderCipher::new(&new, &self.uuid).save(conn),
@@ -376,6 +515,30 @@ impl Cipher {
         }}
     }
 
+    /// Same as `get_access_restrictions`, using the data already loaded for a sync.
+    fn get_access_restrictions_from_sync_data(
+        &self,
+        user_uuid: &str,
+        sync_data: &CipherSyncData,
+    ) -> Option<(bool, bool)> {
+        let full_access = match self.organization_uuid {
+            Some(ref org_uuid) => sync_data.user_organizations.get(org_uuid).map_or(false, |uo| uo.has_full_access()),
+            None => false,
+        };
+        if self.is_owned_by_user(user_uuid) || full_access {
+            return Some((false, false));
+        }
+
+        let mut restrictions = None;
+        for collection_uuid in sync_data.collections.get(&self.uuid).into_iter().flatten() {
+            if let Some(col_user) = sync_data.user_collections.get(collection_uuid) {
+                let (read_only, hide_passwords) = restrictions.unwrap_or((false, false));
+                restrictions = Some((read_only | col_user.read_only, hide_passwords | col_user.hide_passwords));
+            }
+        }
+        restrictions
+    }
+
     pub fn is_write_accessible_to_user(&self, user_uuid: &str, conn: &DbConn) -> bool {
         match self.get_access_restrictions(user_uuid, conn) {
             Some((read_only, _hide_passwords)) => !read_only,
@@ -485,6 +648,12 @@ impl Cipher {
         }}
     }
 
+    pub fn count(conn: &DbConn) -> i64 {
+        db_run! {conn: {
+            ciphers::table.count().first::<i64>(conn).ok().unwrap_or(0)
+        }}
+    }
+
     pub fn count_owned_by_user(user_uuid: &str, conn: &DbConn) -> i64 {
         db_run! {conn: {
             ciphers::table
@@ -496,6 +665,18 @@ impl Cipher {
         }}
     }
 
+    /// Returns the last time one of the ciphers owned by the user was modified
+    pub fn last_updated_owned_by_user(user_uuid: &str, conn: &DbConn) -> Option<NaiveDateTime> {
+        db_run! {conn: {
+            ciphers::table
+                .filter(ciphers::user_uuid.eq(user_uuid))
+                .select(diesel::dsl::max(ciphers::updated_at))
+                .first::<Option<NaiveDateTime>>(conn)
+                .ok()
+                .flatten()
+        }}
+    }
+
     pub fn find_by_org(org_uuid: &str, conn: &DbConn) -> Vec<Self> {
         db_run! {conn: {
             ciphers::table
@@ -533,6 +714,43 @@ impl Cipher {
         }}
     }
 
+    pub fn find_deleted_before_by_user(user_uuid: &str, dt: &NaiveDateTime, conn: &DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            ciphers::table
+                .filter(ciphers::user_uuid.eq(user_uuid))
+                .filter(ciphers::deleted_at.lt(dt))
+                .load::<CipherDb>(conn).expect("Error loading ciphers").from_db()
+        }}
+    }
+
+    /// Returns the (cipher uuid, collection uuid) pairs of all the collections visible to the user,
+    /// like `get_collections` does for a single cipher.
+    pub fn get_collections_by_user(user_id: &str, conn: &DbConn) -> Vec<(String, String)> {
+        db_run! {conn: {
+            ciphers_collections::table
+            .inner_join(collections::table.on(
+                collections::uuid.eq(ciphers_collections::collection_uuid)
+            ))
+            .inner_join(users_organizations::table.on(
+                users_organizations::org_uuid.eq(collections::org_uuid).and(
+                    users_organizations::user_uuid.eq(user_id)
+                )
+            ))
+            .left_join(users_collections::table.on(
+                users_collections::collection_uuid.eq(ciphers_collections::collection_uuid).and(
+                    users_collections::user_uuid.eq(user_id)
+                )
+            ))
+            .filter(users_collections::user_uuid.eq(user_id).or( // User has access to collection
+                users_organizations::access_all.eq(true).or( // User has access all
+                    users_organizations::atype.le(UserOrgType::Admin as i32) // User is admin or owner
+                )
+            ))
+            .select((ciphers_collections::cipher_uuid, ciphers_collections::collection_uuid))
+            .load::<(String, String)>(conn).unwrap_or_default()
+        }}
+    }
+
     pub fn get_collections(&self, user_id: &str, conn: &DbConn) -> Vec<String> {
         db_run! {conn: {
             ciphers_collections::table
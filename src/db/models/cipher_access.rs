@@ -0,0 +1,114 @@
+use chrono::{NaiveDateTime, Utc};
+
+use crate::CONFIG;
+
+use super::{Cipher, User};
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, Associations, AsChangeset)]
+    #[table_name = "ciphers_access"]
+    #[belongs_to(Cipher, foreign_key = "cipher_uuid")]
+    #[belongs_to(User, foreign_key = "user_uuid")]
+    #[primary_key(cipher_uuid, user_uuid)]
+    pub struct CipherAccess {
+        pub cipher_uuid: String,
+        pub user_uuid: String,
+        pub access_count: i32,
+        pub last_access_at: NaiveDateTime,
+    }
+}
+
+/// Local methods
+impl CipherAccess {
+    pub fn new(cipher_uuid: String, user_uuid: String) -> Self {
+        Self {
+            cipher_uuid,
+            user_uuid,
+            access_count: 0,
+            last_access_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl CipherAccess {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(ciphers_access::table)
+                    .values(CipherAccessDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving cipher access")
+            }
+            postgresql {
+                let value = CipherAccessDb::to_db(self);
+                diesel::insert_into(ciphers_access::table)
+                    .values(&value)
+                    .on_conflict((ciphers_access::cipher_uuid, ciphers_access::user_uuid))
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving cipher access")
+            }
+        }
+    }
+
+    /// Records that the user read the cipher. Nothing is stored unless the access tracking is enabled,
+    /// and failures are only logged so they never prevent the access itself.
+    pub fn record(cipher_uuid: &str, user_uuid: &str, conn: &DbConn) {
+        if !CONFIG.cipher_access_tracking() {
+            return;
+        }
+
+        let mut access = Self::find_by_cipher_and_user(cipher_uuid, user_uuid, conn)
+            .unwrap_or_else(|| Self::new(cipher_uuid.to_string(), user_uuid.to_string()));
+        access.access_count = access.access_count.saturating_add(1);
+        access.last_access_at = Utc::now().naive_utc();
+
+        if let Err(e) = access.save(conn) {
+            warn!("Failed to record the access to cipher {}: {:#?}", cipher_uuid, e);
+        }
+    }
+
+    pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(ciphers_access::table.filter(ciphers_access::cipher_uuid.eq(cipher_uuid)))
+                .execute(conn)
+                .map_res("Error deleting cipher accesses")
+        }}
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(ciphers_access::table.filter(ciphers_access::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting cipher accesses")
+        }}
+    }
+
+    pub fn find_by_cipher_and_user(cipher_uuid: &str, user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            ciphers_access::table
+                .filter(ciphers_access::cipher_uuid.eq(cipher_uuid))
+                .filter(ciphers_access::user_uuid.eq(user_uuid))
+                .first::<CipherAccessDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            ciphers_access::table
+                .filter(ciphers_access::user_uuid.eq(user_uuid))
+                .load::<CipherAccessDb>(conn)
+                .expect("Error loading cipher accesses")
+                .from_db()
+        }}
+    }
+}
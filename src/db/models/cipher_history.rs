@@ -0,0 +1,136 @@
+use chrono::NaiveDateTime;
+
+use crate::CONFIG;
+
+use super::Cipher;
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, Associations, AsChangeset)]
+    #[table_name = "ciphers_history"]
+    #[changeset_options(treat_none_as_null="true")]
+    #[belongs_to(Cipher, foreign_key = "cipher_uuid")]
+    #[primary_key(uuid)]
+    pub struct CipherHistory {
+        pub uuid: String,
+        pub cipher_uuid: String,
+        pub revision_date: NaiveDateTime,
+
+        // Encrypted content of the cipher at that revision
+        pub atype: i32,
+        pub name: String,
+        pub notes: Option<String>,
+        pub fields: Option<String>,
+        pub data: String,
+        pub password_history: Option<String>,
+        pub reprompt: Option<i32>,
+    }
+}
+
+/// Local methods
+impl CipherHistory {
+    /// Takes a snapshot of the current content of the cipher
+    pub fn new(cipher: &Cipher) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            cipher_uuid: cipher.uuid.clone(),
+            revision_date: cipher.updated_at,
+
+            atype: cipher.atype,
+            name: cipher.name.clone(),
+            notes: cipher.notes.clone(),
+            fields: cipher.fields.clone(),
+            data: cipher.data.clone(),
+            password_history: cipher.password_history.clone(),
+            reprompt: cipher.reprompt,
+        }
+    }
+
+    /// Returns whether the content of the cipher is still the one of this revision
+    pub fn matches(&self, cipher: &Cipher) -> bool {
+        self.atype == cipher.atype
+            && self.name == cipher.name
+            && self.notes == cipher.notes
+            && self.fields == cipher.fields
+            && self.data == cipher.data
+            && self.password_history == cipher.password_history
+            && self.reprompt == cipher.reprompt
+    }
+
+    /// Replaces the content of the cipher with the one of this revision
+    pub fn apply_to(&self, cipher: &mut Cipher) {
+        cipher.atype = self.atype;
+        cipher.name = self.name.clone();
+        cipher.notes = self.notes.clone();
+        cipher.fields = self.fields.clone();
+        cipher.data = self.data.clone();
+        cipher.password_history = self.password_history.clone();
+        cipher.reprompt = self.reprompt;
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl CipherHistory {
+    /// Stores the revision, and removes the oldest ones of the cipher over the configured limit
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        let max_revisions = CONFIG.cipher_history_max_revisions() as usize;
+        if max_revisions == 0 {
+            return Ok(());
+        }
+
+        db_run! { conn: {
+            diesel::insert_into(ciphers_history::table)
+                .values(CipherHistoryDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving cipher history")?;
+        }}
+
+        for revision in Self::find_by_cipher(&self.cipher_uuid, conn).iter().skip(max_revisions) {
+            revision.delete(conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(ciphers_history::table.filter(ciphers_history::uuid.eq(&self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting cipher history")
+        }}
+    }
+
+    pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(ciphers_history::table.filter(ciphers_history::cipher_uuid.eq(cipher_uuid)))
+                .execute(conn)
+                .map_res("Error deleting cipher history")
+        }}
+    }
+
+    /// Returns the revisions of the cipher, newest first
+    pub fn find_by_cipher(cipher_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            ciphers_history::table
+                .filter(ciphers_history::cipher_uuid.eq(cipher_uuid))
+                .order(ciphers_history::revision_date.desc())
+                .load::<CipherHistoryDb>(conn)
+                .expect("Error loading cipher history")
+                .from_db()
+        }}
+    }
+
+    pub fn find_by_uuid_and_cipher(uuid: &str, cipher_uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            ciphers_history::table
+                .filter(ciphers_history::uuid.eq(uuid))
+                .filter(ciphers_history::cipher_uuid.eq(cipher_uuid))
+                .first::<CipherHistoryDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+}
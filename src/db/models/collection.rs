@@ -5,12 +5,14 @@ use super::{Cipher, Organization, User, UserOrgStatus, UserOrgType, UserOrganiza
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, Associations, AsChangeset)]
     #[table_name = "collections"]
+    #[changeset_options(treat_none_as_null="true")]
     #[belongs_to(Organization, foreign_key = "org_uuid")]
     #[primary_key(uuid)]
     pub struct Collection {
         pub uuid: String,
         pub org_uuid: String,
         pub name: String,
+        pub external_id: Option<String>,
     }
 
     #[derive(Identifiable, Queryable, Insertable, Associations)]
@@ -44,12 +46,13 @@ impl Collection {
 
             org_uuid,
             name,
+            external_id: None,
         }
     }
 
     pub fn to_json(&self) -> Value {
         json!({
-            "ExternalId": null, // Not support by us
+            "ExternalId": self.external_id,
             "Id": self.uuid,
             "OrganizationId": self.org_uuid,
             "Name": self.name,
@@ -128,7 +131,7 @@ impl Collection {
 
     pub fn update_users_revision(&self, conn: &DbConn) {
         UserOrganization::find_by_collection_and_org(&self.uuid, &self.org_uuid, conn).iter().for_each(|user_org| {
-            User::update_uuid_revision(&user_org.user_uuid, conn);
+            User::reset_uuid_sync(&user_org.user_uuid, conn);
         });
     }
 
@@ -286,7 +289,7 @@ impl CollectionUser {
         hide_passwords: bool,
         conn: &DbConn,
     ) -> EmptyResult {
-        User::update_uuid_revision(user_uuid, conn);
+        User::reset_uuid_sync(user_uuid, conn);
 
         db_run! { conn:
             sqlite, mysql {
@@ -338,7 +341,7 @@ impl CollectionUser {
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
-        User::update_uuid_revision(&self.user_uuid, conn);
+        User::reset_uuid_sync(&self.user_uuid, conn);
 
         db_run! { conn: {
             diesel::delete(
@@ -362,6 +365,17 @@ impl CollectionUser {
         }}
     }
 
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            users_collections::table
+                .filter(users_collections::user_uuid.eq(user_uuid))
+                .select(users_collections::all_columns)
+                .load::<CollectionUserDb>(conn)
+                .expect("Error loading users_collections")
+                .from_db()
+        }}
+    }
+
     pub fn find_by_collection_and_user(collection_uuid: &str, user_uuid: &str, conn: &DbConn) -> Option<Self> {
         db_run! { conn: {
             users_collections::table
@@ -376,7 +390,7 @@ impl CollectionUser {
 
     pub fn delete_all_by_collection(collection_uuid: &str, conn: &DbConn) -> EmptyResult {
         CollectionUser::find_by_collection(collection_uuid, conn).iter().for_each(|collection| {
-            User::update_uuid_revision(&collection.user_uuid, conn);
+            User::reset_uuid_sync(&collection.user_uuid, conn);
         });
 
         db_run! { conn: {
@@ -386,6 +400,24 @@ impl CollectionUser {
         }}
     }
 
+    /// Returns the accesses to collections of users which aren't members of the collection's organization anymore,
+    /// or to collections which don't exist anymore
+    pub fn find_dangling(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            users_collections::table
+                .left_join(collections::table.on(collections::uuid.eq(users_collections::collection_uuid)))
+                .left_join(users_organizations::table.on(
+                    users_organizations::org_uuid.eq(collections::org_uuid)
+                        .and(users_organizations::user_uuid.eq(users_collections::user_uuid))
+                ))
+                .filter(users_organizations::uuid.is_null())
+                .select(users_collections::all_columns)
+                .load::<CollectionUserDb>(conn)
+                .expect("Error loading users_collections")
+                .from_db()
+        }}
+    }
+
     pub fn delete_all_by_user_and_org(user_uuid: &str, org_uuid: &str, conn: &DbConn) -> EmptyResult {
         let collectionusers = Self::find_by_organization_and_user_uuid(org_uuid, user_uuid, conn);
 
@@ -61,6 +61,33 @@ impl Device {
         self.twofactor_remember = None;
     }
 
+    /// Name of the device type, from the `DeviceType` enum of the Bitwarden server
+    pub fn type_name(&self) -> &'static str {
+        match self.atype {
+            0 => "Android",
+            1 => "iOS",
+            2 => "Chrome Extension",
+            3 => "Firefox Extension",
+            4 => "Opera Extension",
+            5 => "Edge Extension",
+            6 => "Windows Desktop",
+            7 => "macOS Desktop",
+            8 => "Linux Desktop",
+            9 => "Chrome",
+            10 => "Firefox",
+            11 => "Opera",
+            12 => "Edge",
+            13 => "Internet Explorer",
+            15 => "Android (Amazon)",
+            16 => "UWP",
+            17 => "Safari",
+            18 => "Vivaldi",
+            19 => "Vivaldi Extension",
+            20 => "Safari Extension",
+            _ => "Unknown",
+        }
+    }
+
     pub fn refresh_tokens(&mut self, user: &super::User, orgs: Vec<super::UserOrganization>) -> (String, i64) {
         // If there is no refresh token, we create one
         if self.refresh_token.is_empty() {
@@ -159,6 +186,17 @@ impl Device {
         }}
     }
 
+    pub fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            devices::table
+                .filter(devices::uuid.eq(uuid))
+                .filter(devices::user_uuid.eq(user_uuid))
+                .first::<DeviceDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
     pub fn find_by_refresh_token(refresh_token: &str, conn: &DbConn) -> Option<Self> {
         db_run! { conn: {
             devices::table
@@ -179,6 +217,17 @@ impl Device {
         }}
     }
 
+    /// Returns the devices which haven't refreshed their session since `dt`
+    pub fn find_inactive_since(dt: &NaiveDateTime, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            devices::table
+                .filter(devices::updated_at.lt(dt))
+                .load::<DeviceDb>(conn)
+                .expect("Error loading devices")
+                .from_db()
+        }}
+    }
+
     pub fn find_latest_active_by_user(user_uuid: &str, conn: &DbConn) -> Option<Self> {
         db_run! { conn: {
             devices::table
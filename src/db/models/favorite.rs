@@ -35,7 +35,7 @@ impl Favorite {
         let (old, new) = (Self::is_favorite(cipher_uuid, user_uuid, conn), favorite);
         match (old, new) {
             (false, true) => {
-                User::update_uuid_revision(user_uuid, conn);
+                User::reset_uuid_sync(user_uuid, conn);
                 db_run! { conn: {
                 diesel::insert_into(favorites::table)
                     .values((
@@ -47,7 +47,7 @@ impl Favorite {
                 }}
             }
             (true, false) => {
-                User::update_uuid_revision(user_uuid, conn);
+                User::reset_uuid_sync(user_uuid, conn);
                 db_run! { conn: {
                     diesel::delete(
                         favorites::table
@@ -63,6 +63,17 @@ impl Favorite {
         }
     }
 
+    // Returns the uuids of all the ciphers that are a favorite of the specified user.
+    pub fn get_all_cipher_uuid_by_user(user_uuid: &str, conn: &DbConn) -> Vec<String> {
+        db_run! { conn: {
+            favorites::table
+                .filter(favorites::user_uuid.eq(user_uuid))
+                .select(favorites::cipher_uuid)
+                .load::<String>(conn)
+                .unwrap_or_default()
+        }}
+    }
+
     // Delete all favorite entries associated with the specified cipher.
     pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
         db_run! { conn: {
@@ -72,6 +83,23 @@ impl Favorite {
         }}
     }
 
+    // Delete the favorite entries of the specified user for the ciphers of the specified organization.
+    pub fn delete_all_by_user_and_org(user_uuid: &str, org_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            let org_ciphers = ciphers::table
+                .filter(ciphers::organization_uuid.eq(org_uuid))
+                .select(ciphers::uuid);
+
+            diesel::delete(
+                favorites::table
+                    .filter(favorites::user_uuid.eq(user_uuid))
+                    .filter(favorites::cipher_uuid.eq_any(org_ciphers))
+            )
+            .execute(conn)
+            .map_res("Error removing favorites by user and organization")
+        }}
+    }
+
     // Delete all favorite entries associated with the specified user.
     pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
         db_run! { conn: {
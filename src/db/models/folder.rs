@@ -106,7 +106,8 @@ impl Folder {
     }
 
     pub fn delete(&self, conn: &DbConn) -> EmptyResult {
-        User::update_uuid_revision(&self.user_uuid, conn);
+        // The ciphers of the folder are moved out of it
+        User::reset_uuid_sync(&self.user_uuid, conn);
         FolderCipher::delete_all_by_folder(&self.uuid, conn)?;
 
         db_run! { conn: {
@@ -142,6 +143,17 @@ impl Folder {
                 .from_db()
         }}
     }
+
+    pub fn count_by_user(user_uuid: &str, conn: &DbConn) -> i64 {
+        db_run! { conn: {
+            folders::table
+                .filter(folders::user_uuid.eq(user_uuid))
+                .count()
+                .first::<i64>(conn)
+                .ok()
+                .unwrap_or(0)
+        }}
+    }
 }
 
 impl FolderCipher {
@@ -206,6 +218,18 @@ impl FolderCipher {
         }}
     }
 
+    /// Returns the (cipher uuid, folder uuid) pairs of all the folders of the user
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<(String, String)> {
+        db_run! { conn: {
+            folders_ciphers::table
+                .inner_join(folders::table)
+                .filter(folders::user_uuid.eq(user_uuid))
+                .select((folders_ciphers::cipher_uuid, folders_ciphers::folder_uuid))
+                .load::<(String, String)>(conn)
+                .unwrap_or_default()
+        }}
+    }
+
     pub fn find_by_folder(folder_uuid: &str, conn: &DbConn) -> Vec<Self> {
         db_run! { conn: {
             folders_ciphers::table
@@ -0,0 +1,108 @@
+use chrono::{NaiveDateTime, Utc};
+
+use super::User;
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, Associations, AsChangeset)]
+    #[table_name = "users_login_ips"]
+    #[belongs_to(User, foreign_key = "user_uuid")]
+    #[primary_key(user_uuid, ip_network)]
+    pub struct LoginIp {
+        pub user_uuid: String,
+        pub ip_network: String,
+        pub first_seen_at: NaiveDateTime,
+        pub last_seen_at: NaiveDateTime,
+    }
+}
+
+/// Local methods
+impl LoginIp {
+    pub fn new(user_uuid: String, ip_network: String) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            user_uuid,
+            ip_network,
+            first_seen_at: now,
+            last_seen_at: now,
+        }
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl LoginIp {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(users_login_ips::table)
+                    .values(LoginIpDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving login IP")
+            }
+            postgresql {
+                let value = LoginIpDb::to_db(self);
+                diesel::insert_into(users_login_ips::table)
+                    .values(&value)
+                    .on_conflict((users_login_ips::user_uuid, users_login_ips::ip_network))
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving login IP")
+            }
+        }
+    }
+
+    /// Records a login of the user from the IP network, and returns whether it's the first one from that network.
+    /// The first network recorded for a user isn't considered new, so the users which logged in before
+    /// the networks were recorded don't get an alert for their usual one
+    pub fn record(user_uuid: &str, ip_network: &str, conn: &DbConn) -> bool {
+        let (mut login_ip, is_new) = match Self::find_by_user_and_network(user_uuid, ip_network, conn) {
+            Some(login_ip) => (login_ip, false),
+            None => {
+                let is_new = Self::count_by_user(user_uuid, conn) > 0;
+                (Self::new(user_uuid.to_string(), ip_network.to_string()), is_new)
+            }
+        };
+        login_ip.last_seen_at = Utc::now().naive_utc();
+
+        if let Err(e) = login_ip.save(conn) {
+            warn!("Failed to record the login IP of user {}: {:#?}", user_uuid, e);
+        }
+        is_new
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(users_login_ips::table.filter(users_login_ips::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting login IPs")
+        }}
+    }
+
+    pub fn find_by_user_and_network(user_uuid: &str, ip_network: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            users_login_ips::table
+                .filter(users_login_ips::user_uuid.eq(user_uuid))
+                .filter(users_login_ips::ip_network.eq(ip_network))
+                .first::<LoginIpDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn count_by_user(user_uuid: &str, conn: &DbConn) -> i64 {
+        db_run! { conn: {
+            users_login_ips::table
+                .filter(users_login_ips::user_uuid.eq(user_uuid))
+                .count()
+                .first::<i64>(conn)
+                .ok()
+                .unwrap_or(0)
+        }}
+    }
+}
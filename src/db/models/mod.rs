@@ -1,23 +1,33 @@
 mod attachment;
 mod cipher;
+mod cipher_access;
+mod cipher_history;
 mod collection;
 mod device;
 mod favorite;
 mod folder;
+mod login_ip;
 mod org_policy;
 mod organization;
+mod queued_mail;
 mod send;
+mod stat;
 mod two_factor;
 mod user;
 
 pub use self::attachment::Attachment;
-pub use self::cipher::Cipher;
+pub use self::cipher::{Cipher, CipherSyncData};
+pub use self::cipher_access::CipherAccess;
+pub use self::cipher_history::CipherHistory;
 pub use self::collection::{Collection, CollectionCipher, CollectionUser};
 pub use self::device::Device;
 pub use self::favorite::Favorite;
 pub use self::folder::{Folder, FolderCipher};
+pub use self::login_ip::LoginIp;
 pub use self::org_policy::{OrgPolicy, OrgPolicyType};
 pub use self::organization::{Organization, UserOrgStatus, UserOrgType, UserOrganization};
+pub use self::queued_mail::QueuedMail;
 pub use self::send::{Send, SendType};
+pub use self::stat::Stat;
 pub use self::two_factor::{TwoFactor, TwoFactorType};
 pub use self::user::{Invitation, User, UserStampException};
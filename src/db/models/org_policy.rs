@@ -32,6 +32,8 @@ pub enum OrgPolicyType {
     PersonalOwnership = 5,
     DisableSend = 6,
     SendOptions = 7,
+    // ResetPassword = 8, // Not currently supported.
+    MaximumVaultTimeout = 9,
 }
 
 // https://github.com/bitwarden/server/blob/master/src/Core/Models/Data/SendOptionsPolicyData.cs
@@ -41,6 +43,27 @@ pub struct SendOptionsPolicyData {
     pub DisableHideEmail: bool,
 }
 
+// https://github.com/bitwarden/server/blob/master/src/Core/Models/Data/MaximumVaultTimeoutPolicyData.cs
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+pub struct MaximumVaultTimeoutPolicyData {
+    pub Minutes: i32,
+    pub Action: Option<String>,
+}
+
+impl MaximumVaultTimeoutPolicyData {
+    /// The timeout is stored in minutes, so this caps it at a year.
+    const MAX_MINUTES: i32 = 525_600;
+
+    fn is_valid(&self) -> bool {
+        let valid_action = match self.Action.as_deref() {
+            None | Some("lock") | Some("logOut") => true,
+            Some(_) => false,
+        };
+        self.Minutes > 0 && self.Minutes <= Self::MAX_MINUTES && valid_action
+    }
+}
+
 /// Local methods
 impl OrgPolicy {
     pub fn new(org_uuid: String, atype: OrgPolicyType, data: String) -> Self {
@@ -57,6 +80,23 @@ impl OrgPolicy {
         self.atype == policy_type as i32
     }
 
+    /// Returns false if the policy data sent by a client can't be parsed for one of the
+    /// policy types whose options are enforced by the server.
+    pub fn is_valid_data(policy_type: OrgPolicyType, data: &Value) -> bool {
+        match policy_type {
+            OrgPolicyType::SendOptions => {
+                serde_json::from_value::<UpCase<SendOptionsPolicyData>>(data.clone()).is_ok()
+            }
+            OrgPolicyType::MaximumVaultTimeout => {
+                match serde_json::from_value::<UpCase<MaximumVaultTimeoutPolicyData>>(data.clone()) {
+                    Ok(timeout) => timeout.data.is_valid(),
+                    Err(_) => false,
+                }
+            }
+            _ => true,
+        }
+    }
+
     pub fn to_json(&self) -> Value {
         let data_json: Value = serde_json::from_str(&self.data).unwrap_or(Value::Null);
         json!({
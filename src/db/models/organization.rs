@@ -1,21 +1,27 @@
+use chrono::{NaiveDateTime, Utc};
 use num_traits::FromPrimitive;
 use serde_json::Value;
 use std::cmp::Ordering;
 
-use super::{CollectionUser, OrgPolicy, User};
+use super::{CollectionUser, Favorite, OrgPolicy, User};
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
     #[table_name = "organizations"]
+    #[changeset_options(treat_none_as_null="true")]
     #[primary_key(uuid)]
     pub struct Organization {
         pub uuid: String,
         pub name: String,
         pub billing_email: String,
+        pub email_message: Option<String>,
+        // Unknown for the organizations created before it was stored
+        pub created_at: Option<NaiveDateTime>,
     }
 
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
     #[table_name = "users_organizations"]
+    #[changeset_options(treat_none_as_null="true")]
     #[primary_key(uuid)]
     pub struct UserOrganization {
         pub uuid: String,
@@ -26,6 +32,7 @@ db_object! {
         pub akey: String,
         pub status: i32,
         pub atype: i32,
+        pub external_id: Option<String>,
     }
 }
 
@@ -128,6 +135,8 @@ impl Organization {
 
             name,
             billing_email,
+            email_message: None,
+            created_at: Some(Utc::now().naive_utc()),
         }
     }
 
@@ -177,6 +186,7 @@ impl UserOrganization {
             akey: String::new(),
             status: UserOrgStatus::Accepted as i32,
             atype: UserOrgType::User as i32,
+            external_id: None,
         }
     }
 }
@@ -226,18 +236,24 @@ impl Organization {
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
-        use super::{Cipher, Collection};
+        use super::{Attachment, Cipher, Collection};
 
-        Cipher::delete_all_by_organization(&self.uuid, conn)?;
-        Collection::delete_all_by_organization(&self.uuid, conn)?;
-        UserOrganization::delete_all_by_organization(&self.uuid, conn)?;
-        OrgPolicy::delete_all_by_organization(&self.uuid, conn)?;
+        let attachments = conn.transaction(|| {
+            let attachments = Cipher::delete_all_by_organization(&self.uuid, conn)?;
+            Collection::delete_all_by_organization(&self.uuid, conn)?;
+            UserOrganization::delete_all_by_organization(&self.uuid, conn)?;
+            OrgPolicy::delete_all_by_organization(&self.uuid, conn)?;
 
-        db_run! { conn: {
-            diesel::delete(organizations::table.filter(organizations::uuid.eq(self.uuid)))
-                .execute(conn)
-                .map_res("Error saving organization")
-        }}
+            db_run! { conn: {
+                diesel::delete(organizations::table.filter(organizations::uuid.eq(&self.uuid)))
+                    .execute(conn)
+                    .map_res("Error saving organization")?;
+            }}
+            Ok(attachments)
+        })?;
+
+        Attachment::delete_files(&attachments, conn);
+        Ok(())
     }
 
     pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
@@ -323,6 +339,7 @@ impl UserOrganization {
             "Status": self.status,
             "Type": self.atype,
             "AccessAll": self.access_all,
+            "ExternalId": self.external_id,
 
             "Object": "organizationUserUserDetails",
         })
@@ -360,13 +377,14 @@ impl UserOrganization {
             "Status": self.status,
             "Type": self.atype,
             "AccessAll": self.access_all,
+            "ExternalId": self.external_id,
             "Collections": coll_uuids,
 
             "Object": "organizationUserDetails",
         })
     }
     pub fn save(&self, conn: &DbConn) -> EmptyResult {
-        User::update_uuid_revision(&self.user_uuid, conn);
+        User::reset_uuid_sync(&self.user_uuid, conn);
 
         db_run! { conn:
             sqlite, mysql {
@@ -400,9 +418,10 @@ impl UserOrganization {
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
-        User::update_uuid_revision(&self.user_uuid, conn);
+        User::reset_uuid_sync(&self.user_uuid, conn);
 
         CollectionUser::delete_all_by_user_and_org(&self.user_uuid, &self.org_uuid, conn)?;
+        Favorite::delete_all_by_user_and_org(&self.user_uuid, &self.org_uuid, conn)?;
 
         db_run! { conn: {
             diesel::delete(users_organizations::table.filter(users_organizations::uuid.eq(self.uuid)))
@@ -443,6 +462,14 @@ impl UserOrganization {
         self.atype == user_type as i32
     }
 
+    /// Tests whether this is the only confirmed owner left in the organization,
+    /// in which case it can't be removed or lose its owner permissions.
+    pub fn is_last_confirmed_owner(&self, conn: &DbConn) -> bool {
+        self.has_type(UserOrgType::Owner)
+            && self.has_status(UserOrgStatus::Confirmed)
+            && UserOrganization::count_confirmed_by_org_and_type(&self.org_uuid, UserOrgType::Owner as i32, conn) <= 1
+    }
+
     pub fn has_full_access(&self) -> bool {
         (self.access_all || self.atype >= UserOrgType::Admin) && self.has_status(UserOrgStatus::Confirmed)
     }
@@ -515,6 +542,19 @@ impl UserOrganization {
         }}
     }
 
+    pub fn count_confirmed_by_org_and_type(org_uuid: &str, atype: i32, conn: &DbConn) -> i64 {
+        db_run! { conn: {
+            users_organizations::table
+                .filter(users_organizations::org_uuid.eq(org_uuid))
+                .filter(users_organizations::atype.eq(atype))
+                .filter(users_organizations::status.eq(UserOrgStatus::Confirmed as i32))
+                .count()
+                .first::<i64>(conn)
+                .ok()
+                .unwrap_or(0)
+        }}
+    }
+
     pub fn find_by_org_and_type(org_uuid: &str, atype: i32, conn: &DbConn) -> Vec<Self> {
         db_run! { conn: {
             users_organizations::table
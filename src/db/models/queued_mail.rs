@@ -0,0 +1,176 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::CONFIG;
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[table_name = "mail_queue"]
+    #[changeset_options(treat_none_as_null="true")]
+    #[primary_key(uuid)]
+    pub struct QueuedMail {
+        pub uuid: String,
+        pub address: String,
+        pub subject: String,
+        pub body_html: String,
+        pub body_text: String,
+        pub attempts: i32,
+        pub next_attempt_at: NaiveDateTime,
+        pub last_error: Option<String>,
+        pub failed: bool,
+        pub created_at: NaiveDateTime,
+        // The emails with tokens or codes are removed from the queue once these aren't valid anymore
+        pub expires_at: Option<NaiveDateTime>,
+    }
+}
+
+/// Longest wait between two attempts to send an email
+const MAX_RETRY_DELAY_MINUTES: i64 = 6 * 60;
+
+/// Local methods
+impl QueuedMail {
+    pub fn new(
+        address: String,
+        subject: String,
+        body_html: String,
+        body_text: String,
+        expires_at: Option<NaiveDateTime>,
+    ) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            uuid: crate::util::get_uuid(),
+            address,
+            subject,
+            body_html,
+            body_text,
+            attempts: 0,
+            // The first attempt is made right away outside of the queue job, this avoids both sending it at once
+            next_attempt_at: now + Duration::minutes(1),
+            last_error: None,
+            failed: false,
+            created_at: now,
+            expires_at,
+        }
+    }
+
+    /// Records a failed attempt, and schedules the next one with an exponential backoff,
+    /// or marks the email as failed once it reached the maximum number of attempts
+    pub fn record_failure(&mut self, error: String) {
+        self.attempts += 1;
+        self.last_error = Some(error);
+
+        if self.attempts as u32 >= CONFIG.mail_queue_max_attempts() {
+            self.failed = true;
+        } else {
+            let delay = 2i64.saturating_pow(self.attempts as u32 - 1).min(MAX_RETRY_DELAY_MINUTES);
+            self.next_attempt_at = Utc::now().naive_utc() + Duration::minutes(delay);
+        }
+    }
+
+    /// Puts a failed email back in the queue, to be sent on the next run of the queue job
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.failed = false;
+        self.next_attempt_at = Utc::now().naive_utc();
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_naive_datetime_local;
+        let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
+
+        json!({
+            "uuid": self.uuid,
+            "address": self.address,
+            "subject": self.subject,
+            "attempts": self.attempts,
+            "next_attempt_at": format_naive_datetime_local(&self.next_attempt_at, dt_fmt),
+            "last_error": self.last_error,
+            "failed": self.failed,
+            "created_at": format_naive_datetime_local(&self.created_at, dt_fmt),
+            "expires_at": self.expires_at.map(|e| format_naive_datetime_local(&e, dt_fmt)),
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl QueuedMail {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(mail_queue::table)
+                    .values(QueuedMailDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving queued email")
+            }
+            postgresql {
+                let value = QueuedMailDb::to_db(self);
+                diesel::insert_into(mail_queue::table)
+                    .values(&value)
+                    .on_conflict(mail_queue::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving queued email")
+            }
+        }
+    }
+
+    pub fn delete(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(mail_queue::table.filter(mail_queue::uuid.eq(&self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting queued email")
+        }}
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            mail_queue::table
+                .filter(mail_queue::uuid.eq(uuid))
+                .first::<QueuedMailDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    /// Returns the emails which are due for a new attempt and haven't expired
+    pub fn find_due(conn: &DbConn) -> Vec<Self> {
+        let now = Utc::now().naive_utc();
+        db_run! { conn: {
+            mail_queue::table
+                .filter(mail_queue::failed.eq(false))
+                .filter(mail_queue::next_attempt_at.le(now))
+                .filter(mail_queue::expires_at.is_null().or(mail_queue::expires_at.gt(now)))
+                .order(mail_queue::created_at.asc())
+                .load::<QueuedMailDb>(conn)
+                .expect("Error loading queued emails")
+                .from_db()
+        }}
+    }
+
+    pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            mail_queue::table
+                .order(mail_queue::created_at.desc())
+                .load::<QueuedMailDb>(conn)
+                .expect("Error loading queued emails")
+                .from_db()
+        }}
+    }
+
+    /// Removes the emails whose tokens or codes aren't valid anymore, including the failed ones
+    pub fn delete_expired(conn: &DbConn) -> EmptyResult {
+        let now = Utc::now().naive_utc();
+        db_run! { conn: {
+            diesel::delete(mail_queue::table.filter(mail_queue::expires_at.le(now)))
+                .execute(conn)
+                .map_res("Error deleting expired queued emails")
+        }}
+    }
+}
@@ -1,6 +1,10 @@
+use std::convert::TryFrom;
+
 use chrono::{NaiveDateTime, Utc};
 use serde_json::Value;
 
+use crate::CONFIG;
+
 use super::{Organization, User};
 
 db_object! {
@@ -37,6 +41,9 @@ db_object! {
 
         pub disabled: bool,
         pub hide_email: Option<bool>,
+
+        // Number of wrong passwords given in a row, the Send is disabled once the configured limit is reached
+        pub password_failures: i32,
     }
 }
 
@@ -75,12 +82,16 @@ impl Send {
 
             disabled: false,
             hide_email: None,
+
+            password_failures: 0,
         }
     }
 
     pub fn set_password(&mut self, password: Option<&str>) {
         const PASSWORD_ITER: i32 = 100_000;
 
+        self.password_failures = 0;
+
         if let Some(password) = password {
             self.password_iter = Some(PASSWORD_ITER);
             let salt = crate::crypto::get_random_64();
@@ -103,11 +114,25 @@ impl Send {
         }
     }
 
+    /// Returns the size of the file of a file send, as announced by the client
+    pub fn file_size(&self) -> Option<i64> {
+        if self.atype != SendType::File as i32 {
+            return None;
+        }
+
+        let data: Value = serde_json::from_str(&self.data).ok()?;
+        data["Size"].as_i64().or_else(|| data["Size"].as_str().and_then(|s| s.parse().ok()))
+    }
+
+    /// Returns true if the email of the creator is hidden from the recipients,
+    /// either by the creator or because the instance hides it on all the sends.
+    pub fn is_email_hidden(&self) -> bool {
+        CONFIG.sends_hide_email() || self.hide_email.unwrap_or(false)
+    }
+
     pub fn creator_identifier(&self, conn: &DbConn) -> Option<String> {
-        if let Some(hide_email) = self.hide_email {
-            if hide_email {
-                return None;
-            }
+        if self.is_email_hidden() {
+            return None;
         }
 
         if let Some(user_uuid) = &self.user_uuid {
@@ -141,7 +166,7 @@ impl Send {
             "AccessCount": self.access_count,
             "Password": self.password_hash.as_deref().map(|h| BASE64URL_NOPAD.encode(h)),
             "Disabled": self.disabled,
-            "HideEmail": self.hide_email,
+            "HideEmail": if CONFIG.sends_hide_email() { Some(true) } else { self.hide_email },
 
             "RevisionDate": format_date(&self.revision_date),
             "ExpirationDate": self.expiration_date.as_ref().map(format_date),
@@ -172,8 +197,9 @@ impl Send {
 
 use crate::db::DbConn;
 
-use crate::api::EmptyResult;
+use crate::api::{ApiResult, EmptyResult};
 use crate::error::MapResult;
+use crate::storage::{get_storage, FileArea};
 
 impl Send {
     pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
@@ -211,12 +237,89 @@ impl Send {
         }
     }
 
-    pub fn delete(&self, conn: &DbConn) -> EmptyResult {
+    /// Increments the access count directly in the database, which only succeeds while the count is below
+    /// the maximum, so concurrent accesses can't go over it. Returns false if the send can't be accessed anymore.
+    pub fn increment_access_count(&mut self, conn: &DbConn) -> ApiResult<bool> {
+        let now = Utc::now().naive_utc();
+
+        let updated: usize = db_run! { conn: {
+            let target = sends::table.filter(sends::uuid.eq(&self.uuid));
+            let changes = (sends::access_count.eq(sends::access_count + 1), sends::revision_date.eq(now));
+
+            let result = match self.max_access_count {
+                Some(max_access_count) => diesel::update(target.filter(sends::access_count.lt(max_access_count)))
+                    .set(changes)
+                    .execute(conn),
+                None => diesel::update(target).set(changes).execute(conn),
+            };
+            result.map_res("Error updating send access count")?
+        }};
+
+        if updated == 0 {
+            return Ok(false);
+        }
+
         self.update_users_revision(conn);
+        self.access_count += 1;
+        self.revision_date = now;
+        Ok(true)
+    }
 
-        if self.atype == SendType::File as i32 {
-            std::fs::remove_dir_all(std::path::Path::new(&crate::CONFIG.sends_folder()).join(&self.uuid)).ok();
+    /// Counts a wrong password directly in the database, so a concurrent access of the send isn't overwritten,
+    /// and disables the send once `max_attempts` is reached. A `max_attempts` of 0 never disables it.
+    pub fn add_password_failure(&mut self, max_attempts: u32, conn: &DbConn) -> EmptyResult {
+        let max_attempts = i32::try_from(max_attempts).unwrap_or(i32::MAX);
+        let now = Utc::now().naive_utc();
+
+        let disabled: usize = db_run! { conn: {
+            diesel::update(sends::table.filter(sends::uuid.eq(&self.uuid)))
+                .set(sends::password_failures.eq(sends::password_failures + 1))
+                .execute(conn)
+                .map_res("Error updating send password failures")?;
+
+            if max_attempts > 0 {
+                diesel::update(sends::table.filter(sends::uuid.eq(&self.uuid)))
+                    .filter(sends::password_failures.ge(max_attempts))
+                    .filter(sends::disabled.eq(false))
+                    .set((sends::disabled.eq(true), sends::revision_date.eq(now)))
+                    .execute(conn)
+                    .map_res("Error disabling send")?
+            } else {
+                0
+            }
+        }};
+
+        self.password_failures = self.password_failures.saturating_add(1);
+        if disabled > 0 {
+            self.update_users_revision(conn);
+            self.disabled = true;
+            self.revision_date = now;
         }
+        Ok(())
+    }
+
+    /// Resets the wrong password count after a successful access, without touching the other fields of the send
+    pub fn reset_password_failures(&mut self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::update(sends::table.filter(sends::uuid.eq(&self.uuid)))
+                .set(sends::password_failures.eq(0))
+                .execute(conn)
+                .map_res("Error resetting send password failures")
+        }}?;
+
+        self.password_failures = 0;
+        Ok(())
+    }
+
+    pub fn delete(&self, conn: &DbConn) -> EmptyResult {
+        self.delete_record(conn)?;
+        self.delete_file();
+        Ok(())
+    }
+
+    /// Deletes the send from the database, leaving its file in the storage
+    pub fn delete_record(&self, conn: &DbConn) -> EmptyResult {
+        self.update_users_revision(conn);
 
         db_run! { conn: {
             diesel::delete(sends::table.filter(sends::uuid.eq(&self.uuid)))
@@ -225,11 +328,36 @@ impl Send {
         }}
     }
 
-    /// Purge all sends that are past their deletion date.
-    pub fn purge(conn: &DbConn) {
+    /// Path of the file of a file send in the sends storage
+    pub fn file_storage_path(&self) -> Option<String> {
+        if self.atype != SendType::File as i32 {
+            return None;
+        }
+
+        let data: Value = serde_json::from_str(&self.data).ok()?;
+        data["Id"].as_str().map(|file_id| format!("{}/{}", self.uuid, file_id))
+    }
+
+    /// Deletes the file of a file send which was already deleted from the database
+    pub fn delete_file(&self) {
+        if let Some(path) = self.file_storage_path() {
+            if let Err(e) = get_storage(FileArea::Sends).delete(&path) {
+                warn!("Failed to delete the file of send {}: {:#?}", self.uuid, e);
+            }
+        }
+    }
+
+    /// Deletes the sends which are past their deletion date together with their files,
+    /// and returns how many of them were purged.
+    pub fn purge(conn: &DbConn) -> usize {
+        let mut purged = 0;
         for send in Self::find_by_past_deletion_date(conn) {
-            send.delete(conn).ok();
+            match send.delete(conn) {
+                Ok(()) => purged += 1,
+                Err(e) => warn!("Failed to purge send {}: {:#?}", send.uuid, e),
+            }
         }
+        purged
     }
 
     pub fn update_users_revision(&self, conn: &DbConn) {
@@ -243,11 +371,14 @@ impl Send {
         }
     }
 
-    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
-        for send in Self::find_by_user(user_uuid, conn) {
-            send.delete(conn)?;
+    /// Deletes all the sends of the user from the database, and returns them so their files
+    /// can be removed with `delete_file` once the surrounding transaction is committed.
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> ApiResult<Vec<Self>> {
+        let sends = Self::find_by_user(user_uuid, conn);
+        for send in &sends {
+            send.delete_record(conn)?;
         }
-        Ok(())
+        Ok(sends)
     }
 
     pub fn find_by_access_id(access_id: &str, conn: &DbConn) -> Option<Self> {
@@ -285,6 +416,22 @@ impl Send {
         }}
     }
 
+    /// Returns the total size in bytes of the files of the user's file Sends
+    pub fn size_by_user(user_uuid: &str, conn: &DbConn) -> i64 {
+        Self::find_by_user(user_uuid, conn).iter().filter_map(Self::file_size).sum()
+    }
+
+    pub fn count_by_user(user_uuid: &str, conn: &DbConn) -> i64 {
+        db_run! {conn: {
+            sends::table
+                .filter(sends::user_uuid.eq(user_uuid))
+                .count()
+                .first::<i64>(conn)
+                .ok()
+                .unwrap_or(0)
+        }}
+    }
+
     pub fn find_by_org(org_uuid: &str, conn: &DbConn) -> Vec<Self> {
         db_run! {conn: {
             sends::table
@@ -293,6 +440,28 @@ impl Send {
         }}
     }
 
+    pub fn count_by_org(org_uuid: &str, conn: &DbConn) -> i64 {
+        db_run! {conn: {
+            sends::table
+                .filter(sends::organization_uuid.eq(org_uuid))
+                .count()
+                .first::<i64>(conn)
+                .ok()
+                .unwrap_or(0)
+        }}
+    }
+
+    /// Returns the sends which haven't reached their deletion date yet
+    pub fn find_active(conn: &DbConn) -> Vec<Self> {
+        let now = Utc::now().naive_utc();
+        db_run! {conn: {
+            sends::table
+                .filter(sends::deletion_date.ge(now))
+                .order(sends::deletion_date.asc())
+                .load::<SendDb>(conn).expect("Error loading sends").from_db()
+        }}
+    }
+
     pub fn find_by_past_deletion_date(conn: &DbConn) -> Vec<Self> {
         let now = Utc::now().naive_utc();
         db_run! {conn: {
@@ -0,0 +1,100 @@
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[table_name = "stats"]
+    #[primary_key(day, name)]
+    pub struct Stat {
+        pub day: String,
+        pub name: String,
+        pub value: i64,
+    }
+}
+
+/// Local methods
+impl Stat {
+    pub fn new(day: String, name: String, value: i64) -> Self {
+        Self {
+            day,
+            name,
+            value,
+        }
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl Stat {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(stats::table)
+                    .values(StatDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving stat")
+            }
+            postgresql {
+                let value = StatDb::to_db(self);
+                diesel::insert_into(stats::table)
+                    .values(&value)
+                    .on_conflict((stats::day, stats::name))
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving stat")
+            }
+        }
+    }
+
+    /// Adds `amount` to the counter `name` of the given day. The counter is incremented by the database
+    /// itself, so concurrent additions can't overwrite each other.
+    pub fn add(day: &str, name: &str, amount: i64, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            let updated: usize = diesel::update(stats::table.filter(stats::day.eq(day)).filter(stats::name.eq(name)))
+                .set(stats::value.eq(stats::value + amount))
+                .execute(conn)
+                .map_res("Error updating stat")?;
+            if updated > 0 {
+                return Ok(());
+            }
+
+            let value = StatDb::to_db(&Self::new(day.to_string(), name.to_string(), amount));
+            if diesel::insert_into(stats::table).values(&value).execute(conn).is_ok() {
+                return Ok(());
+            }
+
+            // The counter was created by someone else in the meantime
+            diesel::update(stats::table.filter(stats::day.eq(day)).filter(stats::name.eq(name)))
+                .set(stats::value.eq(stats::value + amount))
+                .execute(conn)
+                .map_res("Error updating stat")
+        }}
+    }
+
+    /// Sets the value of `name` for the given day, for the values measured instead of counted
+    pub fn set(day: &str, name: &str, value: i64, conn: &DbConn) -> EmptyResult {
+        Self::new(day.to_string(), name.to_string(), value).save(conn)
+    }
+
+    /// The days are stored as `YYYY-MM-DD`, so they can be compared as strings
+    pub fn find_since(day: &str, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            stats::table
+                .filter(stats::day.ge(day))
+                .order((stats::day.asc(), stats::name.asc()))
+                .load::<StatDb>(conn)
+                .expect("Error loading stats")
+                .from_db()
+        }}
+    }
+
+    pub fn delete_before(day: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(stats::table.filter(stats::day.lt(day)))
+                .execute(conn)
+                .map_res("Error deleting old stats")
+        }}
+    }
+}
@@ -22,7 +22,7 @@ db_object! {
 }
 
 #[allow(dead_code)]
-#[derive(num_derive::FromPrimitive)]
+#[derive(Debug, num_derive::FromPrimitive)]
 pub enum TwoFactorType {
     Authenticator = 0,
     Email = 1,
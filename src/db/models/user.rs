@@ -44,6 +44,18 @@ db_object! {
 
         pub client_kdf_type: i32,
         pub client_kdf_iter: i32,
+
+        pub trash_auto_delete_days: Option<i32>,
+
+        // Set by an admin to make the clients ask for a new master password
+        pub force_password_reset: bool,
+
+        // When the token of the pending email change was sent, and how many times a wrong one was entered
+        pub email_new_token_sent_at: Option<NaiveDateTime>,
+        pub email_new_token_attempts: i32,
+
+        // Last change which a delta sync can't pick up, like a cipher moved to another folder
+        pub sync_reset_at: NaiveDateTime,
     }
 
 
@@ -109,6 +121,25 @@ impl User {
 
             client_kdf_type: Self::CLIENT_KDF_TYPE_DEFAULT,
             client_kdf_iter: Self::CLIENT_KDF_ITER_DEFAULT,
+
+            trash_auto_delete_days: None,
+
+            force_password_reset: false,
+
+            email_new_token_sent_at: None,
+            email_new_token_attempts: 0,
+
+            sync_reset_at: now,
+        }
+    }
+
+    /// Returns the number of days after which the trashed items of the user are deleted permanently.
+    /// The value chosen by the user can't exceed the one configured for the whole instance.
+    pub fn get_trash_auto_delete_days(&self) -> Option<i64> {
+        let user_days = self.trash_auto_delete_days.map(i64::from);
+        match (user_days, CONFIG.trash_auto_delete_days()) {
+            (Some(days), Some(max_days)) => Some(days.min(max_days)),
+            (days, max_days) => days.or(max_days),
         }
     }
 
@@ -147,6 +178,21 @@ impl User {
         self.reset_security_stamp()
     }
 
+    /// Starts a change of the email address to `email`, which has to be confirmed with the `token` sent to it
+    pub fn set_email_change(&mut self, email: String, token: String) {
+        self.email_new = Some(email);
+        self.email_new_token = Some(token);
+        self.email_new_token_sent_at = Some(Utc::now().naive_utc());
+        self.email_new_token_attempts = 0;
+    }
+
+    pub fn clear_email_change(&mut self) {
+        self.email_new = None;
+        self.email_new_token = None;
+        self.email_new_token_sent_at = None;
+        self.email_new_token_attempts = 0;
+    }
+
     pub fn reset_security_stamp(&mut self) {
         self.security_stamp = crate::util::get_uuid();
     }
@@ -177,7 +223,10 @@ impl User {
     }
 }
 
-use super::{Cipher, Device, Favorite, Folder, Send, TwoFactor, UserOrgType, UserOrganization};
+use super::{
+    Attachment, Cipher, CipherAccess, Device, Favorite, Folder, LoginIp, Send, TwoFactor, UserOrgType,
+    UserOrganization,
+};
 use crate::db::DbConn;
 
 use crate::api::EmptyResult;
@@ -190,6 +239,11 @@ impl User {
         let orgs_json: Vec<Value> = orgs.iter().map(|c| c.to_json(conn)).collect();
         let twofactor_enabled = !TwoFactor::find_by_user(&self.uuid, conn).is_empty();
 
+        // Attachment storage used by the user and the configured quota
+        let storage_used = Attachment::size_by_user(&self.uuid, conn);
+        let max_storage_gb =
+            CONFIG.user_attachment_limit().map(|limit_kb| crate::util::get_size_gb(limit_kb * 1024).ceil() as i64);
+
         // TODO: Might want to save the status field in the DB
         let status = if self.password_hash.is_empty() {
             UserStatus::Invited
@@ -211,6 +265,11 @@ impl User {
             "PrivateKey": self.private_key,
             "SecurityStamp": self.security_stamp,
             "Organizations": orgs_json,
+            "StorageName": crate::util::get_display_size(storage_used),
+            "StorageGb": crate::util::get_size_gb(storage_used),
+            "MaxStorageGb": max_storage_gb,
+            "TrashAutoDeleteDays": self.get_trash_auto_delete_days(),
+            "ForcePasswordReset": self.force_password_reset,
             "Object": "profile"
         })
     }
@@ -263,20 +322,32 @@ impl User {
             }
         }
 
-        Send::delete_all_by_user(&self.uuid, conn)?;
-        UserOrganization::delete_all_by_user(&self.uuid, conn)?;
-        Cipher::delete_all_by_user(&self.uuid, conn)?;
-        Favorite::delete_all_by_user(&self.uuid, conn)?;
-        Folder::delete_all_by_user(&self.uuid, conn)?;
-        Device::delete_all_by_user(&self.uuid, conn)?;
-        TwoFactor::delete_all_by_user(&self.uuid, conn)?;
-        Invitation::take(&self.email, conn); // Delete invitation if any
+        // Everything is removed from the database at once, the files are only deleted after the commit
+        let (sends, attachments) = conn.transaction(|| {
+            let sends = Send::delete_all_by_user(&self.uuid, conn)?;
+            UserOrganization::delete_all_by_user(&self.uuid, conn)?;
+            let attachments = Cipher::delete_all_by_user(&self.uuid, conn)?;
+            Favorite::delete_all_by_user(&self.uuid, conn)?;
+            CipherAccess::delete_all_by_user(&self.uuid, conn)?;
+            Folder::delete_all_by_user(&self.uuid, conn)?;
+            Device::delete_all_by_user(&self.uuid, conn)?;
+            LoginIp::delete_all_by_user(&self.uuid, conn)?;
+            TwoFactor::delete_all_by_user(&self.uuid, conn)?;
+            Invitation::take(&self.email, conn); // Delete invitation if any
+
+            db_run! {conn: {
+                diesel::delete(users::table.filter(users::uuid.eq(&self.uuid)))
+                    .execute(conn)
+                    .map_res("Error deleting user")?;
+            }}
+            Ok((sends, attachments))
+        })?;
 
-        db_run! {conn: {
-            diesel::delete(users::table.filter(users::uuid.eq(self.uuid)))
-                .execute(conn)
-                .map_res("Error deleting user")
-        }}
+        for send in &sends {
+            send.delete_file();
+        }
+        Attachment::delete_files(&attachments, conn);
+        Ok(())
     }
 
     pub fn update_uuid_revision(uuid: &str, conn: &DbConn) {
@@ -285,6 +356,14 @@ impl User {
         }
     }
 
+    /// Updates the revision of the user, and makes the next delta sync return the whole vault.
+    /// Used for the changes which don't modify the ciphers themselves, like the folder or the access to them.
+    pub fn reset_uuid_sync(uuid: &str, conn: &DbConn) {
+        if let Err(e) = Self::_reset_sync(uuid, &Utc::now().naive_utc(), conn) {
+            warn!("Failed to reset sync for {}: {:#?}", uuid, e);
+        }
+    }
+
     pub fn update_all_revisions(conn: &DbConn) -> EmptyResult {
         let updated_at = Utc::now().naive_utc();
 
@@ -315,6 +394,17 @@ impl User {
         }}
     }
 
+    fn _reset_sync(uuid: &str, date: &NaiveDateTime, conn: &DbConn) -> EmptyResult {
+        db_run! {conn: {
+            crate::util::retry(|| {
+                diesel::update(users::table.filter(users::uuid.eq(uuid)))
+                    .set((users::updated_at.eq(date), users::sync_reset_at.eq(date)))
+                    .execute(conn)
+            }, 10)
+            .map_res("Error resetting user sync")
+        }}
+    }
+
     pub fn find_by_mail(mail: &str, conn: &DbConn) -> Option<Self> {
         let lower_mail = mail.to_lowercase();
         db_run! {conn: {
@@ -338,6 +428,58 @@ impl User {
         }}
     }
 
+    /// Returns the users ordered by email, only the ones whose email or name contain `search` when it's set.
+    /// With a `limit`, only that many users are returned, starting at `offset`.
+    pub fn find_page(search: Option<&str>, offset: i64, limit: Option<i64>, conn: &DbConn) -> Vec<Self> {
+        // The wildcards in the search are matched literally
+        let pattern = search.map(|s| format!("%{}%", s.replace('!', "!!").replace('%', "!%").replace('_', "!_")));
+
+        db_run! {conn:
+            sqlite, mysql {
+                let mut query = users::table.order(users::email.asc()).into_boxed();
+                if let Some(ref pattern) = pattern {
+                    query = query.filter(
+                        users::email.like(pattern).escape('!').or(users::name.like(pattern).escape('!')),
+                    );
+                }
+                if let Some(limit) = limit {
+                    query = query.offset(offset).limit(limit);
+                }
+                query.load::<UserDb>(conn).expect("Error loading users").from_db()
+            }
+            postgresql {
+                // LIKE is case sensitive in PostgreSQL
+                let mut query = users::table.order(users::email.asc()).into_boxed();
+                if let Some(ref pattern) = pattern {
+                    query = query.filter(
+                        users::email.ilike(pattern).escape('!').or(users::name.ilike(pattern).escape('!')),
+                    );
+                }
+                if let Some(limit) = limit {
+                    query = query.offset(offset).limit(limit);
+                }
+                query.load::<UserDb>(conn).expect("Error loading users").from_db()
+            }
+        }
+    }
+
+    pub fn count(conn: &DbConn) -> i64 {
+        db_run! {conn: {
+            users::table.count().first::<i64>(conn).ok().unwrap_or(0)
+        }}
+    }
+
+    /// Returns the users that have chosen their own trash retention
+    pub fn find_with_trash_auto_delete_days(conn: &DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            users::table
+                .filter(users::trash_auto_delete_days.is_not_null())
+                .load::<UserDb>(conn)
+                .expect("Error loading users")
+                .from_db()
+        }}
+    }
+
     pub fn last_active(&self, conn: &DbConn) -> Option<NaiveDateTime> {
         match Device::find_latest_active_by_user(&self.uuid, conn) {
             Some(device) => Some(device.updated_at),
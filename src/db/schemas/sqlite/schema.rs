@@ -5,6 +5,7 @@ table! {
         file_name -> Text,
         file_size -> Integer,
         akey -> Nullable<Text>,
+        file_hash -> Nullable<Text>,
     }
 }
 
@@ -23,6 +24,31 @@ table! {
         password_history -> Nullable<Text>,
         deleted_at -> Nullable<Timestamp>,
         reprompt -> Nullable<Integer>,
+        archived_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    ciphers_history (uuid) {
+        uuid -> Text,
+        cipher_uuid -> Text,
+        revision_date -> Timestamp,
+        atype -> Integer,
+        name -> Text,
+        notes -> Nullable<Text>,
+        fields -> Nullable<Text>,
+        data -> Text,
+        password_history -> Nullable<Text>,
+        reprompt -> Nullable<Integer>,
+    }
+}
+
+table! {
+    ciphers_access (cipher_uuid, user_uuid) {
+        cipher_uuid -> Text,
+        user_uuid -> Text,
+        access_count -> Integer,
+        last_access_at -> Timestamp,
     }
 }
 
@@ -38,6 +64,7 @@ table! {
         uuid -> Text,
         org_uuid -> Text,
         name -> Text,
+        external_id -> Nullable<Text>,
     }
 }
 
@@ -85,6 +112,22 @@ table! {
     }
 }
 
+table! {
+    mail_queue (uuid) {
+        uuid -> Text,
+        address -> Text,
+        subject -> Text,
+        body_html -> Text,
+        body_text -> Text,
+        attempts -> Integer,
+        next_attempt_at -> Timestamp,
+        last_error -> Nullable<Text>,
+        failed -> Bool,
+        created_at -> Timestamp,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     org_policies (uuid) {
         uuid -> Text,
@@ -100,6 +143,8 @@ table! {
         uuid -> Text,
         name -> Text,
         billing_email -> Text,
+        email_message -> Nullable<Text>,
+        created_at -> Nullable<Timestamp>,
     }
 }
 
@@ -124,6 +169,15 @@ table! {
         deletion_date -> Timestamp,
         disabled -> Bool,
         hide_email -> Nullable<Bool>,
+        password_failures -> Integer,
+    }
+}
+
+table! {
+    stats (day, name) {
+        day -> Text,
+        name -> Text,
+        value -> BigInt,
     }
 }
 
@@ -166,6 +220,11 @@ table! {
         excluded_globals -> Text,
         client_kdf_type -> Integer,
         client_kdf_iter -> Integer,
+        trash_auto_delete_days -> Nullable<Integer>,
+        force_password_reset -> Bool,
+        email_new_token_sent_at -> Nullable<Timestamp>,
+        email_new_token_attempts -> Integer,
+        sync_reset_at -> Timestamp,
     }
 }
 
@@ -178,6 +237,15 @@ table! {
     }
 }
 
+table! {
+    users_login_ips (user_uuid, ip_network) {
+        user_uuid -> Text,
+        ip_network -> Text,
+        first_seen_at -> Timestamp,
+        last_seen_at -> Timestamp,
+    }
+}
+
 table! {
     users_organizations (uuid) {
         uuid -> Text,
@@ -187,13 +255,17 @@ table! {
         akey -> Text,
         status -> Integer,
         atype -> Integer,
+        external_id -> Nullable<Text>,
     }
 }
 
 joinable!(attachments -> ciphers (cipher_uuid));
 joinable!(ciphers -> organizations (organization_uuid));
 joinable!(ciphers -> users (user_uuid));
+joinable!(ciphers_access -> ciphers (cipher_uuid));
+joinable!(ciphers_access -> users (user_uuid));
 joinable!(ciphers_collections -> ciphers (cipher_uuid));
+joinable!(ciphers_history -> ciphers (cipher_uuid));
 joinable!(ciphers_collections -> collections (collection_uuid));
 joinable!(collections -> organizations (org_uuid));
 joinable!(devices -> users (user_uuid));
@@ -206,23 +278,29 @@ joinable!(sends -> users (user_uuid));
 joinable!(twofactor -> users (user_uuid));
 joinable!(users_collections -> collections (collection_uuid));
 joinable!(users_collections -> users (user_uuid));
+joinable!(users_login_ips -> users (user_uuid));
 joinable!(users_organizations -> organizations (org_uuid));
 joinable!(users_organizations -> users (user_uuid));
 
 allow_tables_to_appear_in_same_query!(
     attachments,
     ciphers,
+    ciphers_access,
     ciphers_collections,
+    ciphers_history,
     collections,
     devices,
     folders,
     folders_ciphers,
     invitations,
+    mail_queue,
     org_policies,
     organizations,
     sends,
+    stats,
     twofactor,
     users,
     users_collections,
+    users_login_ips,
     users_organizations,
 );
@@ -128,6 +128,10 @@ impl Error {
         self.error_code = code;
         self
     }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
 }
 
 pub trait MapResult<S> {
@@ -1,6 +1,7 @@
-use std::str::FromStr;
+use std::{str::FromStr, sync::Mutex};
 
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Duration, Local, NaiveDateTime, Utc};
+use once_cell::sync::{Lazy, OnceCell};
 use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
 
 use lettre::{
@@ -8,17 +9,19 @@ use lettre::{
     transport::smtp::authentication::{Credentials, Mechanism as SmtpAuthMechanism},
     transport::smtp::client::{Tls, TlsParameters},
     transport::smtp::extension::ClientId,
-    Address, SmtpTransport, Transport,
+    transport::smtp::response::Response,
+    Address, SendmailTransport, SmtpTransport, Transport,
 };
 
 use crate::{
     api::EmptyResult,
     auth::{encode_jwt, generate_delete_claims, generate_invite_claims, generate_verify_email_claims},
+    db::{models::QueuedMail, DbConn, DbPool},
     error::Error,
     CONFIG,
 };
 
-fn mailer() -> SmtpTransport {
+fn mailer() -> Result<SmtpTransport, Error> {
     use std::time::Duration;
     let host = CONFIG.smtp_host().unwrap();
 
@@ -27,7 +30,7 @@ fn mailer() -> SmtpTransport {
         .timeout(Some(Duration::from_secs(CONFIG.smtp_timeout())));
 
     // Determine security
-    let smtp_client = if CONFIG.smtp_ssl() || CONFIG.smtp_explicit_tls() {
+    let smtp_client = if CONFIG.smtp_security() != *"off" {
         let mut tls_parameters = TlsParameters::builder(host);
         if CONFIG.smtp_accept_invalid_hostnames() {
             tls_parameters = tls_parameters.dangerous_accept_invalid_hostnames(true);
@@ -37,7 +40,7 @@ fn mailer() -> SmtpTransport {
         }
         let tls_parameters = tls_parameters.build().unwrap();
 
-        if CONFIG.smtp_explicit_tls() {
+        if CONFIG.smtp_security() == *"force_tls" {
             smtp_client.tls(Tls::Wrapper(tls_parameters))
         } else {
             smtp_client.tls(Tls::Required(tls_parameters))
@@ -47,6 +50,9 @@ fn mailer() -> SmtpTransport {
     };
 
     let smtp_client = match (CONFIG.smtp_username(), CONFIG.smtp_password()) {
+        (Some(user), _) if CONFIG.smtp_oauth2_client_id().is_some() => smtp_client
+            .credentials(Credentials::new(user, smtp_oauth2_access_token()?))
+            .authentication(vec![SmtpAuthMechanism::Xoauth2]),
         (Some(user), Some(pass)) => smtp_client.credentials(Credentials::new(user, pass)),
         _ => smtp_client,
     };
@@ -57,6 +63,8 @@ fn mailer() -> SmtpTransport {
     };
 
     let smtp_client = match CONFIG.smtp_auth_mechanism() {
+        // The OAuth2 access token can only be used with XOAUTH2
+        Some(_) if CONFIG.smtp_oauth2_client_id().is_some() => smtp_client,
         Some(mechanism) => {
             let allowed_mechanisms = [SmtpAuthMechanism::Plain, SmtpAuthMechanism::Login, SmtpAuthMechanism::Xoauth2];
             let mut selected_mechanisms = vec![];
@@ -81,7 +89,79 @@ fn mailer() -> SmtpTransport {
         _ => smtp_client,
     };
 
-    smtp_client.build()
+    Ok(smtp_client.build())
+}
+
+#[derive(Deserialize)]
+struct OAuth2TokenResponse {
+    access_token: String,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+}
+
+struct OAuth2Token {
+    client_id: String,
+    access_token: String,
+    expires_at: NaiveDateTime,
+    // Some providers, like Microsoft, return a new refresh token with each access token
+    refresh_token: Option<String>,
+}
+
+/// The access token is reused until shortly before it expires, it's only kept in memory
+static SMTP_OAUTH2_TOKEN: Lazy<Mutex<Option<OAuth2Token>>> = Lazy::new(|| Mutex::new(None));
+
+/// Returns a valid access token for the XOAUTH2 authentication, requesting a new one to the OAuth2 provider when needed.
+/// The refresh token is used when configured, otherwise the token is requested with the client credentials
+fn smtp_oauth2_access_token() -> Result<String, Error> {
+    let client_id = CONFIG.smtp_oauth2_client_id().unwrap();
+    let now = Utc::now().naive_utc();
+
+    let mut cached = SMTP_OAUTH2_TOKEN.lock().unwrap_or_else(|e| e.into_inner());
+    let mut refresh_token = CONFIG.smtp_oauth2_refresh_token();
+    if let Some(token) = cached.as_ref().filter(|t| t.client_id == client_id) {
+        if token.expires_at > now + Duration::seconds(60) {
+            return Ok(token.access_token.clone());
+        }
+        if token.refresh_token.is_some() {
+            refresh_token = token.refresh_token.clone();
+        }
+    }
+
+    let mut form = vec![("client_id", client_id.clone())];
+    if let Some(secret) = CONFIG.smtp_oauth2_client_secret() {
+        form.push(("client_secret", secret));
+    }
+    if let Some(scope) = CONFIG.smtp_oauth2_scope() {
+        form.push(("scope", scope));
+    }
+    match refresh_token {
+        Some(ref refresh_token) => {
+            form.push(("grant_type", String::from("refresh_token")));
+            form.push(("refresh_token", refresh_token.clone()));
+        }
+        None => form.push(("grant_type", String::from("client_credentials"))),
+    }
+
+    let response = crate::util::get_reqwest_client()
+        .post(&CONFIG.smtp_oauth2_token_url())
+        .form(&form)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| Error::new("Error requesting the SMTP OAuth2 access token", e.to_string()))?;
+    let token: OAuth2TokenResponse = response
+        .json()
+        .map_err(|e| Error::new("Invalid response to the SMTP OAuth2 token request", e.to_string()))?;
+
+    let access_token = token.access_token.clone();
+    *cached = Some(OAuth2Token {
+        client_id,
+        access_token: token.access_token,
+        // Assume the usual lifetime of one hour when the provider doesn't say it
+        expires_at: now + Duration::seconds(token.expires_in.unwrap_or(3600)),
+        refresh_token: token.refresh_token.or(refresh_token),
+    });
+
+    Ok(access_token)
 }
 
 fn get_text(template_name: &'static str, data: serde_json::Value) -> Result<(String, String, String), Error> {
@@ -122,6 +202,7 @@ pub fn send_password_hint(address: &str, hint: Option<String>) -> EmptyResult {
 pub fn send_delete_account(address: &str, uuid: &str) -> EmptyResult {
     let claims = generate_delete_claims(uuid.to_string());
     let delete_token = encode_jwt(&claims);
+    let expires_at = NaiveDateTime::from_timestamp(claims.exp, 0);
 
     let (subject, body_html, body_text) = get_text(
         "email/delete_account",
@@ -133,12 +214,13 @@ pub fn send_delete_account(address: &str, uuid: &str) -> EmptyResult {
         }),
     )?;
 
-    send_email(address, &subject, body_html, body_text)
+    send_email_until(address, &subject, body_html, body_text, expires_at)
 }
 
 pub fn send_verify_email(address: &str, uuid: &str) -> EmptyResult {
     let claims = generate_verify_email_claims(uuid.to_string());
     let verify_email_token = encode_jwt(&claims);
+    let expires_at = NaiveDateTime::from_timestamp(claims.exp, 0);
 
     let (subject, body_html, body_text) = get_text(
         "email/verify_email",
@@ -150,7 +232,7 @@ pub fn send_verify_email(address: &str, uuid: &str) -> EmptyResult {
         }),
     )?;
 
-    send_email(address, &subject, body_html, body_text)
+    send_email_until(address, &subject, body_html, body_text, expires_at)
 }
 
 pub fn send_welcome(address: &str) -> EmptyResult {
@@ -158,6 +240,8 @@ pub fn send_welcome(address: &str) -> EmptyResult {
         "email/welcome",
         json!({
             "url": CONFIG.domain(),
+            "email": address,
+            "contact": CONFIG.welcome_email_contact(),
         }),
     )?;
 
@@ -167,6 +251,7 @@ pub fn send_welcome(address: &str) -> EmptyResult {
 pub fn send_welcome_must_verify(address: &str, uuid: &str) -> EmptyResult {
     let claims = generate_verify_email_claims(uuid.to_string());
     let verify_email_token = encode_jwt(&claims);
+    let expires_at = NaiveDateTime::from_timestamp(claims.exp, 0);
 
     let (subject, body_html, body_text) = get_text(
         "email/welcome_must_verify",
@@ -177,7 +262,7 @@ pub fn send_welcome_must_verify(address: &str, uuid: &str) -> EmptyResult {
         }),
     )?;
 
-    send_email(address, &subject, body_html, body_text)
+    send_email_until(address, &subject, body_html, body_text, expires_at)
 }
 
 pub fn send_invite(
@@ -187,6 +272,7 @@ pub fn send_invite(
     org_user_id: Option<String>,
     org_name: &str,
     invited_by_email: Option<String>,
+    email_message: Option<&str>,
 ) -> EmptyResult {
     let claims = generate_invite_claims(
         uuid.to_string(),
@@ -196,6 +282,7 @@ pub fn send_invite(
         invited_by_email,
     );
     let invite_token = encode_jwt(&claims);
+    let expires_at = NaiveDateTime::from_timestamp(claims.exp, 0);
 
     let (subject, body_html, body_text) = get_text(
         "email/send_org_invite",
@@ -206,10 +293,11 @@ pub fn send_invite(
             "email": percent_encode(address.as_bytes(), NON_ALPHANUMERIC).to_string(),
             "org_name": org_name,
             "token": invite_token,
+            "email_message": email_message,
         }),
     )?;
 
-    send_email(address, &subject, body_html, body_text)
+    send_email_until(address, &subject, body_html, body_text, expires_at)
 }
 
 pub fn send_invite_accepted(new_user_email: &str, address: &str, org_name: &str) -> EmptyResult {
@@ -237,6 +325,19 @@ pub fn send_invite_confirmed(address: &str, org_name: &str) -> EmptyResult {
     send_email(address, &subject, body_html, body_text)
 }
 
+pub fn send_removed_from_org(address: &str, org_name: &str, email_message: Option<&str>) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/removed_from_org",
+        json!({
+            "url": CONFIG.domain(),
+            "org_name": org_name,
+            "email_message": email_message,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text)
+}
+
 pub fn send_new_device_logged_in(address: &str, ip: &str, dt: &DateTime<Local>, device: &str) -> EmptyResult {
     use crate::util::upcase_first;
     let device = upcase_first(device);
@@ -255,7 +356,40 @@ pub fn send_new_device_logged_in(address: &str, ip: &str, dt: &DateTime<Local>,
     send_email(address, &subject, body_html, body_text)
 }
 
+pub fn send_new_ip_logged_in(address: &str, ip: &str, dt: &DateTime<Local>, device: &str) -> EmptyResult {
+    use crate::util::upcase_first;
+    let device = upcase_first(device);
+
+    let fmt = "%A, %B %_d, %Y at %r %Z";
+    let (subject, body_html, body_text) = get_text(
+        "email/new_ip_logged_in",
+        json!({
+            "url": CONFIG.domain(),
+            "ip": ip,
+            "device": device,
+            "datetime": crate::util::format_datetime_local(dt, fmt),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text)
+}
+
+pub fn send_send_accessed(address: &str, ip: &str, dt: &DateTime<Local>) -> EmptyResult {
+    let fmt = "%A, %B %_d, %Y at %r %Z";
+    let (subject, body_html, body_text) = get_text(
+        "email/send_accessed",
+        json!({
+            "url": CONFIG.domain(),
+            "ip": ip,
+            "datetime": crate::util::format_datetime_local(dt, fmt),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text)
+}
+
 pub fn send_token(address: &str, token: &str) -> EmptyResult {
+    let expires_at = Utc::now().naive_utc() + Duration::seconds(CONFIG.email_expiration_time() as i64);
     let (subject, body_html, body_text) = get_text(
         "email/twofactor_email",
         json!({
@@ -264,10 +398,11 @@ pub fn send_token(address: &str, token: &str) -> EmptyResult {
         }),
     )?;
 
-    send_email(address, &subject, body_html, body_text)
+    send_email_until(address, &subject, body_html, body_text, expires_at)
 }
 
 pub fn send_change_email(address: &str, token: &str) -> EmptyResult {
+    let expires_at = Utc::now().naive_utc() + Duration::seconds(CONFIG.email_expiration_time() as i64);
     let (subject, body_html, body_text) = get_text(
         "email/change_email",
         json!({
@@ -276,10 +411,12 @@ pub fn send_change_email(address: &str, token: &str) -> EmptyResult {
         }),
     )?;
 
-    send_email(address, &subject, body_html, body_text)
+    send_email_until(address, &subject, body_html, body_text, expires_at)
 }
 
-pub fn send_test(address: &str) -> EmptyResult {
+/// Sends the test email, adding a line to `transcript` for each step of the SMTP session,
+/// so the admin can see where it failed without having to enable SMTP_DEBUG
+pub fn send_test(address: &str, transcript: &mut Vec<String>) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/smtp_test",
         json!({
@@ -287,10 +424,162 @@ pub fn send_test(address: &str) -> EmptyResult {
         }),
     )?;
 
-    send_email(address, &subject, body_html, body_text)
+    if CONFIG.use_sendmail() {
+        transcript.push(format!(
+            "Sending email from {} to {} with {}",
+            CONFIG.smtp_from(),
+            address,
+            CONFIG.sendmail_command()
+        ));
+        let email = build_email(address, &subject, body_html, body_text)?;
+        send_with_sendmail(&email)?;
+        transcript.push(String::from("Email accepted by sendmail"));
+        return Ok(());
+    }
+
+    let security = match CONFIG.smtp_security().as_str() {
+        "force_tls" => "implicit TLS",
+        "starttls" => "STARTTLS",
+        _ => "no encryption",
+    };
+    transcript.push(format!(
+        "Connecting to {}:{} using {}",
+        CONFIG.smtp_host().unwrap_or_default(),
+        CONFIG.smtp_port(),
+        security
+    ));
+    if let Some(helo_name) = CONFIG.helo_name() {
+        transcript.push(format!("Identifying as {} in the EHLO", helo_name));
+    }
+    if let Some(user) = CONFIG.smtp_username() {
+        if CONFIG.smtp_oauth2_client_id().is_some() {
+            transcript.push(format!("Authenticating as {} with an OAuth2 access token", user));
+        } else {
+            transcript.push(format!("Authenticating as {}", user));
+        }
+    }
+
+    match mailer()?.test_connection() {
+        Ok(true) => transcript.push(String::from("Connection established")),
+        Ok(false) => transcript.push(String::from("Connected, but the server didn't answer the NOOP command")),
+        Err(e) => err!(format!("Connection failed: {}", e)),
+    }
+
+    transcript.push(format!("Sending email from {} to {}", CONFIG.smtp_from(), address));
+    let email = build_email(address, &subject, body_html, body_text)?;
+    let response = send_with_smtp(&email)?;
+    let message: Vec<&str> = response.message().collect();
+    transcript.push(format!("Server response: {} {}", response.code(), message.join(" ")));
+
+    Ok(())
+}
+
+/// Database pool of the mail queue, set at startup
+static MAIL_QUEUE_POOL: OnceCell<DbPool> = OnceCell::new();
+
+pub fn init_queue(pool: DbPool) {
+    MAIL_QUEUE_POOL.set(pool).ok();
 }
 
+/// Saves the email in the queue when it's enabled, and makes the first attempt to send it in the background
 fn send_email(address: &str, subject: &str, body_html: String, body_text: String) -> EmptyResult {
+    queue_email(address, subject, body_html, body_text, None)
+}
+
+/// Same as `send_email`, for the emails with tokens or codes, which are removed from the queue at `expires_at`
+fn send_email_until(
+    address: &str,
+    subject: &str,
+    body_html: String,
+    body_text: String,
+    expires_at: NaiveDateTime,
+) -> EmptyResult {
+    queue_email(address, subject, body_html, body_text, Some(expires_at))
+}
+
+fn queue_email(
+    address: &str,
+    subject: &str,
+    body_html: String,
+    body_text: String,
+    expires_at: Option<NaiveDateTime>,
+) -> EmptyResult {
+    let pool = match MAIL_QUEUE_POOL.get() {
+        Some(pool) if CONFIG.mail_queue() => pool.clone(),
+        _ => return send_email_direct(address, subject, body_html, body_text),
+    };
+
+    // Check the addresses now, as there is no point in queuing an email which can't be built
+    let email = build_email(address, subject, body_html.clone(), body_text.clone())?;
+
+    // The email is saved before the first attempt, so it isn't lost if the server stops while sending it
+    let mut mail = QueuedMail::new(address.to_string(), subject.to_string(), body_html, body_text, expires_at);
+    let conn = pool.get()?;
+    mail.save(&conn)?;
+    drop(conn);
+
+    std::thread::spawn(move || {
+        let result = send_message(&email);
+        match pool.get() {
+            Ok(conn) => record_attempt(&mut mail, result, &conn),
+            // The email stays in the queue, the queue job will send it again
+            Err(_) => error!("Failed to get DB connection to update the queued email to {}", mail.address),
+        }
+    });
+
+    Ok(())
+}
+
+fn send_email_direct(address: &str, subject: &str, body_html: String, body_text: String) -> EmptyResult {
+    let email = build_email(address, subject, body_html, body_text)?;
+    send_message(&email)
+}
+
+/// Sends a queued email, deleting it from the queue once sent, or scheduling the next attempt otherwise
+fn send_queued(mut mail: QueuedMail, conn: &DbConn) {
+    let result = build_email(&mail.address, &mail.subject, mail.body_html.clone(), mail.body_text.clone())
+        .and_then(|email| send_message(&email));
+
+    record_attempt(&mut mail, result, conn);
+}
+
+/// Removes a sent email from the queue, or schedules the next attempt of a failed one,
+/// keeping it as failed once it reached the maximum number of attempts, so the admin can retry it
+fn record_attempt(mail: &mut QueuedMail, result: EmptyResult, conn: &DbConn) {
+    let result = match result {
+        Ok(_) => mail.delete(conn),
+        Err(e) => {
+            mail.record_failure(e.message().to_string());
+            if mail.failed {
+                error!("Giving up sending email to {} after {} attempts: {}", mail.address, mail.attempts, e.message());
+            } else {
+                warn!("Failed to send email to {}, it will be retried: {}", mail.address, e.message());
+            }
+            mail.save(conn)
+        }
+    };
+
+    if let Err(e) = result {
+        error!("Failed to update a queued email: {:#?}", e);
+    }
+}
+
+/// Retries sending the queued emails which are due
+pub fn send_queued_mails(pool: DbPool) {
+    debug!("Sending queued emails");
+    if let Ok(conn) = pool.get() {
+        if let Err(e) = QueuedMail::delete_expired(&conn) {
+            error!("Failed to remove the expired emails from the queue: {:#?}", e);
+        }
+        for mail in QueuedMail::find_due(&conn) {
+            send_queued(mail, &conn);
+        }
+    } else {
+        error!("Failed to get DB connection while sending queued emails")
+    }
+}
+
+fn build_email(address: &str, subject: &str, body_html: String, body_text: String) -> Result<Message, Error> {
     let address_split: Vec<&str> = address.rsplitn(2, '@').collect();
     if address_split.len() != 2 {
         err!("Invalid email address (no @)");
@@ -323,8 +612,27 @@ fn send_email(address: &str, subject: &str, body_html: String, body_text: String
         .subject(subject)
         .multipart(MultiPart::alternative().singlepart(text).singlepart(html))?;
 
-    match mailer().send(&email) {
+    Ok(email)
+}
+
+fn send_message(email: &Message) -> EmptyResult {
+    if CONFIG.use_sendmail() {
+        send_with_sendmail(email)
+    } else {
+        send_with_smtp(email).map(|_| ())
+    }
+}
+
+fn send_with_sendmail(email: &Message) -> EmptyResult {
+    match SendmailTransport::new_with_command(CONFIG.sendmail_command()).send(email) {
         Ok(_) => Ok(()),
+        Err(e) => err!(format!("Sendmail error: {}", e)),
+    }
+}
+
+fn send_with_smtp(email: &Message) -> Result<Response, Error> {
+    match mailer()?.send(email) {
+        Ok(response) => Ok(response),
         // Match some common errors and make them more user friendly
         Err(e) => {
             if e.is_client() {
@@ -28,6 +28,10 @@ mod crypto;
 #[macro_use]
 mod db;
 mod mail;
+mod ratelimit;
+mod sso;
+mod stats;
+mod storage;
 mod util;
 
 pub use config::CONFIG;
@@ -54,6 +58,7 @@ fn main() {
     create_icon_cache_folder();
 
     let pool = create_db_pool();
+    mail::init_queue(pool.clone());
     schedule_jobs(pool.clone());
     crate::db::models::TwoFactor::migrate_u2f_to_webauthn(&pool.get().unwrap()).unwrap();
 
@@ -69,6 +74,10 @@ const HELP: &str = "\
         FLAGS:
             -h, --help       Prints help information
             -v, --version    Prints the app version
+
+        OPTIONS:
+            --export-config <FILE>    Exports the configuration profile to a file, with secrets referenced by name
+            --import-config <FILE>    Imports a configuration profile from a file into the config file
 ";
 
 fn parse_args() {
@@ -83,6 +92,31 @@ fn parse_args() {
         println!("vaultwarden {}", option_env!("BWRS_VERSION").unwrap_or(NO_VERSION));
         exit(0);
     }
+
+    if let Ok(Some(path)) = pargs.opt_value_from_str::<_, String>("--export-config") {
+        let profile = serde_json::to_string_pretty(&CONFIG.export_profile()).expect("Error serializing config profile");
+        match util::write_file(&path, profile.as_bytes()) {
+            Ok(_) => println!("Configuration profile exported to {}", path),
+            Err(e) => {
+                println!("Error exporting configuration profile: {:?}", e);
+                exit(1);
+            }
+        }
+        exit(0);
+    } else if let Ok(Some(path)) = pargs.opt_value_from_str::<_, String>("--import-config") {
+        let result = util::read_file_string(&path)
+            .map_err(Error::from)
+            .and_then(|profile| serde_json::from_str(&profile).map_err(Error::from))
+            .and_then(|profile| CONFIG.import_profile(profile));
+        match result {
+            Ok(_) => println!("Configuration profile imported from {}", path),
+            Err(e) => {
+                println!("Error importing configuration profile: {:?}", e);
+                exit(1);
+            }
+        }
+        exit(0);
+    }
 }
 
 fn launch_info() {
@@ -313,6 +347,8 @@ fn launch_rocket(pool: db::DbPool, extra_debug: bool) {
         .manage(api::start_notification_server())
         .attach(util::AppHeaders())
         .attach(util::Cors())
+        .attach(util::MaintenanceMode())
+        .attach(stats::RequestStats())
         .attach(util::BetterLogging(extra_debug))
         .launch();
 
@@ -334,6 +370,7 @@ fn schedule_jobs(pool: db::DbPool) {
             // Purge sends that are past their deletion date.
             if !CONFIG.send_purge_schedule().is_empty() {
                 sched.add(Job::new(CONFIG.send_purge_schedule().parse().unwrap(), || {
+                    stats::incr("job_runs");
                     api::purge_sends(pool.clone());
                 }));
             }
@@ -341,10 +378,34 @@ fn schedule_jobs(pool: db::DbPool) {
             // Purge trashed items that are old enough to be auto-deleted.
             if !CONFIG.trash_purge_schedule().is_empty() {
                 sched.add(Job::new(CONFIG.trash_purge_schedule().parse().unwrap(), || {
+                    stats::incr("job_runs");
                     api::purge_trashed_ciphers(pool.clone());
                 }));
             }
 
+            // Remove the attachment files left behind by failed deletions.
+            if !CONFIG.orphaned_attachments_purge_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.orphaned_attachments_purge_schedule().parse().unwrap(), || {
+                    stats::incr("job_runs");
+                    api::purge_orphaned_attachments(pool.clone());
+                }));
+            }
+
+            // Retry sending the queued emails.
+            if !CONFIG.mail_queue_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.mail_queue_schedule().parse().unwrap(), || {
+                    stats::incr("job_runs");
+                    mail::send_queued_mails(pool.clone());
+                }));
+            }
+
+            // Save the usage stats shown in the admin panel.
+            if !CONFIG.stats_flush_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.stats_flush_schedule().parse().unwrap(), || {
+                    stats::flush_stats(pool.clone());
+                }));
+            }
+
             // Periodically check for jobs to run. We probably won't need any
             // jobs that run more often than once a minute, so a default poll
             // interval of 30 seconds should be sufficient. Users who want to
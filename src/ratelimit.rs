@@ -0,0 +1,66 @@
+//
+// Rate limiting of the routes which can be reached without credentials, per client IP
+//
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::{api::EmptyResult, CONFIG};
+
+/// Number of tracked IPs after which the ones with a full bucket are forgotten
+const MAX_TRACKED_IPS: usize = 10_000;
+
+/// A token bucket per IP: every request takes a token, and one token is given back
+/// every `period`, up to `burst` tokens
+struct RateLimiter {
+    buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self {
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check(&self, ip: IpAddr, period: Duration, burst: u32) -> bool {
+        let burst = f64::from(burst.max(1));
+        let period = period.as_secs_f64().max(f64::EPSILON);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_TRACKED_IPS {
+            buckets.retain(|_, (tokens, last)| *tokens + now.duration_since(*last).as_secs_f64() / period < burst);
+        }
+
+        let (tokens, last) = buckets.entry(ip).or_insert((burst, now));
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() / period).min(burst);
+        *last = now;
+
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+static SEND_ACCESS_LIMITER: Lazy<RateLimiter> = Lazy::new(RateLimiter::new);
+
+/// Limits the anonymous accesses to the Sends, which can be used to guess their passwords
+pub fn check_limit_send_access(ip: &IpAddr) -> EmptyResult {
+    let seconds = CONFIG.send_access_ratelimit_seconds();
+    if seconds == 0 {
+        return Ok(());
+    }
+
+    if !SEND_ACCESS_LIMITER.check(*ip, Duration::from_secs(seconds), CONFIG.send_access_ratelimit_max_burst()) {
+        err_code!("Too many Send access requests, try again later", 429)
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//
+// OpenID Connect client, used to log in the admin panel with an external provider
+//
+use std::time::Duration;
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    error::{Error, MapResult},
+    util::get_reqwest_client,
+    CONFIG,
+};
+
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct JsonWebKeySet {
+    keys: Vec<JsonWebKey>,
+}
+
+#[derive(Deserialize)]
+struct JsonWebKey {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+}
+
+fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, Error> {
+    Ok(get_reqwest_client().get(url).timeout(Duration::from_secs(10)).send()?.error_for_status()?.json::<T>()?)
+}
+
+fn get_provider_metadata() -> Result<ProviderMetadata, Error> {
+    let authority = CONFIG.admin_sso_authority().unwrap_or_default();
+    get_json(&format!("{}/.well-known/openid-configuration", authority.trim_end_matches('/')))
+}
+
+/// Returns the URL of the provider where the admin logs in, which then redirects back to `redirect_uri`
+pub fn authorize_url(redirect_uri: &str, state: &str, nonce: &str) -> Result<String, Error> {
+    let metadata = get_provider_metadata()?;
+
+    let mut url = Url::parse(&metadata.authorization_endpoint).ok().map_res("Invalid authorization endpoint")?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &CONFIG.admin_sso_client_id().unwrap_or_default())
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("scope", "openid")
+        .append_pair("state", state)
+        .append_pair("nonce", nonce);
+
+    Ok(url.to_string())
+}
+
+/// Exchanges the authorization code returned by the provider for an ID token,
+/// and returns its subject once its signature, issuer, audience and nonce are validated
+pub fn exchange_code(code: &str, redirect_uri: &str, nonce: &str) -> Result<String, Error> {
+    let metadata = get_provider_metadata()?;
+    let client_id = CONFIG.admin_sso_client_id().unwrap_or_default();
+    let client_secret = CONFIG.admin_sso_client_secret().unwrap_or_default();
+
+    let token: TokenResponse = get_reqwest_client()
+        .post(&metadata.token_endpoint)
+        .timeout(Duration::from_secs(10))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", &client_id),
+            ("client_secret", &client_secret),
+        ])
+        .send()?
+        .error_for_status()?
+        .json()?;
+
+    let header = jsonwebtoken::decode_header(&token.id_token).map_res("Invalid ID token")?;
+    if !matches!(header.alg, Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512) {
+        err!(format!("Unsupported ID token algorithm {:?}", header.alg))
+    }
+
+    let jwks: JsonWebKeySet = get_json(&metadata.jwks_uri)?;
+    let key = jwks
+        .keys
+        .iter()
+        .find(|k| k.kty == "RSA" && (header.kid.is_none() || k.kid == header.kid))
+        .map_res("The signing key of the ID token wasn't found")?;
+    let (n, e) = match (&key.n, &key.e) {
+        (Some(n), Some(e)) => (n, e),
+        _ => err!("Invalid signing key of the ID token"),
+    };
+
+    let mut validation = Validation::new(header.alg);
+    validation.leeway = 30;
+    validation.iss = Some(metadata.issuer);
+    validation.set_audience(&[client_id]);
+
+    let claims: IdTokenClaims =
+        jsonwebtoken::decode(&token.id_token, &DecodingKey::from_rsa_components(n, e), &validation)
+            .map_res("Invalid ID token")?
+            .claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        err!("The ID token doesn't belong to this login")
+    }
+
+    Ok(claims.sub)
+}
+
+pub fn is_subject_allowed(sub: &str) -> bool {
+    CONFIG.admin_sso_allowed_subjects().split(',').map(str::trim).any(|s| !s.is_empty() && s == sub)
+}
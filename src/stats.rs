@@ -0,0 +1,83 @@
+//
+// Daily usage statistics shown in the admin panel
+//
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{Duration, Utc};
+use once_cell::sync::Lazy;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Request, Response,
+};
+
+use crate::db::{models::*, DbConn, DbPool};
+
+/// Stats older than this are deleted when flushing
+const STATS_RETENTION_DAYS: i64 = 365;
+
+/// The counters are kept in memory and added to the stats of the day when flushing,
+/// so counting a request or a login doesn't need to write to the database
+static COUNTERS: Lazy<Mutex<HashMap<&'static str, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn incr(name: &'static str) {
+    let mut counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+    *counters.entry(name).or_insert(0) += 1;
+}
+
+pub fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+pub fn days_ago(days: i64) -> String {
+    (Utc::now() - Duration::days(days)).format("%Y-%m-%d").to_string()
+}
+
+/// Saves the pending counters and the current totals of users and ciphers
+pub fn flush(conn: &DbConn) {
+    let day = today();
+
+    let counters = std::mem::take(&mut *COUNTERS.lock().unwrap_or_else(|e| e.into_inner()));
+    for (name, amount) in counters {
+        if let Err(e) = Stat::add(&day, name, amount, conn) {
+            warn!("Failed to save the {} stat: {:#?}", name, e);
+        }
+    }
+
+    let totals = [("users", User::count(conn)), ("ciphers", Cipher::count(conn))];
+    for (name, value) in totals.iter() {
+        if let Err(e) = Stat::set(&day, name, *value, conn) {
+            warn!("Failed to save the {} stat: {:#?}", name, e);
+        }
+    }
+
+    if let Err(e) = Stat::delete_before(&days_ago(STATS_RETENTION_DAYS), conn) {
+        warn!("Failed to delete the old stats: {:#?}", e);
+    }
+}
+
+pub fn flush_stats(pool: DbPool) {
+    debug!("Flushing stats");
+    if let Ok(conn) = pool.get() {
+        flush(&conn);
+    } else {
+        error!("Failed to get DB connection while flushing stats")
+    }
+}
+
+pub struct RequestStats();
+
+impl Fairing for RequestStats {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Stats",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, _req: &Request, res: &mut Response) {
+        incr("requests");
+        if res.status().code >= 500 {
+            incr("server_errors");
+        }
+    }
+}
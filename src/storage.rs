@@ -0,0 +1,489 @@
+//
+// Storage of the attachment and Send files, either on the local disk or in an S3 compatible object storage
+//
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+use data_encoding::HEXLOWER;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{
+    blocking::{Body, Client},
+    Method, Url,
+};
+use ring::{digest, hmac};
+use rocket::{
+    http::Status,
+    request::Request,
+    response::{self, Redirect, Responder, Response},
+};
+
+use crate::{api::EmptyResult, error::Error, util::get_reqwest_client_builder, CONFIG};
+
+/// The kind of files stored, each one is kept in its own folder
+#[derive(Clone, Copy)]
+pub enum FileArea {
+    Attachments,
+    Sends,
+}
+
+impl FileArea {
+    fn local_folder(self) -> String {
+        match self {
+            FileArea::Attachments => CONFIG.attachments_folder(),
+            FileArea::Sends => CONFIG.sends_folder(),
+        }
+    }
+
+    /// Whether the files are kept in the object storage, when one is configured
+    fn uses_s3(self) -> bool {
+        match self {
+            FileArea::Attachments => CONFIG.s3_store_attachments(),
+            FileArea::Sends => CONFIG.s3_store_sends(),
+        }
+    }
+
+    fn s3_bucket(self) -> Option<String> {
+        match self {
+            FileArea::Attachments => CONFIG.s3_bucket(),
+            FileArea::Sends => CONFIG.s3_sends_bucket().or_else(|| CONFIG.s3_bucket()),
+        }
+    }
+
+    /// Prefix of the keys of the files in the object storage
+    fn s3_prefix(self) -> String {
+        match self {
+            FileArea::Attachments => format!("{}/attachments", CONFIG.s3_prefix()),
+            FileArea::Sends => CONFIG.s3_sends_prefix().unwrap_or_else(|| format!("{}/sends", CONFIG.s3_prefix())),
+        }
+    }
+
+    /// Path on the local disk of a file, uploads are always written here first and then moved to the storage
+    pub fn local_path(self, path: &str) -> PathBuf {
+        Path::new(&self.local_folder()).join(path)
+    }
+}
+
+/// A stored file as returned to the client, either read from the local disk
+/// or as a redirection to a temporary URL of the object storage
+pub enum StoredFile {
+    Local(LocalFile),
+    Remote(Redirect),
+}
+
+impl<'r> Responder<'r> for StoredFile {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        match self {
+            StoredFile::Local(file) => file.respond_to(req),
+            StoredFile::Remote(redirect) => redirect.respond_to(req),
+        }
+    }
+}
+
+/// A file of the local disk, streamed to the client with support for ETags
+/// and range requests, so large downloads can be resumed
+pub struct LocalFile {
+    file: File,
+    size: u64,
+    etag: String,
+    sha256: Option<String>,
+}
+
+impl LocalFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(ErrorKind::NotFound, "Not a file"));
+        }
+
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        Ok(Self {
+            file,
+            size: metadata.len(),
+            etag: format!("\"{:x}-{:x}\"", modified, metadata.len()),
+            sha256: None,
+        })
+    }
+
+    /// Checks the file against its expected size, to detect corrupted files before sending them.
+    /// The expected SHA-256, if known, is only checked when the whole file is sent, hashing it
+    /// for every range or cached request would read the whole file each time.
+    pub fn matches(&mut self, size: u64, sha256: Option<String>) -> bool {
+        self.sha256 = sha256;
+        self.size == size
+    }
+
+    fn matches_hash(&mut self) -> bool {
+        match self.sha256 {
+            None => true,
+            Some(ref expected) => {
+                let hash = crate::crypto::sha256_reader(&mut self.file);
+                let rewound = self.file.seek(SeekFrom::Start(0)).is_ok();
+                rewound && hash.map_or(false, |hash| crate::crypto::ct_eq(hash, expected))
+            }
+        }
+    }
+}
+
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses the `Range` header of a request. Only single byte ranges are supported,
+/// anything else is ignored and the whole file is sent, as allowed by RFC 7233.
+fn parse_range(header: &str, size: u64) -> ByteRange {
+    let range = match header.trim().strip_prefix("bytes=") {
+        Some(range) if !range.contains(',') => range,
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return ByteRange::Full,
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // The last `end` bytes of the file
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => (size.saturating_sub(suffix), size.saturating_sub(1)),
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        _ => return ByteRange::Full,
+    };
+
+    if size == 0 || start >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+
+impl<'r> Responder<'r> for LocalFile {
+    fn respond_to(mut self, req: &Request) -> response::Result<'r> {
+        let mut response = Response::build();
+        response.raw_header("Accept-Ranges", "bytes").raw_header("ETag", self.etag.clone());
+
+        if req.headers().get_one("If-None-Match") == Some(self.etag.as_str()) {
+            return response.status(Status::NotModified).ok();
+        }
+
+        // A range only applies to the same version of the file the client already has
+        let range = match req.headers().get_one("Range") {
+            Some(range) if req.headers().get_one("If-Range").map_or(true, |etag| etag == self.etag) => {
+                parse_range(range, self.size)
+            }
+            _ => ByteRange::Full,
+        };
+
+        match range {
+            ByteRange::Full => {
+                if !self.matches_hash() {
+                    error!("The file doesn't match its hash, it may be corrupted");
+                    return Err(Status::InternalServerError);
+                }
+                response.sized_body(self.file).ok()
+            }
+            ByteRange::Partial(start, end) => {
+                self.file.seek(SeekFrom::Start(start)).map_err(|_| Status::InternalServerError)?;
+                response
+                    .status(Status::PartialContent)
+                    .raw_header("Content-Range", format!("bytes {}-{}/{}", start, end, self.size))
+                    .streamed_body(self.file.take(end - start + 1))
+                    .ok()
+            }
+            ByteRange::Unsatisfiable => response
+                .status(Status::RangeNotSatisfiable)
+                .raw_header("Content-Range", format!("bytes */{}", self.size))
+                .ok(),
+        }
+    }
+}
+
+pub trait FileStorage {
+    /// Moves the file written at `local_path` into the storage, under `path`
+    fn save(&self, path: &str, local_path: &Path) -> EmptyResult;
+
+    /// Deletes the file at `path`, it's not an error if the file doesn't exist
+    fn delete(&self, path: &str) -> EmptyResult;
+
+    fn exists(&self, path: &str) -> bool;
+
+    fn download(&self, path: &str) -> Option<StoredFile>;
+
+    /// Lists the paths of all the stored files, with the time they were last modified
+    fn list(&self) -> Result<Vec<(String, SystemTime)>, Error>;
+}
+
+/// Returns the configured storage for the given kind of files
+pub fn get_storage(area: FileArea) -> Box<dyn FileStorage> {
+    if CONFIG._enable_s3() && area.uses_s3() && area.s3_bucket().is_some() {
+        Box::new(S3Storage {
+            area,
+        })
+    } else {
+        Box::new(LocalStorage {
+            area,
+        })
+    }
+}
+
+struct LocalStorage {
+    area: FileArea,
+}
+
+impl FileStorage for LocalStorage {
+    fn save(&self, path: &str, local_path: &Path) -> EmptyResult {
+        let target = self.area.local_path(path);
+        if target != local_path {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if fs::rename(local_path, &target).is_err() {
+                // The temporary files can be on a different file system
+                fs::copy(local_path, &target)?;
+                fs::remove_file(local_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> EmptyResult {
+        let file_path = self.area.local_path(path);
+
+        match crate::util::delete_file(&file_path.to_string_lossy()) {
+            // Ignore "file not found" errors. This can happen when the
+            // upstream caller has already cleaned up the file as part of
+            // its own error handling.
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                debug!("File '{}' already deleted.", file_path.display());
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.area.local_path(path).exists()
+    }
+
+    fn download(&self, path: &str) -> Option<StoredFile> {
+        LocalFile::open(&self.area.local_path(path)).ok().map(StoredFile::Local)
+    }
+
+    fn list(&self) -> Result<Vec<(String, SystemTime)>, Error> {
+        fn walk(folder: &Path, prefix: &str, files: &mut Vec<(String, SystemTime)>) -> io::Result<()> {
+            for entry in fs::read_dir(folder)? {
+                let entry = entry?;
+                let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    walk(&entry.path(), &format!("{}/", path), files)?;
+                } else {
+                    files.push((path, metadata.modified()?));
+                }
+            }
+            Ok(())
+        }
+
+        let mut files = Vec::new();
+        match walk(&self.area.local_path(""), "", &mut files) {
+            // Nothing was stored yet
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(files),
+            Err(e) => Err(e.into()),
+            Ok(()) => Ok(files),
+        }
+    }
+}
+
+/// Characters which don't need to be encoded in a signed S3 request, as defined by the AWS Signature Version 4
+const S3_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+const S3_UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+/// Number of seconds the download URLs of the stored files are valid
+const S3_DOWNLOAD_EXPIRATION: i64 = 5 * 60;
+
+struct S3Storage {
+    area: FileArea,
+}
+
+impl S3Storage {
+    /// Returns the URL of the object, using path-style addressing which is supported by all S3 compatible services
+    fn object_url(&self, path: &str) -> Result<Url, Error> {
+        let endpoint = CONFIG.s3_endpoint().unwrap_or_default();
+        let bucket = self.area.s3_bucket().unwrap_or_default();
+        let prefix = self.area.s3_prefix();
+
+        let segments = prefix
+            .split('/')
+            .chain(path.split('/'))
+            .filter(|s| !s.is_empty())
+            .map(|s| utf8_percent_encode(s, S3_ENCODE_SET).to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let url = format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, segments);
+        Url::parse(&url).map_err(|e| Error::new("Invalid S3 object URL", e.to_string()))
+    }
+
+    fn client() -> Client {
+        // Uploads can take a lot longer than the default timeout
+        get_reqwest_client_builder().timeout(Duration::from_secs(300)).build().expect("Failed to build client")
+    }
+
+    fn host(url: &Url) -> String {
+        let host = url.host_str().unwrap_or_default();
+        match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        }
+    }
+
+    /// Returns the credential scope and the signature of a request, following the AWS Signature Version 4.
+    /// The headers need to be lowercase and sorted by name.
+    fn sign(
+        method: &Method,
+        url: &Url,
+        query: &str,
+        headers: &[(&str, &str)],
+        now: &DateTime<Utc>,
+    ) -> (String, String) {
+        let date = now.format("%Y%m%d").to_string();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let region = CONFIG.s3_region();
+        let scope = format!("{}/{}/s3/aws4_request", date, region);
+
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{}:{}\n", k, v.trim())).collect();
+        let signed_headers = headers.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(";");
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method.as_str(),
+            url.path(),
+            query,
+            canonical_headers,
+            signed_headers,
+            S3_UNSIGNED_PAYLOAD
+        );
+
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            HEXLOWER.encode(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+        );
+
+        let secret = format!("AWS4{}", CONFIG.s3_secret_access_key().unwrap_or_default());
+        let mut key = secret.into_bytes();
+        for part in &[date.as_str(), region.as_str(), "s3", "aws4_request"] {
+            key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), part.as_bytes()).as_ref().to_vec();
+        }
+        let signature = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), string_to_sign.as_bytes());
+
+        (scope, HEXLOWER.encode(signature.as_ref()))
+    }
+
+    /// Sends a request signed in the Authorization header
+    fn request(&self, method: Method, path: &str, body: Option<Body>) -> Result<reqwest::blocking::Response, Error> {
+        let url = self.object_url(path)?;
+        let now = Utc::now();
+        let host = Self::host(&url);
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+
+        let headers =
+            [("host", host.as_str()), ("x-amz-content-sha256", S3_UNSIGNED_PAYLOAD), ("x-amz-date", &amz_date)];
+        let (scope, signature) = Self::sign(&method, &url, "", &headers, &now);
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            CONFIG.s3_access_key_id().unwrap_or_default(),
+            scope,
+            signature
+        );
+
+        let mut request = Self::client()
+            .request(method, url)
+            .header("x-amz-content-sha256", S3_UNSIGNED_PAYLOAD)
+            .header("x-amz-date", amz_date)
+            .header("Authorization", authorization);
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+
+        Ok(request.send()?)
+    }
+
+    /// Returns a temporary URL to download the object, with the signature in the query string
+    fn presigned_url(&self, path: &str) -> Result<String, Error> {
+        let url = self.object_url(path)?;
+        let now = Utc::now();
+        let host = Self::host(&url);
+        let credential = format!(
+            "{}/{}/{}/s3/aws4_request",
+            CONFIG.s3_access_key_id().unwrap_or_default(),
+            now.format("%Y%m%d"),
+            CONFIG.s3_region()
+        );
+
+        // The parameters need to be sorted by name
+        let query = format!(
+            "X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+            utf8_percent_encode(&credential, S3_ENCODE_SET),
+            now.format("%Y%m%dT%H%M%SZ"),
+            S3_DOWNLOAD_EXPIRATION
+        );
+        let (_, signature) = Self::sign(&Method::GET, &url, &query, &[("host", &host)], &now);
+
+        Ok(format!("{}?{}&X-Amz-Signature={}", url, query, signature))
+    }
+}
+
+impl FileStorage for S3Storage {
+    fn save(&self, path: &str, local_path: &Path) -> EmptyResult {
+        let file = File::open(local_path)?;
+        let size = file.metadata()?.len();
+
+        let res = self.request(Method::PUT, path, Some(Body::sized(file, size)))?;
+        if !res.status().is_success() {
+            err!(format!("Error uploading file to S3: {}", res.status()))
+        }
+
+        crate::util::delete_file(&local_path.to_string_lossy())?;
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> EmptyResult {
+        let res = self.request(Method::DELETE, path, None)?;
+        if !res.status().is_success() && res.status() != reqwest::StatusCode::NOT_FOUND {
+            err!(format!("Error deleting file from S3: {}", res.status()))
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        match self.request(Method::HEAD, path, None) {
+            Ok(res) => res.status().is_success(),
+            Err(e) => {
+                error!("Error checking file in S3: {:#?}", e);
+                false
+            }
+        }
+    }
+
+    fn download(&self, path: &str) -> Option<StoredFile> {
+        match self.presigned_url(path) {
+            Ok(url) => Some(StoredFile::Remote(Redirect::temporary(url))),
+            Err(e) => {
+                error!("Error generating S3 download URL: {:#?}", e);
+                None
+            }
+        }
+    }
+
+    fn list(&self) -> Result<Vec<(String, SystemTime)>, Error> {
+        // Leftover objects can be removed with the lifecycle rules of the bucket instead
+        err!("Listing the files is not supported with the S3 storage")
+    }
+}
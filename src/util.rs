@@ -5,7 +5,7 @@ use std::io::Cursor;
 
 use rocket::{
     fairing::{Fairing, Info, Kind},
-    http::{ContentType, Header, HeaderMap, Method, Status},
+    http::{uri::Origin, ContentType, Header, HeaderMap, Method, Status},
     response::{self, Responder},
     Data, Request, Response, Rocket,
 };
@@ -130,6 +130,66 @@ impl<'r, R: Responder<'r>> Responder<'r> for Cached<R> {
 const LOGGED_ROUTES: [&str; 6] =
     ["/api", "/admin", "/identity", "/icons", "/notifications/hub/negotiate", "/attachments"];
 
+//
+// Maintenance mode
+//
+
+// Routes still served during the maintenance: the admin panel, its static files and the health check
+const MAINTENANCE_ALLOWED_ROUTES: [&str; 3] = ["/admin", "/bwrs_static", "/alive"];
+
+// Routes which don't modify the vault but use POST, allowed with the reads so the clients can log in
+const MAINTENANCE_READ_ROUTES: [&str; 3] =
+    ["/api/accounts/prelogin", "/identity/connect/token", "/notifications/hub/negotiate"];
+
+// Routes used by the clients, which get the error as JSON instead of an HTML page
+const MAINTENANCE_JSON_ROUTES: [&str; 4] = ["/api", "/identity", "/icons", "/notifications"];
+
+/// Redirects the blocked requests to the maintenance route, before they reach their handler
+pub struct MaintenanceMode();
+
+impl Fairing for MaintenanceMode {
+    fn info(&self) -> Info {
+        Info {
+            name: "Maintenance Mode",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request<'_>, _data: &Data) {
+        if !CONFIG.maintenance_mode() {
+            return;
+        }
+
+        let method = request.method();
+        let uri_path = request.uri().path();
+        let uri_subpath = uri_path.strip_prefix(&CONFIG.domain_path()).unwrap_or(uri_path);
+        if method == Method::Options || MAINTENANCE_ALLOWED_ROUTES.iter().any(|r| uri_subpath.starts_with(r)) {
+            return;
+        }
+
+        if CONFIG.maintenance_allow_reads()
+            && (method == Method::Get
+                || method == Method::Head
+                || MAINTENANCE_READ_ROUTES.iter().any(|r| uri_subpath.starts_with(r)))
+        {
+            return;
+        }
+
+        let format = if MAINTENANCE_JSON_ROUTES.iter().any(|r| uri_subpath.starts_with(r)) {
+            "json"
+        } else {
+            "html"
+        };
+        match Origin::parse_owned(format!("{}/bwrs_maintenance/{}", CONFIG.domain_path(), format)) {
+            Ok(uri) => {
+                request.set_method(Method::Get);
+                request.set_uri(uri);
+            }
+            Err(e) => error!("Error redirecting request to the maintenance route: {:?}", e),
+        }
+    }
+}
+
 // Boolean is extra debug, when true, we ignore the whitelist above and also print the mounts
 pub struct BetterLogging(pub bool);
 impl Fairing for BetterLogging {
@@ -250,8 +310,8 @@ pub fn delete_file(path: &str) -> IOResult<()> {
 
 const UNITS: [&str; 6] = ["bytes", "KB", "MB", "GB", "TB", "PB"];
 
-pub fn get_display_size(size: i32) -> String {
-    let mut size: f64 = size.into();
+pub fn get_display_size(size: i64) -> String {
+    let mut size = size as f64;
     let mut unit_counter = 0;
 
     loop {
@@ -266,10 +326,71 @@ pub fn get_display_size(size: i32) -> String {
     format!("{:.2} {}", size, UNITS[unit_counter])
 }
 
+/// Converts a size in bytes to GB, rounded to two decimals like the storage values shown by the clients
+pub fn get_size_gb(size: i64) -> f64 {
+    const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+    ((size as f64 / GB) * 100.0).round() / 100.0
+}
+
 pub fn get_uuid() -> String {
     uuid::Uuid::new_v4().to_string()
 }
 
+//
+// IP methods
+//
+use std::net::IpAddr;
+
+/// Parses an IP network in CIDR notation, like `10.0.0.0/8` or `fd00::/8`. A single IP is a network with only that IP
+pub fn parse_ip_network(network: &str) -> Option<(IpAddr, u8)> {
+    let mut parts = network.trim().splitn(2, '/');
+    let ip: IpAddr = parts.next()?.trim().parse().ok()?;
+    let max_prefix = if ip.is_ipv4() {
+        32
+    } else {
+        128
+    };
+
+    let prefix = match parts.next() {
+        Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|p| *p <= max_prefix)?,
+        None => max_prefix,
+    };
+    Some((ip, prefix))
+}
+
+/// Checks if the IP is part of the network, IPv4 addresses mapped to IPv6 (`::ffff:a.b.c.d`) match the IPv4 networks
+pub fn is_ip_in_network(ip: &IpAddr, network: &(IpAddr, u8)) -> bool {
+    fn same_prefix(ip: u128, network: u128, bits: u32, prefix: u8) -> bool {
+        let shift = bits - u32::from(prefix);
+        shift >= bits || (ip >> shift) == (network >> shift)
+    }
+
+    match (ip, network.0) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => same_prefix(u32::from(*ip).into(), u32::from(net).into(), 32, network.1),
+        (IpAddr::V6(ip), IpAddr::V6(net)) => same_prefix(u128::from(*ip), u128::from(net), 128, network.1),
+        (IpAddr::V6(ip), IpAddr::V4(_)) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => is_ip_in_network(&IpAddr::V4(ip.to_ipv4().unwrap()), network),
+            _ => false,
+        },
+        (IpAddr::V4(_), IpAddr::V6(_)) => false,
+    }
+}
+
+/// Returns the network the IP is part of, as a /24 for IPv4 and a /48 for IPv6, which usually belong to the same user
+/// even when their exact IP changes. IPv4 addresses mapped to IPv6 are handled as IPv4
+pub fn ip_network_of(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => ip_network_of(&IpAddr::V4(ip.to_ipv4().unwrap())),
+            [a, b, c, ..] => format!("{}/48", std::net::Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0)),
+        },
+    }
+}
+
 //
 // String util methods
 //
@@ -284,6 +405,14 @@ pub fn upcase_first(s: &str) -> String {
     }
 }
 
+pub fn lcase_first(s: &str) -> String {
+    let mut c = s.chars();
+    match c.next() {
+        None => String::new(),
+        Some(f) => f.to_lowercase().collect::<String>() + c.as_str(),
+    }
+}
+
 pub fn try_parse_string<S, T>(string: Option<S>) -> Option<T>
 where
     S: AsRef<str>,
@@ -478,6 +607,24 @@ fn _process_key(key: &str) -> String {
     }
 }
 
+/// Converts the keys of the objects to camelCase, the format used by the exports of the clients
+pub fn lcase_value(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            Value::Object(map.into_iter().map(|(key, val)| (_process_lcase_key(&key), lcase_value(val))).collect())
+        }
+        Value::Array(array) => Value::Array(array.into_iter().map(lcase_value).collect()),
+        value => value,
+    }
+}
+
+fn _process_lcase_key(key: &str) -> String {
+    match key.to_lowercase().as_ref() {
+        "ssn" => "ssn".into(),
+        _ => self::lcase_first(key),
+    }
+}
+
 //
 // Retry methods
 //